@@ -0,0 +1,121 @@
+// Renders the street scene, lit by 64 lamps, with each light sampler for the same wall-clock time
+// and reports how close each gets to the stored reference image. A sampler that picks better
+// lights but costs more per pick only wins if it still does better in the same time. Run with
+// `cargo bench --bench light_samplers`, optionally followed by `--` and the seconds to give
+// each sampler.
+
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+use raytracer::camera::PerspectiveCamera;
+use raytracer::color::luminance;
+use raytracer::error::Result;
+use raytracer::film::AdaptiveSampling;
+use raytracer::image::RgbImage;
+use raytracer::light::Light;
+use raytracer::light_sampler::{
+    BvhLightSampler, LightSampler, PowerLightSampler, UniformLightSampler,
+};
+use raytracer::renderer::{CancelToken, RenderSettings, Renderer};
+use raytracer::scenes::street;
+use raytracer::vector3::{Vector3 as Point3, Vector3};
+
+// The same view as the street scene of the scenes bench, whose reference this compares against.
+const WIDTH: usize = 128;
+const HEIGHT: usize = 80;
+const SEED: u64 = 0x5eed;
+const SECONDS: f64 = 4.0;
+
+type MakeSampler = fn(&[Box<dyn Light>]) -> Box<dyn LightSampler>;
+
+// Root mean square difference of the luminance of two images, relative to the mean luminance of
+// the reference.
+fn relative_error(image: &[Vector3], reference: &RgbImage) -> f64 {
+    let (mut squared, mut total) = (0.0, 0.0);
+    for (pixel, expected) in image.iter().zip(&reference.pixels) {
+        let difference = luminance(pixel) - luminance(expected);
+        squared += difference * difference;
+        total += luminance(expected);
+    }
+    let n = reference.pixels.len() as f64;
+    (squared / n).sqrt() / (total / n)
+}
+
+fn run(seconds: f64) -> Result<()> {
+    let reference = RgbImage::read(
+        [
+            env!("CARGO_MANIFEST_DIR"),
+            "benches",
+            "references",
+            "street.pfm",
+        ]
+        .iter()
+        .collect::<PathBuf>(),
+    )?;
+    let camera = PerspectiveCamera::new(
+        Point3::new(0.0, 3.0, 12.0),
+        Point3::new(0.0, 1.0, -20.0),
+        Vector3::new(0.0, 1.0, 0.0),
+        50.0,
+        WIDTH as f64 / HEIGHT as f64,
+        0.0,
+        1.0,
+    )?;
+    let samplers: [(&str, MakeSampler); 3] = [
+        ("uniform", |lights| {
+            Box::new(UniformLightSampler::new(lights))
+        }),
+        ("power", |lights| Box::new(PowerLightSampler::new(lights))),
+        ("bvh", |lights| Box::new(BvhLightSampler::new(lights))),
+    ];
+    for (name, sampler) in samplers {
+        let mut scene = street()?;
+        scene.light_sampler = Option::Some(sampler(&scene.lights));
+
+        // Every pixel is sampled evenly until the time is up.
+        let mut settings = RenderSettings::new(WIDTH, HEIGHT);
+        settings.adaptive = AdaptiveSampling {
+            min_samples: u32::MAX,
+            max_samples: u32::MAX,
+            threshold: 0.0,
+        };
+        settings.samples_per_pass = 1;
+        settings.budget.time = Option::Some(Duration::from_secs_f64(seconds));
+        settings.seed = SEED;
+        let render = Renderer::new(settings).render(
+            &scene,
+            &camera,
+            &mut |_| {},
+            &CancelToken::default(),
+        )?;
+        println!(
+            "{:<8} error {:.4} at {:.1} spp in {:.1}s",
+            name,
+            relative_error(&render.image(), &reference),
+            render.film.mean_sample_count(),
+            render.elapsed.as_secs_f64()
+        );
+    }
+    Ok(())
+}
+
+fn main() {
+    // Cargo passes --bench, and anything after `--` on its command line.
+    let seconds = env::args()
+        .skip(1)
+        .find(|arg| !arg.starts_with("--"))
+        .map_or(Option::Some(SECONDS), |arg| arg.parse().ok());
+    let seconds = match seconds {
+        Some(seconds) if seconds > 0.0 => seconds,
+        _ => {
+            eprintln!("expected a positive number of seconds");
+            process::exit(1);
+        }
+    };
+    if let Err(e) = run(seconds) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,220 @@
+// Renders the canonical scenes with a fixed seed and sample count, and reports how fast rays are
+// traced and how long a render takes to get within a fixed error of a stored reference image.
+// Times are the median of several renders after one to warm up, with the counters off.
+// The crate has no triangle mesh primitive, so the terrain heightfield, over 130,000
+// triangles, stands in for a large mesh.
+// Run with `cargo bench`, or `cargo bench --bench scenes --` followed by the names of the scenes
+// to run. After a change that alters the images on purpose, `cargo bench --bench scenes --
+// --update` renders the references again, which takes several minutes.
+
+use std::env;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::process;
+
+use raytracer::camera::PerspectiveCamera;
+use raytracer::color::luminance;
+use raytracer::error::Result;
+use raytracer::film::AdaptiveSampling;
+use raytracer::image::RgbImage;
+use raytracer::renderer::{CancelToken, Render, RenderSettings, Renderer};
+use raytracer::scene::Scene;
+use raytracer::scenes::{cornell_box, glass_scene, random_scene, street, terrain};
+use raytracer::util::seed_random;
+use raytracer::vector3::{Vector3 as Point3, Vector3};
+
+const WIDTH: usize = 128;
+const HEIGHT: usize = 80;
+const SEED: u64 = 0x5eed;
+// Samples per pixel of the renders timed for throughput.
+const SAMPLES: u32 = 16;
+// The references are rendered with another seed, so that their noise is independent of that of
+// the renders compared to them.
+const REFERENCE_SEED: u64 = 0xbe7c;
+const REFERENCE_SAMPLES: u32 = 1024;
+// Error against the reference, relative to its mean luminance, that a render has to get within.
+const TARGET_ERROR: f64 = 0.05;
+// Most samples per pixel tried to get there, doubling from 1.
+const MAX_SAMPLES: u32 = 256;
+// Timed renders of each measurement, after one that isn't timed.
+const RUNS: usize = 5;
+
+struct Bench {
+    name: &'static str,
+    scene: fn() -> Result<Scene>,
+    look_from: Point3,
+    look_at: Point3,
+    v_fov: f64,
+}
+
+fn benches() -> Vec<Bench> {
+    vec![
+        Bench {
+            name: "random_scene",
+            scene: random_scene,
+            look_from: Point3::new(13.0, 2.0, 3.0),
+            look_at: Point3::zero(),
+            v_fov: 20.0,
+        },
+        Bench {
+            name: "cornell_box",
+            scene: cornell_box,
+            look_from: Point3::new(50.0, 50.0, 250.0),
+            look_at: Point3::new(50.0, 50.0, 0.0),
+            v_fov: 30.0,
+        },
+        Bench {
+            name: "glass",
+            scene: glass_scene,
+            look_from: Point3::new(0.0, 4.0, 9.0),
+            look_at: Point3::new(0.0, 0.5, 0.0),
+            v_fov: 40.0,
+        },
+        Bench {
+            name: "terrain",
+            scene: || terrain(256),
+            look_from: Point3::new(0.0, 20.0, 60.0),
+            look_at: Point3::zero(),
+            v_fov: 40.0,
+        },
+        Bench {
+            name: "street",
+            scene: street,
+            look_from: Point3::new(0.0, 3.0, 12.0),
+            look_at: Point3::new(0.0, 1.0, -20.0),
+            v_fov: 50.0,
+        },
+    ]
+}
+
+impl Bench {
+    fn render(&self, seed: u64, samples: u32, stats: bool) -> Result<Render> {
+        // The random scene is built from the seed too, so it is the same whatever the render.
+        seed_random(SEED);
+        let scene = (self.scene)()?;
+        let camera = PerspectiveCamera::new(
+            self.look_from,
+            self.look_at,
+            Vector3::new(0.0, 1.0, 0.0),
+            self.v_fov,
+            WIDTH as f64 / HEIGHT as f64,
+            0.0,
+            1.0,
+        )?;
+        let mut settings = RenderSettings::new(WIDTH, HEIGHT);
+        settings.adaptive = AdaptiveSampling {
+            min_samples: samples,
+            max_samples: samples,
+            threshold: 0.0,
+        };
+        settings.samples_per_pass = samples.min(4);
+        settings.seed = seed;
+        settings.stats = stats;
+        Renderer::new(settings).render(&scene, &camera, &mut |_| {}, &CancelToken::default())
+    }
+
+    // Median time of the renders, in seconds.
+    fn time(&self, seed: u64, samples: u32) -> Result<f64> {
+        self.render(seed, samples, false)?;
+        let mut times = Vec::with_capacity(RUNS);
+        for _ in 0..RUNS {
+            times.push(self.render(seed, samples, false)?.elapsed.as_secs_f64());
+        }
+        times.sort_by(f64::total_cmp);
+        Ok(times[RUNS / 2])
+    }
+
+    fn reference_path(&self) -> PathBuf {
+        [env!("CARGO_MANIFEST_DIR"), "benches", "references"]
+            .iter()
+            .collect::<PathBuf>()
+            .join(format!("{}.pfm", self.name))
+    }
+}
+
+// Root mean square difference of the luminance of two images, relative to the mean luminance of
+// the reference.
+fn relative_error(image: &[Vector3], reference: &RgbImage) -> f64 {
+    let (mut squared, mut total) = (0.0, 0.0);
+    for (pixel, expected) in image.iter().zip(&reference.pixels) {
+        let difference = luminance(pixel) - luminance(expected);
+        squared += difference * difference;
+        total += luminance(expected);
+    }
+    let n = reference.pixels.len() as f64;
+    (squared / n).sqrt() / (total / n)
+}
+
+fn run(bench: &Bench, update: bool) -> Result<()> {
+    let path = bench.reference_path();
+    if update {
+        let render = bench.render(REFERENCE_SEED, REFERENCE_SAMPLES, false)?;
+        let reference = RgbImage {
+            width: WIDTH,
+            height: HEIGHT,
+            pixels: render.image(),
+        };
+        reference.write_pfm(BufWriter::new(File::create(&path)?))?;
+        println!(
+            "{:<14} reference rendered in {:.1}s",
+            bench.name,
+            render.elapsed.as_secs_f64()
+        );
+        return Ok(());
+    }
+
+    // The rays are counted in a render of their own, which is the same every time.
+    let stats = bench.render(SEED, SAMPLES, true)?.stats;
+    let rays = stats.camera_rays + stats.scatter_rays + stats.shadow_rays;
+    let seconds = bench.time(SEED, SAMPLES)?;
+    print!(
+        "{:<14} {:>8.2} Mrays/s {:>7.3}s at {} spp",
+        bench.name,
+        rays as f64 / seconds / 1e6,
+        seconds,
+        SAMPLES
+    );
+
+    if !path.exists() {
+        println!(", no reference, render one with --update");
+        return Ok(());
+    }
+    let reference = RgbImage::read(&path)?;
+    let mut samples = 1;
+    loop {
+        let render = bench.render(SEED, samples, false)?;
+        let error = relative_error(&render.image(), &reference);
+        if error <= TARGET_ERROR {
+            println!(
+                ", {:.3}s to error {} at {} spp",
+                bench.time(SEED, samples)?,
+                TARGET_ERROR,
+                samples
+            );
+            break;
+        }
+        if samples >= MAX_SAMPLES {
+            println!(", error {:.3} at {} spp", error, samples);
+            break;
+        }
+        samples *= 2;
+    }
+    Ok(())
+}
+
+fn main() {
+    // Cargo passes --bench, and anything after `--` on its command line.
+    let args: Vec<String> = env::args().skip(1).collect();
+    let update = args.iter().any(|arg| arg == "--update");
+    let names: Vec<&String> = args.iter().filter(|arg| !arg.starts_with("--")).collect();
+    for bench in benches() {
+        if !names.is_empty() && !names.iter().any(|name| *name == bench.name) {
+            continue;
+        }
+        if let Err(e) = run(&bench, update) {
+            eprintln!("{}: {}", bench.name, e);
+            process::exit(1);
+        }
+    }
+}
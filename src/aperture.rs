@@ -0,0 +1,181 @@
+use std::f64::consts::PI;
+
+use crate::error::{check, Result};
+use crate::image::GrayImage;
+use crate::sampling::Distribution2D;
+use crate::util::{degrees_to_radians, random_f64};
+use crate::vector3::Vector3;
+
+pub enum ApertureShape {
+    Circle,
+    // Regular polygon formed by straight diaphragm blades, with a vertex at `rotation` radians
+    // counterclockwise from the x axis.
+    Polygon {
+        blades: u32,
+        rotation: f64,
+    },
+    // Transmission of the aperture read from a grayscale image, which spans the square around
+    // the unit disk. White lets light through and black blocks it.
+    Mask {
+        image: GrayImage,
+        distribution: Distribution2D,
+    },
+}
+
+// Shape of the lens opening, which out-of-focus highlights take on. Points are sampled in the
+// unit disk and scaled by the lens radius of the camera.
+pub struct Aperture {
+    pub shape: ApertureShape,
+    // Strength of the optical vignetting that clips the aperture into a cat's eye towards the
+    // edges of the image, from 0 for none to 1, where the clipping barrel is offset by the
+    // distance to the image center.
+    pub cat_eye: f64,
+    // Horizontal squeeze of an anamorphic lens, which stretches highlights vertically by this
+    // factor. 1 for spherical lenses.
+    pub squeeze: f64,
+}
+
+impl Aperture {
+    pub fn new(shape: ApertureShape) -> Aperture {
+        Aperture {
+            shape,
+            cat_eye: 0.0,
+            squeeze: 1.0,
+        }
+    }
+
+    pub fn circle() -> Aperture {
+        Aperture::new(ApertureShape::Circle)
+    }
+
+    // `rotation` is in degrees.
+    pub fn polygon(blades: u32, rotation: f64) -> Result<Aperture> {
+        check(blades >= 3, "an aperture needs at least three blades")?;
+        Ok(Aperture::new(ApertureShape::Polygon {
+            blades,
+            rotation: degrees_to_radians(rotation),
+        }))
+    }
+
+    pub fn mask(image: GrayImage) -> Result<Aperture> {
+        check(
+            image.pixels.iter().any(|p| *p > 0.0),
+            "an aperture mask must let some light through",
+        )?;
+        let distribution = Distribution2D::new(&image.pixels, image.width, image.height)?;
+        Ok(Aperture::new(ApertureShape::Mask {
+            image,
+            distribution,
+        }))
+    }
+
+    // Point on the aperture for a ray through the image at (s, t), or None if the ray is
+    // vignetted.
+    pub fn sample(&self, s: f64, t: f64) -> Option<Vector3> {
+        let mut p = match &self.shape {
+            ApertureShape::Circle => Vector3::new_random_in_unit_disk(),
+            ApertureShape::Polygon { blades, rotation } => {
+                // Uniform point in one of the triangles between the center and each blade.
+                let n = *blades as f64;
+                let blade = ((random_f64() * n) as u32).min(blades - 1) as f64;
+                let a = rotation + 2.0 * PI * blade / n;
+                let b = rotation + 2.0 * PI * (blade + 1.0) / n;
+                let (mut u, mut v) = (random_f64(), random_f64());
+                if u + v > 1.0 {
+                    u = 1.0 - u;
+                    v = 1.0 - v;
+                }
+                u * Vector3::new(a.cos(), a.sin(), 0.0) + v * Vector3::new(b.cos(), b.sin(), 0.0)
+            }
+            ApertureShape::Mask { distribution, .. } => {
+                let (u, v, _) = distribution.sample(random_f64(), random_f64());
+                // The first row of the image is the top of the aperture.
+                Vector3::new(2.0 * u - 1.0, 1.0 - 2.0 * v, 0.0)
+            }
+        };
+
+        if self.cat_eye > 0.0 {
+            // Seen from off the axis, the lens barrel overlaps the aperture only partly, leaving
+            // its intersection with a disk offset towards the edge of the image.
+            let center = self.cat_eye * Vector3::new(2.0 * s - 1.0, 2.0 * t - 1.0, 0.0);
+            if (p - center).length_squared() > 1.0 {
+                return Option::None;
+            }
+        }
+        p.x /= self.squeeze;
+        Option::Some(p)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use crate::aperture::Aperture;
+    use crate::image::GrayImage;
+
+    #[test]
+    fn polygon() {
+        let aperture = Aperture::polygon(6, 15.0).unwrap();
+        let apothem = (PI / 6.0).cos();
+        for _ in 0..1000 {
+            let p = aperture.sample(0.5, 0.5).unwrap();
+            // Inside every edge of the hexagon.
+            for i in 0..6 {
+                let normal = (15.0_f64 + 30.0 + 60.0 * i as f64).to_radians();
+                assert!(p.x * normal.cos() + p.y * normal.sin() <= apothem + 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn mask() {
+        // Only the upper left quarter of the aperture is open.
+        let mut pixels = vec![0.0; 16];
+        pixels[0] = 1.0;
+        pixels[1] = 0.5;
+        pixels[4] = 1.0;
+        let aperture = Aperture::mask(GrayImage {
+            width: 4,
+            height: 4,
+            pixels,
+        })
+        .unwrap();
+        for _ in 0..1000 {
+            let p = aperture.sample(0.5, 0.5).unwrap();
+            assert!(p.x < 0.0 && p.y > 0.0);
+        }
+
+        assert!(Aperture::mask(GrayImage {
+            width: 2,
+            height: 2,
+            pixels: vec![0.0; 4],
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn cat_eye() {
+        let mut aperture = Aperture::circle();
+        aperture.cat_eye = 1.0;
+        assert!((0..1000).all(|_| aperture.sample(0.5, 0.5).is_some()));
+        let mut vignetted = 0;
+        for _ in 0..1000 {
+            match aperture.sample(1.0, 0.5) {
+                // The barrel is offset towards +x, so the left of the aperture is cut off.
+                Some(p) => assert!(p.x >= 0.0),
+                None => vignetted += 1,
+            }
+        }
+        assert!(vignetted > 400);
+    }
+
+    #[test]
+    fn anamorphic() {
+        let mut aperture = Aperture::circle();
+        aperture.squeeze = 2.0;
+        for _ in 0..1000 {
+            assert!(aperture.sample(0.5, 0.5).unwrap().x.abs() <= 0.5);
+        }
+    }
+}
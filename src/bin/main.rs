@@ -1,27 +1,122 @@
-use std::f64::INFINITY;
+use std::env;
+use std::fs::File;
 use std::io;
-use std::io::Write;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::process;
+use std::time::{Duration, Instant};
 
-use raytracer::camera::Camera;
-use raytracer::color::write_color;
-use raytracer::hittable::Hittable;
-use raytracer::material::{Dielectric, Lambertian, Metal};
-use raytracer::ray::Ray;
-use raytracer::sphere::Sphere;
-use raytracer::util::{random_f64, random_range};
-use raytracer::vector3::{Vector3 as Color, Vector3 as Point3, Vector3};
+use raytracer::camera::{
+    Camera, EquirectangularCamera, FisheyeCamera, FisheyeProjection, OrthographicCamera,
+    PerspectiveCamera,
+};
+use raytracer::denoise::Denoiser;
+use raytracer::error::{Error, Result};
+use raytracer::film::{write_ppm, AdaptiveSampling, Budget};
+use raytracer::lens::RealisticCamera;
+use raytracer::renderer::{CancelToken, Progress, RenderSettings, Renderer};
+use raytracer::scenes::random_scene;
+use raytracer::sky::PreethamSky;
+use raytracer::stereo::{StereoCamera, StereoLayout};
+use raytracer::util::seed_random;
+use raytracer::vector3::{Vector3 as Point3, Vector3};
 
 // Image
 const ASPECT_RATIO: f64 = 3.0 / 2.0;
-const IMAGE_WIDTH: i32 = 1200;
-const IMAGE_HEIGHT: i32 = (IMAGE_WIDTH as f64 / ASPECT_RATIO) as i32;
-const SAMPLES_PER_PIXEL: u16 = 500;
+const IMAGE_WIDTH: usize = 1200;
+const MIN_SAMPLES_PER_PIXEL: u32 = 16;
+const MAX_SAMPLES_PER_PIXEL: u32 = 500;
+// Relative error at which a pixel counts as converged.
+const ERROR_THRESHOLD: f64 = 0.01;
+// Samples taken for each pixel that hasn't converged in every pass over the image.
+const SAMPLE_BATCH: u32 = 4;
+// Passes between checkpoints.
+const CHECKPOINT_INTERVAL: u32 = 8;
+const SEED: u64 = 0x5eed;
 const MAX_DEPTH: u8 = 50;
+// Daylight for --sky: a clear sky, the sun in the south east, and exposure for its radiance in
+// kcd/m^2.
+const SKY_TURBIDITY: f64 = 3.0;
+const SUN_AZIMUTH: f64 = 135.0;
+const SKY_INTENSITY: f64 = 0.08;
+// Number of objects and materials stored per pixel in the mattes.
+const CRYPTOMATTE_RANKS: usize = 6;
 
 fn main() {
-    // World
+    if let Err(e) = run() {
+        eprintln!("\n{}", e);
+        process::exit(1);
+    }
+}
 
-    let world = random_scene();
+fn run() -> Result<()> {
+    // Options are --samples for the most samples per pixel, --checkpoint to save the render to
+    // a file every few passes so that it can be carried on with --resume, which keeps saving to
+    // the file it resumed from unless told otherwise. --time, in seconds, and --noise, a relative
+    // error, stop the render once either is reached, with no limit on samples unless one is
+    // given. --clamp limits the indirect light of a sample to suppress fireflies, and --stats
+    // counts the rays traced and writes them with the time taken to the named file as JSON.
+    // --sky lights the scene with daylight instead of the plain gradient, with the sun at the
+    // given elevation in degrees. --denoise filters the noise out of the finished image, guided
+    // by the albedo, normals and depth of what the camera sees, which makes low sample counts
+    // usable for previews. The rest are positional.
+    let mut max_samples = Option::None;
+    let mut stats_path = Option::None;
+    let mut clamp_indirect = Option::None;
+    let mut sun_elevation = Option::None;
+    let mut denoise = false;
+    let mut budget = Budget::default();
+    let mut checkpoint = Option::None;
+    let mut resume = Option::None;
+    let mut positional = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| Error::InvalidParameter(format!("missing value for {}", arg)))
+        };
+        let invalid = |what: &str, e: &dyn std::fmt::Display| {
+            Error::InvalidParameter(format!("invalid {}: {}", what, e))
+        };
+        match arg.as_str() {
+            "--samples" => {
+                max_samples =
+                    Option::Some(value()?.parse().map_err(|e| invalid("sample count", &e))?)
+            }
+            "--time" => {
+                let seconds: f64 = value()?.parse().map_err(|e| invalid("time", &e))?;
+                if !(seconds >= 0.0 && seconds.is_finite()) {
+                    return Err(invalid("time", &seconds));
+                }
+                budget.time = Option::Some(Duration::from_secs_f64(seconds));
+            }
+            "--noise" => {
+                budget.error =
+                    Option::Some(value()?.parse().map_err(|e| invalid("noise level", &e))?)
+            }
+            "--clamp" => {
+                clamp_indirect = Option::Some(value()?.parse().map_err(|e| invalid("clamp", &e))?)
+            }
+            "--sky" => {
+                let elevation: f64 = value()?.parse().map_err(|e| invalid("sun elevation", &e))?;
+                if !(-90.0..=90.0).contains(&elevation) {
+                    return Err(invalid("sun elevation", &elevation));
+                }
+                sun_elevation = Option::Some(elevation);
+            }
+            "--denoise" => denoise = true,
+            "--stats" => stats_path = Option::Some(value()?),
+            "--checkpoint" => checkpoint = Option::Some(value()?),
+            "--resume" => resume = Option::Some(value()?),
+            _ => positional.push(arg),
+        }
+    }
+    let checkpoint = checkpoint.or_else(|| resume.clone());
+    let max_samples = match max_samples {
+        Some(samples) => samples,
+        None if budget.time.is_some() || budget.error.is_some() => u32::MAX,
+        None => MAX_SAMPLES_PER_PIXEL,
+    };
 
     // Camera
     let look_from = Point3::new(13.0, 2.0, 3.0);
@@ -30,123 +125,211 @@ fn main() {
     let dist_to_focus = 10.0;
     let aperture = 0.1;
 
-    let camera = Camera::new(
-        look_from,
-        look_at,
-        v_up,
-        20.0,
-        ASPECT_RATIO,
-        aperture,
-        dist_to_focus,
-    );
+    // The projection is picked by the first argument. The second, if given, names a file to
+    // write the number of samples taken for each pixel to, and the third an OpenEXR file to write
+    // the render passes to. Panoramas are twice as wide as tall, and stereo images hold the views
+    // of both eyes.
+    let heatmap = positional.get(1).filter(|path| !path.is_empty()).cloned();
+    let passes = positional.get(2).cloned();
+    let ipd = 0.064;
+    let projection = positional
+        .first()
+        .cloned()
+        .unwrap_or_else(|| String::from("perspective"));
+    let aspect_ratio = match projection.as_str() {
+        "equirectangular" => 2.0,
+        "stereo" => StereoLayout::SideBySide.aspect_ratio(ASPECT_RATIO),
+        "ods" => StereoLayout::OverUnder.aspect_ratio(2.0),
+        _ => ASPECT_RATIO,
+    };
+    let image_height = (IMAGE_WIDTH as f64 / aspect_ratio) as usize;
+    let camera: Box<dyn Camera> = match projection.as_str() {
+        "perspective" => Box::new(PerspectiveCamera::new(
+            look_from,
+            look_at,
+            v_up,
+            20.0,
+            aspect_ratio,
+            aperture,
+            dist_to_focus,
+        )?),
+        "orthographic" => Box::new(OrthographicCamera::new(
+            look_from,
+            look_at,
+            v_up,
+            5.0,
+            aspect_ratio,
+        )?),
+        "fisheye" => Box::new(FisheyeCamera::new(
+            look_from,
+            look_at,
+            v_up,
+            180.0,
+            aspect_ratio,
+            FisheyeProjection::Equisolid,
+        )?),
+        "equirectangular" => Box::new(EquirectangularCamera::new(look_from, look_at, v_up)?),
+        "stereo" => Box::new(StereoCamera::perspective(
+            look_from,
+            look_at,
+            v_up,
+            20.0,
+            ASPECT_RATIO,
+            ipd,
+            dist_to_focus,
+            StereoLayout::SideBySide,
+        )?),
+        "ods" => Box::new(StereoCamera::ods(
+            look_from,
+            look_at,
+            v_up,
+            ipd,
+            f64::INFINITY,
+            StereoLayout::OverUnder,
+        )?),
+        // A 50mm double Gauss lens at f/4 on full frame film.
+        "realistic" => Box::new(RealisticCamera::read(
+            look_from,
+            look_at,
+            v_up,
+            concat!(env!("CARGO_MANIFEST_DIR"), "/assets/lenses/dgauss.50mm.dat"),
+            12.5,
+            dist_to_focus,
+            43.27,
+            aspect_ratio,
+        )?),
+        _ => {
+            return Err(Error::InvalidParameter(format!(
+                "unknown projection {}, expected perspective, orthographic, fisheye, \
+                 equirectangular, stereo, ods or realistic",
+                projection
+            )))
+        }
+    };
 
     // Render
-    io::stdout()
-        .write_fmt(format_args!("P3\n{} {}\n255\n", IMAGE_WIDTH, IMAGE_HEIGHT))
-        .unwrap();
-
-    for y in (0..IMAGE_HEIGHT).rev() {
-        io::stderr()
-            .write_fmt(format_args!("\rScanlines remaining: {} ", y))
-            .unwrap();
+    let mut settings = RenderSettings::new(IMAGE_WIDTH, image_height);
+    settings.max_depth = MAX_DEPTH;
+    settings.adaptive = AdaptiveSampling {
+        min_samples: MIN_SAMPLES_PER_PIXEL.min(max_samples),
+        max_samples,
+        threshold: ERROR_THRESHOLD,
+    };
+    settings.samples_per_pass = SAMPLE_BATCH;
+    settings.budget = budget;
+    settings.seed = SEED;
+    if denoise {
+        settings.denoiser = Option::Some(Denoiser::default());
+    }
+    settings.aovs = passes.is_some();
+    settings.stats = stats_path.is_some();
+    settings.checkpoint = checkpoint.map(PathBuf::from);
+    settings.checkpoint_interval = CHECKPOINT_INTERVAL;
+    settings.resume = resume.map(PathBuf::from);
+    settings.clamp_indirect = clamp_indirect;
+    let renderer = Renderer::new(settings);
 
-        for x in 0..IMAGE_WIDTH {
-            let mut pixel_color = Color::zero();
-            for _ in 0..SAMPLES_PER_PIXEL {
-                let u = (x as f64 + random_f64()) / IMAGE_WIDTH as f64;
-                let v = (y as f64 + random_f64()) / IMAGE_HEIGHT as f64;
+    // World, made from the seed of the render so that resuming rebuilds the same one.
+    seed_random(SEED);
+    let mut scene = random_scene()?;
+    if let Some(elevation) = sun_elevation {
+        scene.environment = Box::new(PreethamSky::new(
+            SKY_TURBIDITY,
+            Vector3::new(0.3, 0.3, 0.3),
+            elevation,
+            SUN_AZIMUTH,
+            SKY_INTENSITY,
+        )?);
+    }
 
-                let r = camera.get_ray(u, v);
-                pixel_color += ray_color(&r, &world, MAX_DEPTH);
-            }
-            write_color(io::stdout(), pixel_color, SAMPLES_PER_PIXEL);
+    let unlimited = budget.time.is_some() || budget.error.is_some();
+    let mut report = |progress: &Progress| {
+        if progress.rows_done < progress.rows {
+            return;
+        }
+        if unlimited {
+            eprint!("\rPass {} ", progress.pass + 1);
+        } else {
+            eprint!("\rPass {} of {} ", progress.pass + 1, progress.total_passes);
         }
+    };
+    let render = renderer.render(
+        &scene,
+        camera.as_ref(),
+        &mut report,
+        &CancelToken::default(),
+    )?;
+    if render.out_of_time {
+        eprint!(
+            "\nRan out of time part way through pass {}, which was cut short",
+            render.state.passes + 1
+        );
     }
-
-    io::stderr().write_all(b"\nDone\n").unwrap();
-}
-
-fn random_scene() -> Vec<Box<dyn Hittable>> {
-    let mut world: Vec<Box<dyn Hittable>> = Vec::new();
-
-    let ground_material = Lambertian::new(Color::new(0.5, 0.5, 0.5));
-    world.push(Box::new(Sphere::new(
-        Point3::new(0.0, -1000.0, 0.0),
-        1000.0,
-        ground_material.clone(),
-    )));
-
-    for a in -11..11 {
-        for b in -11..11 {
-            let choose_mat = random_f64();
-            let center = Point3::new(
-                a as f64 + 0.9 * random_f64(),
-                0.2,
-                b as f64 + 0.9 * random_f64(),
+    if let Some(e) = &render.checkpoint_error {
+        eprint!("\nFailed to save checkpoint: {}", e);
+    }
+    if render.nonfinite_samples > 0 {
+        eprint!(
+            "\nDiscarded {} samples with radiance that isn't finite",
+            render.nonfinite_samples
+        );
+        for sample in &render.nonfinite {
+            eprint!(
+                "\n  pixel ({}, {}) in pass {} at bounce {}",
+                sample.x,
+                sample.y,
+                sample.pass + 1,
+                sample.bounce
             );
-
-            if (center - Point3::new(4.0, 0.2, 0.0)).length() > 0.9 {
-                if choose_mat < 0.8 {
-                    // diffuse
-                    let albedo = Color::new_random() * Color::new_random();
-                    let sphere_material = Lambertian::new(albedo);
-                    world.push(Box::new(Sphere::new(center, 0.2, sphere_material)))
-                } else if choose_mat < 0.95 {
-                    // metal
-                    let albedo = Color::new_random_range(0.5, 1.0);
-                    let fuzz = random_range(0.0, 0.5);
-                    let sphere_material = Metal::new(albedo, fuzz);
-                    world.push(Box::new(Sphere::new(center, 0.2, sphere_material)))
-                } else {
-                    // glass
-                    let sphere_material = Dielectric::new(1.5);
-                    world.push(Box::new(Sphere::new(center, 0.2, sphere_material)))
-                }
-            }
         }
     }
+    if render.clamped_samples > 0 {
+        eprint!(
+            "\nClamped the indirect light of {} samples, removing {:.3} of luminance",
+            render.clamped_samples, render.clamped_energy
+        );
+    }
+    let film = &render.film;
+    eprint!(
+        "\nRendered {} passes in {:.1}s, {:.1} samples per pixel on average, estimated error {:.4}",
+        render.state.passes,
+        render.elapsed.as_secs_f64(),
+        film.mean_sample_count(),
+        film.estimated_error()
+    );
 
-    let material1 = Dielectric::new(1.5);
-    world.push(Box::new(Sphere::new(
-        Point3::new(0.0, 1.0, 0.0),
-        1.0,
-        material1,
-    )));
-
-    let material2 = Lambertian::new(Color::new(0.4, 0.2, 0.1));
-    world.push(Box::new(Sphere::new(
-        Point3::new(-4.0, 1.0, 0.0),
-        1.0,
-        material2,
-    )));
-
-    let material3 = Metal::new(Color::new(0.7, 0.6, 0.5), 0.0);
-    world.push(Box::new(Sphere::new(
-        Point3::new(4.0, 1.0, 0.0),
-        1.0,
-        material3,
-    )));
-
-    world
-}
-
-fn ray_color(r: &Ray, world: &Vec<Box<dyn Hittable>>, depth: u8) -> Color {
-    // If we've exceeded the ray bounce limit, no more light is gathered.
-    if depth <= 0 {
-        return Color::zero();
+    let mut stats = render.stats.clone();
+    if denoise {
+        eprint!("\nDenoising");
     }
-    let hit_rec = world.hit(r, 0.001, INFINITY);
-    if hit_rec.is_some() {
-        let hit = hit_rec.unwrap();
-        let scatter_rec = hit.material.scatter(r, &hit.p, &hit.normal, hit.front_face);
-        if scatter_rec.is_some() {
-            let scatter = scatter_rec.unwrap();
-            return scatter.attenuation * ray_color(&scatter.scattered_ray, world, depth - 1);
-        } else {
-            return Color::zero();
+    let denoise_start = Instant::now();
+    let image = render.image();
+    if denoise {
+        stats.add_phase("denoise", denoise_start.elapsed());
+    }
+
+    let output_start = Instant::now();
+    write_ppm(io::stdout().lock(), IMAGE_WIDTH, image_height, &image)?;
+    if let Some(path) = heatmap {
+        let file = File::create(&path)?;
+        // Without a limit on samples, the most taken for any pixel is white.
+        let mut most = 0;
+        for y in 0..image_height {
+            for x in 0..IMAGE_WIDTH {
+                most = most.max(film.sample_count(x, y));
+            }
         }
+        film.write_sample_heatmap(BufWriter::new(file), max_samples.min(most.max(1)))?;
+    }
+    if let Some(path) = passes {
+        let file = File::create(&path)?;
+        render.write_exr(BufWriter::new(file), CRYPTOMATTE_RANKS)?;
+    }
+    stats.add_phase("output", output_start.elapsed());
+    eprint!("\n{}", stats);
+    if let Some(path) = stats_path {
+        stats.write_json(BufWriter::new(File::create(&path)?))?;
     }
-    let unit_direction = r.direction.unit();
-    let t = 0.5 * (unit_direction.y + 1.0);
-    (1.0 - t) * Color::new(1.0, 1.0, 1.0) + t * Color::new(0.5, 0.7, 1.0)
+    eprintln!("Done");
+    Ok(())
 }
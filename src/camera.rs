@@ -1,18 +1,67 @@
+use std::f64::consts::PI;
+
+use crate::aperture::Aperture;
+use crate::error::{check, Result};
 use crate::ray::Ray;
 use crate::util::degrees_to_radians;
 use crate::vector3::{Vector3 as Point3, Vector3};
 
-pub struct Camera {
+pub trait Camera {
+    // Ray through the image at (s, t), where (0, 0) is the lower left corner and (1, 1) the upper
+    // right. None if the point lies outside the area the projection covers, such as the corners
+    // of a circular fisheye image.
+    fn get_ray(&self, s: f64, t: f64) -> Option<Ray>;
+
+    // The same ray together with the fraction of the light along it that reaches the film, for
+    // cameras whose optics darken parts of the image. Renderers scale samples by the weight,
+    // where `get_ray` drops rays in proportion instead.
+    fn get_weighted_ray(&self, s: f64, t: f64) -> Option<(Ray, f64)> {
+        self.get_ray(s, t).map(|r| (r, 1.0))
+    }
+}
+
+// Right, up and backwards axes of a camera at `look_from` facing `look_at`. Fails if the camera
+// looks at itself or straight along `v_up`, which leaves the axes undefined.
+pub(crate) fn basis(
+    look_from: Point3,
+    look_at: Point3,
+    v_up: Vector3,
+) -> Result<(Vector3, Vector3, Vector3)> {
+    check(
+        look_from != look_at,
+        "look_from and look_at are the same point",
+    )?;
+    let w = (look_from - look_at).unit();
+    let across = v_up.cross(&w);
+    check(
+        across.length_squared() > 0.0 && across.length_squared().is_finite(),
+        "v_up is parallel to the view direction",
+    )?;
+    let u = across.unit();
+    let v = w.cross(&u);
+    Ok((u, v, w))
+}
+
+// Thin lens camera, focused on a plane `focus_dist` away. The lens can be shifted and tilted
+// like that of a view camera.
+pub struct PerspectiveCamera {
     origin: Point3,
     lower_left_corner: Point3,
     horizontal: Vector3,
     vertical: Vector3,
     u: Vector3,
     v: Vector3,
+    w: Vector3,
     lens_radius: f64,
+    focus_dist: f64,
+    // Focal length of a lens with the same field of view on 35mm film, in scene units.
+    focal_length: f64,
+    // Point and normal of the plane of sharp focus, if the lens is tilted.
+    focal_plane: Option<(Point3, Vector3)>,
+    pub aperture: Aperture,
 }
 
-impl Camera {
+impl PerspectiveCamera {
     pub fn new(
         look_from: Point3,
         look_at: Point3,
@@ -21,15 +70,23 @@ impl Camera {
         aspect_ratio: f64,
         aperture: f64,
         focus_dist: f64,
-    ) -> Camera {
+    ) -> Result<PerspectiveCamera> {
+        check(
+            v_fov > 0.0 && v_fov < 180.0,
+            "v_fov must be between 0 and 180 degrees",
+        )?;
+        check(aspect_ratio > 0.0, "aspect_ratio must be positive")?;
+        check(aperture >= 0.0, "aperture must not be negative")?;
+        check(
+            focus_dist > 0.0 && focus_dist.is_finite(),
+            "focus_dist must be positive and finite",
+        )?;
         let theta = degrees_to_radians(v_fov);
         let h = (theta / 2.0).tan();
         let viewport_height = 2.0 * h;
         let viewport_width = aspect_ratio * viewport_height;
 
-        let w = (look_from - look_at).unit();
-        let u = v_up.cross(&w).unit();
-        let v = w.cross(&u);
+        let (u, v, w) = basis(look_from, look_at, v_up)?;
 
         let origin = look_from;
         let horizontal = focus_dist * viewport_width * u;
@@ -38,22 +95,391 @@ impl Camera {
 
         let lens_radius = aperture / 2.0;
 
-        Camera {
+        Ok(PerspectiveCamera {
             origin,
             lower_left_corner,
             horizontal,
             vertical,
             u,
             v,
+            w,
             lens_radius,
+            focus_dist,
+            // Half the height of 35mm film is 12mm.
+            focal_length: 0.012 / h,
+            focal_plane: Option::None,
+            aperture: Aperture::circle(),
+        })
+    }
+
+    // Shifts the lens across the film by fractions of the image width and height, which frames
+    // off-center without turning the camera. Shifting up keeps the verticals of a tall building
+    // parallel where tilting the camera would make them converge.
+    pub fn shift(&mut self, x: f64, y: f64) {
+        self.offset_window(x * self.horizontal + y * self.vertical);
+    }
+
+    // Tilts the lens down by `tilt` and swings it right by `swing` degrees, which tilts the plane
+    // of sharp focus by the Scheimpflug principle. The plane still passes through the point in
+    // focus on the axis, and also through the hinge line, where the plane through the lens
+    // parallel to the film meets the front focal plane of the lens.
+    pub fn tilt(&mut self, tilt: f64, swing: f64) {
+        let (tilt, swing) = (degrees_to_radians(tilt), degrees_to_radians(swing));
+        let axis = swing.sin() * self.u
+            - tilt.sin() * swing.cos() * self.v
+            - tilt.cos() * swing.cos() * self.w;
+        let focus = self.origin - self.focus_dist * self.w;
+
+        // Lens axis projected onto the plane parallel to the film.
+        let across = axis - axis.dot(&self.w) * self.w;
+        if across.length_squared() == 0.0 {
+            self.focal_plane = Option::None;
+            return;
         }
+        let hinge = self.origin + self.focal_length / across.length_squared() * across;
+        let hinge_direction = self.w.cross(&axis);
+        let normal = hinge_direction.cross(&(focus - hinge)).unit();
+        self.focal_plane = Option::Some((focus, normal));
     }
-    pub fn get_ray(&self, s: f64, t: f64) -> Ray {
-        let rd = self.lens_radius * Vector3::new_random_in_unit_disk();
+
+    // Moves the image window across the focus plane without moving the lens, so that cameras
+    // at different positions can share one window.
+    pub(crate) fn offset_window(&mut self, offset: Vector3) {
+        self.lower_left_corner += offset;
+    }
+}
+
+impl Camera for PerspectiveCamera {
+    fn get_ray(&self, s: f64, t: f64) -> Option<Ray> {
+        let rd = self.lens_radius * self.aperture.sample(s, t)?;
         let offset = self.u * rd.x + self.v * rd.y;
-        Ray::new(
+        let mut target = self.lower_left_corner + s * self.horizontal + t * self.vertical;
+        if let Some((point, normal)) = &self.focal_plane {
+            // Focus where the ray through the center of the lens meets the tilted plane. Rays
+            // that never reach it in front of the camera are focused at infinity.
+            let direction = target - self.origin;
+            let distance = normal.dot(&(point - self.origin)) / normal.dot(&direction);
+            if !(distance > 0.0 && distance.is_finite()) {
+                return Option::Some(Ray::new(self.origin + offset, direction));
+            }
+            target = self.origin + distance * direction;
+        }
+        Option::Some(Ray::new(
             self.origin + offset,
-            self.lower_left_corner + s * self.horizontal + t * self.vertical - self.origin - offset,
+            target - self.origin - offset,
+        ))
+    }
+}
+
+// Parallel projection, for technical drawings. Rays start on the plane through `look_from`, so
+// anything behind it isn't seen.
+pub struct OrthographicCamera {
+    lower_left_corner: Point3,
+    horizontal: Vector3,
+    vertical: Vector3,
+    direction: Vector3,
+}
+
+impl OrthographicCamera {
+    // `view_height` is the height of the visible area in world units.
+    pub fn new(
+        look_from: Point3,
+        look_at: Point3,
+        v_up: Vector3,
+        view_height: f64,
+        aspect_ratio: f64,
+    ) -> Result<OrthographicCamera> {
+        check(view_height > 0.0, "view_height must be positive")?;
+        check(aspect_ratio > 0.0, "aspect_ratio must be positive")?;
+        let (u, v, w) = basis(look_from, look_at, v_up)?;
+        let horizontal = aspect_ratio * view_height * u;
+        let vertical = view_height * v;
+        Ok(OrthographicCamera {
+            lower_left_corner: look_from - horizontal / 2.0 - vertical / 2.0,
+            horizontal,
+            vertical,
+            direction: -w,
+        })
+    }
+}
+
+impl Camera for OrthographicCamera {
+    fn get_ray(&self, s: f64, t: f64) -> Option<Ray> {
+        Option::Some(Ray::new(
+            self.lower_left_corner + s * self.horizontal + t * self.vertical,
+            self.direction,
+        ))
+    }
+}
+
+pub enum FisheyeProjection {
+    // Distance from the image center is proportional to the angle off the axis.
+    Equidistant,
+    // Equal solid angles cover equal image areas.
+    Equisolid,
+}
+
+// Circular fisheye whose image circle fits the height of the image. Points outside the circle
+// produce no ray.
+pub struct FisheyeCamera {
+    origin: Point3,
+    u: Vector3,
+    v: Vector3,
+    w: Vector3,
+    // Half the field of view across the image circle, in radians.
+    half_fov: f64,
+    aspect_ratio: f64,
+    projection: FisheyeProjection,
+}
+
+impl FisheyeCamera {
+    // `fov` is the angle across the image circle in degrees, up to 360.
+    pub fn new(
+        look_from: Point3,
+        look_at: Point3,
+        v_up: Vector3,
+        fov: f64,
+        aspect_ratio: f64,
+        projection: FisheyeProjection,
+    ) -> Result<FisheyeCamera> {
+        check(fov > 0.0, "fov must be positive")?;
+        check(aspect_ratio > 0.0, "aspect_ratio must be positive")?;
+        let (u, v, w) = basis(look_from, look_at, v_up)?;
+        Ok(FisheyeCamera {
+            origin: look_from,
+            u,
+            v,
+            w,
+            half_fov: degrees_to_radians(fov.min(360.0)) / 2.0,
+            aspect_ratio,
+            projection,
+        })
+    }
+}
+
+impl Camera for FisheyeCamera {
+    fn get_ray(&self, s: f64, t: f64) -> Option<Ray> {
+        // Coordinates relative to the image circle, which has unit radius.
+        let x = (2.0 * s - 1.0) * self.aspect_ratio;
+        let y = 2.0 * t - 1.0;
+        let r = (x * x + y * y).sqrt();
+        if r > 1.0 {
+            return Option::None;
+        }
+        let theta = match self.projection {
+            FisheyeProjection::Equidistant => r * self.half_fov,
+            FisheyeProjection::Equisolid => 2.0 * (r * (self.half_fov / 2.0).sin()).asin(),
+        };
+        let phi = y.atan2(x);
+        let direction = theta.sin() * phi.cos() * self.u + theta.sin() * phi.sin() * self.v
+            - theta.cos() * self.w;
+        Option::Some(Ray::new(self.origin, direction))
+    }
+}
+
+// Full 360 by 180 degree latitude-longitude panorama, meant for images twice as wide as they are
+// tall. The center of the image looks at `look_at`, and the top and bottom rows look along
+// `v_up` and against it. This is the same mapping `ImageEnvironment` reads.
+pub struct EquirectangularCamera {
+    origin: Point3,
+    u: Vector3,
+    v: Vector3,
+    w: Vector3,
+}
+
+impl EquirectangularCamera {
+    pub fn new(look_from: Point3, look_at: Point3, v_up: Vector3) -> Result<EquirectangularCamera> {
+        let (u, v, w) = basis(look_from, look_at, v_up)?;
+        Ok(EquirectangularCamera {
+            origin: look_from,
+            u,
+            v,
+            w,
+        })
+    }
+}
+
+impl Camera for EquirectangularCamera {
+    fn get_ray(&self, s: f64, t: f64) -> Option<Ray> {
+        let phi = (s - 0.5) * 2.0 * PI;
+        let latitude = (t - 0.5) * PI;
+        let direction = latitude.cos() * phi.sin() * self.u + latitude.sin() * self.v
+            - latitude.cos() * phi.cos() * self.w;
+        Option::Some(Ray::new(self.origin, direction))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::camera::{
+        Camera, EquirectangularCamera, FisheyeCamera, FisheyeProjection, OrthographicCamera,
+        PerspectiveCamera,
+    };
+    use crate::vector3::{Vector3 as Point3, Vector3};
+
+    fn assert_near(expected: Vector3, actual: Vector3) {
+        assert!((expected - actual).length() < 1e-9, "{:?}", actual);
+    }
+
+    fn up() -> Vector3 {
+        Vector3::new(0.0, 1.0, 0.0)
+    }
+
+    #[test]
+    fn perspective() {
+        let camera = PerspectiveCamera::new(
+            Point3::zero(),
+            Point3::new(0.0, 0.0, -1.0),
+            up(),
+            90.0,
+            2.0,
+            0.0,
+            1.0,
+        )
+        .unwrap();
+        let r = camera.get_ray(1.0, 1.0).unwrap();
+        assert_near(Vector3::new(2.0, 1.0, -1.0), r.direction);
+    }
+
+    #[test]
+    fn shift() {
+        let mut camera = PerspectiveCamera::new(
+            Point3::zero(),
+            Point3::new(0.0, 0.0, -1.0),
+            up(),
+            90.0,
+            1.0,
+            0.0,
+            1.0,
+        )
+        .unwrap();
+        camera.shift(0.0, 0.5);
+        // The view direction doesn't change, only the window moves up.
+        assert_near(
+            Vector3::new(0.0, 1.0, -1.0),
+            camera.get_ray(0.5, 0.5).unwrap().direction,
+        );
+        assert_near(
+            Vector3::new(0.0, 0.0, -1.0),
+            camera.get_ray(0.5, 0.0).unwrap().direction,
+        );
+    }
+
+    #[test]
+    fn tilt() {
+        let mut camera = PerspectiveCamera::new(
+            Point3::zero(),
+            Point3::new(0.0, 0.0, -1.0),
+            up(),
+            40.0,
+            1.5,
+            0.5,
+            10.0,
+        )
+        .unwrap();
+        camera.tilt(5.0, 0.0);
+        let focal_length = 0.012 / 20.0_f64.to_radians().tan();
+        let hinge = focal_length / 5.0_f64.to_radians().sin();
+        for (s, t) in [(0.5, 0.5), (0.2, 0.1), (0.9, 0.3)] {
+            // Rays through any part of the lens meet on the plane through the hinge line below
+            // the camera and the point in focus on the axis.
+            let p = camera.get_ray(s, t).unwrap().at(1.0);
+            for _ in 0..10 {
+                assert_near(p, camera.get_ray(s, t).unwrap().at(1.0));
+            }
+            assert!((p.y / -hinge + p.z / -10.0 - 1.0).abs() < 1e-9, "{:?}", p);
+        }
+        // The bottom of the image is focused closer than the center, and the top, which looks
+        // above the plane, at infinity.
+        let bottom = camera.get_ray(0.5, 0.0).unwrap().at(1.0);
+        assert!(-bottom.z < 10.0);
+        let top = camera.get_ray(0.5, 1.0).unwrap();
+        assert_near(top.direction, camera.get_ray(0.5, 1.0).unwrap().direction);
+    }
+
+    #[test]
+    fn orthographic() {
+        let camera =
+            OrthographicCamera::new(Point3::new(0.0, 0.0, 5.0), Point3::zero(), up(), 2.0, 1.5)
+                .unwrap();
+        let r = camera.get_ray(0.0, 1.0).unwrap();
+        assert_near(Point3::new(-1.5, 1.0, 5.0), r.origin);
+        assert_near(Vector3::new(0.0, 0.0, -1.0), r.direction);
+    }
+
+    #[test]
+    fn fisheye() {
+        let look_at = Point3::new(0.0, 0.0, -1.0);
+        for projection in [FisheyeProjection::Equidistant, FisheyeProjection::Equisolid] {
+            let camera =
+                FisheyeCamera::new(Point3::zero(), look_at, up(), 180.0, 1.0, projection).unwrap();
+            assert_near(look_at, camera.get_ray(0.5, 0.5).unwrap().direction);
+            // The edge of a 180 degree circle looks sideways.
+            assert_near(
+                Vector3::new(1.0, 0.0, 0.0),
+                camera.get_ray(1.0, 0.5).unwrap().direction,
+            );
+            assert_near(up(), camera.get_ray(0.5, 1.0).unwrap().direction);
+            assert!(camera.get_ray(1.0, 1.0).is_none());
+        }
+
+        // Halfway out, equidistant is at half the angle while equisolid, which compresses the edge
+        // of the circle more, is still closer to the axis.
+        let camera = FisheyeCamera::new(
+            Point3::zero(),
+            look_at,
+            up(),
+            180.0,
+            1.0,
+            FisheyeProjection::Equidistant,
+        )
+        .unwrap();
+        let d = camera.get_ray(0.75, 0.5).unwrap().direction;
+        assert!((d.x - std::f64::consts::FRAC_PI_4.sin()).abs() < 1e-9);
+        let camera = FisheyeCamera::new(
+            Point3::zero(),
+            look_at,
+            up(),
+            180.0,
+            1.0,
+            FisheyeProjection::Equisolid,
         )
+        .unwrap();
+        let d = camera.get_ray(0.75, 0.5).unwrap().direction;
+        assert!(d.x < std::f64::consts::FRAC_PI_4.sin());
+    }
+
+    #[test]
+    fn equirectangular() {
+        let camera =
+            EquirectangularCamera::new(Point3::zero(), Point3::new(0.0, 0.0, -1.0), up()).unwrap();
+        assert_near(
+            Vector3::new(0.0, 0.0, -1.0),
+            camera.get_ray(0.5, 0.5).unwrap().direction,
+        );
+        assert_near(
+            Vector3::new(1.0, 0.0, 0.0),
+            camera.get_ray(0.75, 0.5).unwrap().direction,
+        );
+        assert_near(
+            Vector3::new(0.0, 0.0, 1.0),
+            camera.get_ray(0.0, 0.5).unwrap().direction,
+        );
+        assert_near(up(), camera.get_ray(0.3, 1.0).unwrap().direction);
+    }
+
+    #[test]
+    fn invalid() {
+        let at = Point3::new(0.0, 0.0, -1.0);
+        assert!(PerspectiveCamera::new(at, at, up(), 40.0, 1.5, 0.0, 1.0).is_err());
+        assert!(PerspectiveCamera::new(Point3::zero(), at, up(), 40.0, 0.0, 0.0, 1.0).is_err());
+        // A focus plane at infinity would put the image window there too.
+        let infinity = f64::INFINITY;
+        assert!(
+            PerspectiveCamera::new(Point3::zero(), at, up(), 40.0, 1.5, 0.0, infinity).is_err()
+        );
+        // Looking straight up leaves no way to tell which way is right.
+        assert!(EquirectangularCamera::new(Point3::zero(), up(), up()).is_err());
+        assert!(OrthographicCamera::new(Point3::zero(), at, up(), -1.0, 1.5).is_err());
     }
 }
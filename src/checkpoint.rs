@@ -0,0 +1,166 @@
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::error::{Error, Result};
+
+const MAGIC: &[u8; 8] = b"RTCKPT1\n";
+
+// Something that accumulates samples over a render and can be saved part way through, then
+// restored into a freshly made copy of the same size to carry on.
+pub trait Checkpoint {
+    fn save(&self, out: &mut dyn Write) -> Result<()>;
+
+    fn load(&mut self, input: &mut dyn Read) -> Result<()>;
+}
+
+// Where a progressive render is: the seed its random numbers derive from and how many passes
+// over the image are done.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RenderState {
+    pub seed: u64,
+    pub passes: u32,
+}
+
+// Writes the state and parts of a render to `path`. The file is replaced only once it is
+// complete, so a render killed while saving keeps its previous checkpoint.
+pub fn save_checkpoint<P: AsRef<Path>>(
+    path: P,
+    state: &RenderState,
+    parts: &[&dyn Checkpoint],
+) -> Result<()> {
+    let path = path.as_ref();
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    {
+        let mut out = BufWriter::new(File::create(&temporary)?);
+        out.write_all(MAGIC)?;
+        write_u64(&mut out, state.seed)?;
+        write_u64(&mut out, state.passes as u64)?;
+        for part in parts {
+            part.save(&mut out)?;
+        }
+        out.flush()?;
+    }
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+// Restores the parts of a render saved by `save_checkpoint`, which must be given in the same
+// order and sizes, and returns its state.
+pub fn load_checkpoint<P: AsRef<Path>>(
+    path: P,
+    parts: &mut [&mut dyn Checkpoint],
+) -> Result<RenderState> {
+    let mut input = BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::Parse("not a render checkpoint".to_string()));
+    }
+    let seed = read_u64(&mut input)?;
+    let passes = read_u64(&mut input)? as u32;
+    for part in parts.iter_mut() {
+        part.load(&mut input)?;
+    }
+    Ok(RenderState { seed, passes })
+}
+
+pub(crate) fn write_u64(out: &mut dyn Write, value: u64) -> Result<()> {
+    out.write_all(&value.to_le_bytes())?;
+    Ok(())
+}
+
+pub(crate) fn read_u64(input: &mut dyn Read) -> Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub(crate) fn write_f64(out: &mut dyn Write, value: f64) -> Result<()> {
+    out.write_all(&value.to_le_bytes())?;
+    Ok(())
+}
+
+pub(crate) fn read_f64(input: &mut dyn Read) -> Result<f64> {
+    Ok(f64::from_bits(read_u64(input)?))
+}
+
+// Reads a length written with `write_u64` and checks it against the size of what is being
+// restored.
+pub(crate) fn read_len(input: &mut dyn Read, expected: usize, what: &str) -> Result<()> {
+    let len = read_u64(input)? as usize;
+    if len != expected {
+        return Err(Error::Parse(format!(
+            "checkpoint has {} {}, expected {}",
+            len, what, expected
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use crate::checkpoint::{load_checkpoint, save_checkpoint, RenderState};
+    use crate::film::{ChannelFilter, Film};
+    use crate::filter::TentFilter;
+    use crate::util::{random_f64, seed_pixel};
+    use crate::vector3::Vector3 as Color;
+
+    fn pass(film: &mut Film, seed: u64, pass: u32) {
+        for y in 0..film.height {
+            for x in 0..film.width {
+                seed_pixel(seed, pass, x, y);
+                let (fx, fy) = (x as f64 + random_f64(), y as f64 + random_f64());
+                film.add_sample(fx, fy, Color::new(random_f64(), 0.5, 0.25));
+                let id = (random_f64() * 3.0).floor();
+                film.add_channel_sample(fx, fy, &[random_f64(), id]);
+            }
+        }
+    }
+
+    #[test]
+    fn resume() {
+        let film = || {
+            let mut film = Film::new(4, 3, Box::new(TentFilter { radius: 1.0 }));
+            film.add_channels(&[
+                ("depth.Z", ChannelFilter::Nearest),
+                ("object.id", ChannelFilter::Majority),
+            ]);
+            film
+        };
+        let mut straight = film();
+        for i in 0..4 {
+            pass(&mut straight, 7, i);
+        }
+
+        let path = env::temp_dir().join(format!("raytracer-{}.ckpt", std::process::id()));
+        let mut first = film();
+        for i in 0..2 {
+            pass(&mut first, 7, i);
+        }
+        let state = RenderState { seed: 7, passes: 2 };
+        save_checkpoint(&path, &state, &[&first]).unwrap();
+
+        let mut resumed = film();
+        assert_eq!(state, load_checkpoint(&path, &mut [&mut resumed]).unwrap());
+        fs::remove_file(&path).unwrap();
+        for i in state.passes..4 {
+            pass(&mut resumed, state.seed, i);
+        }
+        assert_eq!(straight.pixels(), resumed.pixels());
+        assert_eq!(straight.error(1, 1), resumed.error(1, 1));
+        assert_eq!(straight.channel("depth.Z"), resumed.channel("depth.Z"));
+        assert_eq!(straight.channel("object.id"), resumed.channel("object.id"));
+
+        // A film of another size is refused.
+        save_checkpoint(&path, &state, &[&first]).unwrap();
+        let mut other = Film::new(3, 3, Box::new(TentFilter { radius: 1.0 }));
+        assert!(load_checkpoint(&path, &mut [&mut other]).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
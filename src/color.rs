@@ -1,25 +1,31 @@
 use std::io::{Stdout, Write};
 
+use crate::error::Result;
 use crate::util::clamp;
 use crate::vector3::Vector3 as Color;
 
-pub fn write_color(mut stdout: Stdout, pixel_color: Color, samples_per_pixel: u16) {
-    let mut r = pixel_color.x;
-    let mut g = pixel_color.y;
-    let mut b = pixel_color.z;
+// Relative luminance of a linear sRGB color.
+pub fn luminance(color: &Color) -> f64 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
 
-    // Divide the color by the number of samples and gamma-correct for gamma=2.0.
+pub fn write_color(mut stdout: Stdout, pixel_color: Color, samples_per_pixel: u16) -> Result<()> {
+    // Divide the color by the number of samples.
     let scale = 1.0 / samples_per_pixel as f64;
-    r = (scale * r).sqrt();
-    g = (scale * g).sqrt();
-    b = (scale * b).sqrt();
+    write_rgb(&mut stdout, scale * pixel_color)
+}
+
+// Writes a color as a line of plain PPM values, gamma-corrected for gamma=2.0.
+pub fn write_rgb<W: Write>(out: &mut W, color: Color) -> Result<()> {
+    let r = color.x.max(0.0).sqrt();
+    let g = color.y.max(0.0).sqrt();
+    let b = color.z.max(0.0).sqrt();
 
-    stdout
-        .write_fmt(format_args!(
-            "{} {} {}\n",
-            (256.0 * clamp(r, 0.0, 0.999)) as u8,
-            (256.0 * clamp(g, 0.0, 0.999)) as u8,
-            (256.0 * clamp(b, 0.0, 0.999)) as u8
-        ))
-        .unwrap()
+    out.write_fmt(format_args!(
+        "{} {} {}\n",
+        (256.0 * clamp(r, 0.0, 0.999)) as u8,
+        (256.0 * clamp(g, 0.0, 0.999)) as u8,
+        (256.0 * clamp(b, 0.0, 0.999)) as u8
+    ))?;
+    Ok(())
 }
@@ -0,0 +1,283 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+use crate::checkpoint::{read_f64, read_len, read_u64, write_f64, write_u64, Checkpoint};
+use crate::error::{Error, Result};
+
+// MurmurHash3, x86 32-bit variant, which Cryptomatte uses to turn names into IDs.
+pub fn murmur3_32(key: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+    let mix = |k: u32| k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+
+    let mut h = seed;
+    let mut blocks = key.chunks_exact(4);
+    for block in &mut blocks {
+        h ^= mix(u32::from_le_bytes([block[0], block[1], block[2], block[3]]));
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        let k = tail
+            .iter()
+            .rev()
+            .fold(0_u32, |k, &byte| (k << 8) | byte as u32);
+        h ^= mix(k);
+    }
+
+    h ^= key.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^ (h >> 16)
+}
+
+// Stable ID of a name, as stored in ID passes and Cryptomatte layers. IDs are floats with the
+// bits of the hash, nudged so that none is denormal, infinite or NaN.
+pub fn name_id(name: &str) -> f32 {
+    let mut hash = murmur3_32(name.as_bytes(), 0);
+    let exponent = (hash >> 23) & 0xff;
+    if exponent == 0 || exponent == 255 {
+        hash ^= 1 << 23;
+    }
+    f32::from_bits(hash)
+}
+
+// Per-pixel coverage of the objects or materials seen by the camera, for extracting
+// anti-aliased mattes in compositing. Layers and metadata follow the Cryptomatte specification.
+pub struct Cryptomatte {
+    // Type of names, such as CryptoObject or CryptoMaterial, which prefixes the layers.
+    pub name: String,
+    pub width: usize,
+    pub height: usize,
+    // Weights of the IDs seen in each pixel, and the total weight of its samples.
+    coverage: Vec<Vec<(f32, f64)>>,
+    totals: Vec<f64>,
+    // Names of all IDs that may be seen, keyed by the bits of the ID.
+    manifest: BTreeMap<u32, String>,
+}
+
+impl Cryptomatte {
+    pub fn new(name: &str, width: usize, height: usize) -> Cryptomatte {
+        Cryptomatte {
+            name: name.to_string(),
+            width,
+            height,
+            coverage: vec![Vec::new(); width * height],
+            totals: vec![0.0; width * height],
+            manifest: BTreeMap::new(),
+        }
+    }
+
+    // Adds a name to the manifest, so that samples can be added with its ID alone.
+    pub fn add_name(&mut self, name: &str) {
+        self.manifest
+            .entry(name_id(name).to_bits())
+            .or_insert_with(|| name.to_string());
+    }
+
+    // Adds a sample at raster position (x, y) that saw the object or material with ID `id`, as
+    // returned by `name_id` for a name added with `add_name`, or nothing if None.
+    pub fn add_sample(&mut self, x: f64, y: f64, id: Option<f32>) {
+        let (column, row) = (x.floor(), y.floor());
+        if column < 0.0 || row < 0.0 || column >= self.width as f64 || row >= self.height as f64 {
+            return;
+        }
+        let i = row as usize * self.width + column as usize;
+        self.totals[i] += 1.0;
+        let id = match id {
+            Some(id) => id,
+            None => return,
+        };
+        match self.coverage[i].iter_mut().find(|(other, _)| *other == id) {
+            Some((_, weight)) => *weight += 1.0,
+            None => self.coverage[i].push((id, 1.0)),
+        }
+    }
+
+    // IDs in a pixel with the fraction of the pixel each covers, most coverage first.
+    pub fn ranked(&self, x: usize, y: usize) -> Vec<(f32, f64)> {
+        let i = y * self.width + x;
+        let mut ranked: Vec<(f32, f64)> = self.coverage[i]
+            .iter()
+            .map(|&(id, weight)| (id, weight / self.totals[i]))
+            .collect();
+        ranked.sort_by(|a, b| {
+            b.1.partial_cmp(&a.1)
+                .unwrap()
+                .then(a.0.to_bits().cmp(&b.0.to_bits()))
+        });
+        ranked
+    }
+
+    // Channels holding the `ranks` best covering IDs of every pixel, two to a layer of the form
+    // Name00.RGBA with the ID and coverage of one rank in R and G and of the next in B and A.
+    pub fn channels(&self, ranks: usize) -> Vec<(String, Vec<f64>)> {
+        let layers = ranks.div_ceil(2);
+        let mut channels = Vec::new();
+        for layer in 0..layers {
+            for channel in ["R", "G", "B", "A"] {
+                let name = format!("{}{:02}.{}", self.name, layer, channel);
+                channels.push((name, vec![0.0; self.width * self.height]));
+            }
+        }
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let i = y * self.width + x;
+                for (rank, (id, coverage)) in self.ranked(x, y).into_iter().take(ranks).enumerate()
+                {
+                    channels[2 * rank].1[i] = id as f64;
+                    channels[2 * rank + 1].1[i] = coverage;
+                }
+            }
+        }
+        channels
+    }
+
+    // Header attributes that describe the layers, with a manifest of the names of all IDs.
+    pub fn metadata(&self) -> Vec<(String, String)> {
+        let key = format!("{:08x}", murmur3_32(self.name.as_bytes(), 0));
+        let prefix = format!("cryptomatte/{}/", &key[..7]);
+        let manifest = self
+            .manifest
+            .iter()
+            .map(|(id, name)| format!("\"{}\":\"{:08x}\"", escape(name), id))
+            .collect::<Vec<String>>()
+            .join(",");
+        vec![
+            (prefix.clone() + "name", self.name.clone()),
+            (prefix.clone() + "hash", "MurmurHash3_32".to_string()),
+            (
+                prefix.clone() + "conversion",
+                "uint32_to_float32".to_string(),
+            ),
+            (prefix + "manifest", format!("{{{}}}", manifest)),
+        ]
+    }
+}
+
+impl Checkpoint for Cryptomatte {
+    fn save(&self, out: &mut dyn Write) -> Result<()> {
+        write_u64(out, self.totals.len() as u64)?;
+        for (coverage, total) in self.coverage.iter().zip(&self.totals) {
+            write_f64(out, *total)?;
+            write_u64(out, coverage.len() as u64)?;
+            for (id, weight) in coverage {
+                write_u64(out, id.to_bits() as u64)?;
+                write_f64(out, *weight)?;
+            }
+        }
+        write_u64(out, self.manifest.len() as u64)?;
+        for (id, name) in &self.manifest {
+            write_u64(out, *id as u64)?;
+            write_u64(out, name.len() as u64)?;
+            out.write_all(name.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, input: &mut dyn Read) -> Result<()> {
+        read_len(input, self.totals.len(), "pixels")?;
+        for (coverage, total) in self.coverage.iter_mut().zip(self.totals.iter_mut()) {
+            *total = read_f64(input)?;
+            let count = read_u64(input)?;
+            coverage.clear();
+            for _ in 0..count {
+                let id = f32::from_bits(read_u64(input)? as u32);
+                coverage.push((id, read_f64(input)?));
+            }
+        }
+        self.manifest.clear();
+        for _ in 0..read_u64(input)? {
+            let id = read_u64(input)? as u32;
+            let mut name = vec![0; read_u64(input)? as usize];
+            input.read_exact(&mut name)?;
+            let name = String::from_utf8(name)
+                .map_err(|_| Error::Parse("invalid name in checkpoint".to_string()))?;
+            self.manifest.insert(id, name);
+        }
+        Ok(())
+    }
+}
+
+// Escapes a string for a JSON string literal.
+fn escape(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cryptomatte::{murmur3_32, name_id, Cryptomatte};
+
+    #[test]
+    fn murmur() {
+        assert_eq!(0, murmur3_32(b"", 0));
+        assert_eq!(0x248b_fa47, murmur3_32(b"hello", 0));
+        assert_eq!(
+            0x2e4f_f723,
+            murmur3_32(b"The quick brown fox jumps over the lazy dog", 0)
+        );
+        // Every ID is a normal float, so it survives being stored and blended.
+        for name in ["ground", "glass", "sphere12", ""] {
+            assert!(name_id(name).is_normal() || name_id(name) == 0.0);
+        }
+    }
+
+    #[test]
+    fn coverage() {
+        let mut matte = Cryptomatte::new("CryptoObject", 2, 1);
+        matte.add_name("ball");
+        matte.add_name("ground");
+        for _ in 0..3 {
+            matte.add_sample(0.5, 0.5, Some(name_id("ball")));
+        }
+        matte.add_sample(0.5, 0.5, Some(name_id("ground")));
+        matte.add_sample(0.5, 0.5, None);
+        matte.add_sample(1.5, 0.5, Some(name_id("ground")));
+
+        let ranked = matte.ranked(0, 0);
+        assert_eq!(
+            vec![(name_id("ball"), 0.6), (name_id("ground"), 0.2)],
+            ranked
+        );
+
+        let channels = matte.channels(4);
+        let names: Vec<&str> = channels.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            vec![
+                "CryptoObject00.R",
+                "CryptoObject00.G",
+                "CryptoObject00.B",
+                "CryptoObject00.A",
+                "CryptoObject01.R",
+                "CryptoObject01.G",
+                "CryptoObject01.B",
+                "CryptoObject01.A",
+            ],
+            names
+        );
+        assert_eq!(
+            vec![name_id("ball") as f64, name_id("ground") as f64],
+            channels[0].1
+        );
+        assert_eq!(vec![0.6, 1.0], channels[1].1);
+        assert_eq!(vec![0.0, 0.0], channels[4].1);
+
+        let metadata = matte.metadata();
+        assert_eq!("cryptomatte/3ae39a5/name", metadata[0].0);
+        assert_eq!("CryptoObject", metadata[0].1);
+        let manifest = &metadata[3].1;
+        assert!(manifest.contains(&format!("\"ball\":\"{:08x}\"", name_id("ball").to_bits())));
+        assert!(manifest.contains("\"ground\":"));
+    }
+}
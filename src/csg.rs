@@ -0,0 +1,151 @@
+pub use crate::hittable::Operation;
+
+use crate::hittable::{combine, HitRecord, Hittable, Interval};
+use crate::ray::Ray;
+use crate::stats::{count_test, Primitive};
+
+// Constructive solid geometry node. Both operands are expected to be closed objects.
+pub struct Csg {
+    pub left: Box<dyn Hittable>,
+    pub right: Box<dyn Hittable>,
+    pub operation: Operation,
+}
+
+impl Csg {
+    pub fn new<L, R>(left: L, right: R, operation: Operation) -> Csg
+    where
+        L: Hittable + 'static,
+        R: Hittable + 'static,
+    {
+        Csg {
+            left: Box::new(left),
+            right: Box::new(right),
+            operation,
+        }
+    }
+    pub fn union<L: Hittable + 'static, R: Hittable + 'static>(left: L, right: R) -> Csg {
+        Csg::new(left, right, Operation::Union)
+    }
+    pub fn intersection<L: Hittable + 'static, R: Hittable + 'static>(left: L, right: R) -> Csg {
+        Csg::new(left, right, Operation::Intersection)
+    }
+    pub fn difference<L: Hittable + 'static, R: Hittable + 'static>(left: L, right: R) -> Csg {
+        Csg::new(left, right, Operation::Difference)
+    }
+}
+
+impl Hittable for Csg {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        // The nearest surface is the first bound of the combined intervals, or the first exit
+        // if the ray starts inside the solid.
+        let hit = self
+            .intervals(r, t_min, t_max)
+            .into_iter()
+            .flat_map(|interval| vec![interval.enter, interval.exit])
+            .flatten()
+            .find(|hit| t_min <= hit.t && hit.t <= t_max);
+        count_test(Primitive::Csg, hit.is_some());
+        hit
+    }
+
+    fn intervals(&self, r: &Ray, t_min: f64, t_max: f64) -> Vec<Interval<'_>> {
+        combine(
+            self.left.intervals(r, t_min, t_max),
+            self.right.intervals(r, t_min, t_max),
+            &self.operation,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::csg::Csg;
+    use crate::hittable::{HitRecord, Hittable};
+    use crate::material::Lambertian;
+    use crate::ray::Ray;
+    use crate::sphere::Sphere;
+    use crate::vector3::{Vector3 as Point3, Vector3};
+
+    fn sphere(z: f64) -> Sphere<Lambertian> {
+        Sphere::new(
+            Point3::new(0.0, 0.0, z),
+            1.0,
+            Lambertian::new(Vector3::zero()),
+        )
+        .unwrap()
+    }
+
+    fn ray() -> Ray {
+        Ray::new(Point3::new(0.0, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0))
+    }
+
+    fn bounds(csg: &Csg) -> Vec<(f64, f64)> {
+        csg.intervals(&ray(), 0.001, f64::INFINITY)
+            .iter()
+            .map(|i| (i.t_enter, i.t_exit))
+            .collect()
+    }
+
+    #[test]
+    fn union() {
+        assert_eq!(
+            vec![(3.0, 6.0)],
+            bounds(&Csg::union(sphere(0.0), sphere(1.0)))
+        );
+        assert_eq!(
+            vec![(1.0, 3.0), (4.0, 6.0)],
+            bounds(&Csg::union(sphere(0.0), sphere(3.0)))
+        );
+    }
+
+    #[test]
+    fn intersection() {
+        assert_eq!(
+            vec![(4.0, 5.0)],
+            bounds(&Csg::intersection(sphere(0.0), sphere(1.0)))
+        );
+        assert!(bounds(&Csg::intersection(sphere(0.0), sphere(3.0))).is_empty());
+    }
+
+    #[test]
+    fn difference() {
+        let csg = Csg::difference(sphere(0.0), sphere(1.0));
+        assert_eq!(vec![(5.0, 6.0)], bounds(&csg));
+
+        // The ray enters the solid through the cavity left by the right sphere.
+        let hit = csg.hit(&ray(), 0.001, f64::INFINITY).unwrap();
+        assert_eq!(5.0, hit.t);
+        assert!(hit.front_face);
+        assert_eq!(Vector3::new(0.0, 0.0, 1.0), hit.normal);
+    }
+
+    #[test]
+    fn starts_inside() {
+        let csg = Csg::difference(sphere(5.0), sphere(10.0));
+        let hit = csg.hit(&ray(), 0.001, f64::INFINITY).unwrap();
+        assert_eq!(1.0, hit.t);
+        assert!(!hit.front_face);
+        assert_eq!(vec![(0.001, 1.0)], bounds(&csg));
+    }
+
+    // Only exposes `hit`, so intervals come from marching the ray.
+    struct Opaque(Sphere<Lambertian>);
+
+    impl Hittable for Opaque {
+        fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+            self.0.hit(r, t_min, t_max)
+        }
+    }
+
+    #[test]
+    fn marched() {
+        let csg = Csg::difference(Opaque(sphere(0.0)), Opaque(sphere(1.0)));
+        assert_eq!(vec![(5.0, 6.0)], bounds(&csg));
+    }
+
+    #[test]
+    fn nested() {
+        let csg = Csg::difference(Csg::union(sphere(0.0), sphere(1.0)), sphere(-1.0));
+        assert_eq!(vec![(3.0, 5.0)], bounds(&csg));
+    }
+}
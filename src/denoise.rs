@@ -0,0 +1,328 @@
+use std::io::{Read, Write};
+
+use crate::checkpoint::{read_f64, read_len, read_u64, write_f64, write_u64, Checkpoint};
+use crate::color::luminance;
+use crate::error::Result;
+use crate::integrator::Features;
+use crate::vector3::{Vector3 as Color, Vector3};
+
+// Per-pixel averages of the features of the camera rays.
+pub struct FeatureBuffers {
+    pub width: usize,
+    pub height: usize,
+    albedo: Vec<Color>,
+    normal: Vec<Vector3>,
+    depth: Vec<f64>,
+    // Rays that escaped are counted separately, so that their infinite depth doesn't swamp the
+    // depth of the surfaces seen by the rest of the pixel.
+    hits: Vec<u32>,
+    counts: Vec<u32>,
+}
+
+impl FeatureBuffers {
+    pub fn new(width: usize, height: usize) -> FeatureBuffers {
+        FeatureBuffers {
+            width,
+            height,
+            albedo: vec![Color::zero(); width * height],
+            normal: vec![Vector3::zero(); width * height],
+            depth: vec![0.0; width * height],
+            hits: vec![0; width * height],
+            counts: vec![0; width * height],
+        }
+    }
+
+    // Adds the features of a camera ray through raster position (x, y).
+    pub fn add_sample(&mut self, x: f64, y: f64, features: &Features) {
+        let (column, row) = (x.floor(), y.floor());
+        if column < 0.0 || row < 0.0 || column >= self.width as f64 || row >= self.height as f64 {
+            return;
+        }
+        let i = row as usize * self.width + column as usize;
+        self.albedo[i] += features.albedo;
+        self.counts[i] += 1;
+        if features.depth.is_finite() {
+            self.normal[i] += features.normal;
+            self.depth[i] += features.depth;
+            self.hits[i] += 1;
+        }
+    }
+
+    pub fn albedo(&self, i: usize) -> Color {
+        if self.counts[i] == 0 {
+            return Color::zero();
+        }
+        self.albedo[i] / self.counts[i] as f64
+    }
+
+    // Average normal of the surfaces hit, not renormalized, so it is shorter at silhouettes.
+    pub fn normal(&self, i: usize) -> Vector3 {
+        if self.hits[i] == 0 {
+            return Vector3::zero();
+        }
+        self.normal[i] / self.hits[i] as f64
+    }
+
+    // Average depth of the surfaces hit, infinite if most rays escaped.
+    pub fn depth(&self, i: usize) -> f64 {
+        if 2 * self.hits[i] <= self.counts[i] {
+            return f64::INFINITY;
+        }
+        self.depth[i] / self.hits[i] as f64
+    }
+}
+
+impl Checkpoint for FeatureBuffers {
+    fn save(&self, out: &mut dyn Write) -> Result<()> {
+        write_u64(out, self.counts.len() as u64)?;
+        for i in 0..self.counts.len() {
+            let (albedo, normal) = (self.albedo[i], self.normal[i]);
+            for value in [albedo.x, albedo.y, albedo.z, normal.x, normal.y, normal.z] {
+                write_f64(out, value)?;
+            }
+            write_f64(out, self.depth[i])?;
+            write_u64(out, self.hits[i] as u64)?;
+            write_u64(out, self.counts[i] as u64)?;
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, input: &mut dyn Read) -> Result<()> {
+        read_len(input, self.counts.len(), "pixels")?;
+        for i in 0..self.counts.len() {
+            self.albedo[i] = Color::new(read_f64(input)?, read_f64(input)?, read_f64(input)?);
+            self.normal[i] = Vector3::new(read_f64(input)?, read_f64(input)?, read_f64(input)?);
+            self.depth[i] = read_f64(input)?;
+            self.hits[i] = read_u64(input)? as u32;
+            self.counts[i] = read_u64(input)? as u32;
+        }
+        Ok(())
+    }
+}
+
+// Edge-avoiding a-trous wavelet filter (Dammertz et al., "Edge-Avoiding A-Trous Wavelet
+// Transform for fast Global Illumination Filtering", 2010), in the style of SVGF. Each pass
+// blurs with a 5x5 B3 spline kernel whose taps are spread twice as far apart as in the previous
+// pass, while weights on differences in color, albedo, normal and depth keep edges sharp. The
+// noise of every pixel is estimated from its neighborhood and filtered along with the image.
+// Lighting is filtered apart from albedo, so that textures aren't blurred.
+#[derive(Copy, Clone, Debug)]
+pub struct Denoiser {
+    pub iterations: usize,
+    // How far apart albedos, normals and relative depths may be before the filter stops mixing
+    // pixels, and brightness in multiples of the standard deviation of the noise. Smaller is
+    // sharper.
+    pub sigma_color: f64,
+    pub sigma_albedo: f64,
+    pub sigma_normal: f64,
+    pub sigma_depth: f64,
+}
+
+impl Default for Denoiser {
+    fn default() -> Denoiser {
+        Denoiser {
+            iterations: 5,
+            sigma_color: 4.0,
+            sigma_albedo: 0.1,
+            sigma_normal: 0.3,
+            sigma_depth: 0.05,
+        }
+    }
+}
+
+const KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+// Keeps dark albedos from blowing up the demodulated lighting.
+const ALBEDO_EPSILON: f64 = 1e-3;
+
+impl Denoiser {
+    // Denoises an image stored row by row, returning the filtered image.
+    pub fn denoise(&self, beauty: &[Color], features: &FeatureBuffers) -> Vec<Color> {
+        let (width, height) = (features.width, features.height);
+        let albedo: Vec<Color> = (0..beauty.len()).map(|i| features.albedo(i)).collect();
+        let normal: Vec<Vector3> = (0..beauty.len()).map(|i| features.normal(i)).collect();
+        let depth: Vec<f64> = (0..beauty.len()).map(|i| features.depth(i)).collect();
+
+        let demodulate = |c: f64, a: f64| if a > ALBEDO_EPSILON { c / a } else { c };
+        let mut lighting: Vec<Color> = beauty
+            .iter()
+            .zip(&albedo)
+            .map(|(c, a)| {
+                Color::new(
+                    demodulate(c.x, a.x),
+                    demodulate(c.y, a.y),
+                    demodulate(c.z, a.z),
+                )
+            })
+            .collect();
+
+        // Noise level of every pixel, from the spread of its neighborhood.
+        let mut variance: Vec<f64> = (0..lighting.len())
+            .map(|p| {
+                let (x, y) = ((p % width) as isize, (p / width) as isize);
+                let mut sum = 0.0;
+                let mut sum_squares = 0.0;
+                let mut count = 0.0;
+                for qy in (y - 1).max(0)..(y + 2).min(height as isize) {
+                    for qx in (x - 1).max(0)..(x + 2).min(width as isize) {
+                        let l = luminance(&lighting[qy as usize * width + qx as usize]);
+                        sum += l;
+                        sum_squares += l * l;
+                        count += 1.0;
+                    }
+                }
+                (sum_squares / count - (sum / count).powi(2)).max(0.0)
+            })
+            .collect();
+
+        for iteration in 0..self.iterations {
+            let step = 1 << iteration;
+            let mut filtered = vec![Color::zero(); lighting.len()];
+            let mut filtered_variance = vec![0.0; lighting.len()];
+            for y in 0..height {
+                for x in 0..width {
+                    let p = y * width + x;
+                    let luminance_p = luminance(&lighting[p]);
+                    // Differences in brightness are measured against the noise, which shrinks
+                    // with every pass.
+                    let color_scale = self.sigma_color * variance[p].sqrt() + 1e-6;
+                    let mut sum = Color::zero();
+                    let mut sum_variance = 0.0;
+                    let mut weights = 0.0;
+                    for (j, ky) in KERNEL.iter().enumerate() {
+                        let qy = y as isize + (j as isize - 2) * step;
+                        if qy < 0 || qy >= height as isize {
+                            continue;
+                        }
+                        for (i, kx) in KERNEL.iter().enumerate() {
+                            let qx = x as isize + (i as isize - 2) * step;
+                            if qx < 0 || qx >= width as isize {
+                                continue;
+                            }
+                            let q = qy as usize * width + qx as usize;
+                            let color = (luminance_p - luminance(&lighting[q])).abs() / color_scale;
+                            let albedo = (albedo[p] - albedo[q]).length_squared()
+                                / (self.sigma_albedo * self.sigma_albedo);
+                            let normal = (normal[p] - normal[q]).length_squared()
+                                / (self.sigma_normal * self.sigma_normal);
+                            let depth = depth_difference(depth[p], depth[q]) / self.sigma_depth;
+                            let weight = kx * ky * (-color - albedo - normal - depth).exp();
+                            sum += weight * lighting[q];
+                            sum_variance += weight * weight * variance[q];
+                            weights += weight;
+                        }
+                    }
+                    // The center tap always has a positive weight.
+                    filtered[p] = sum / weights;
+                    filtered_variance[p] = sum_variance / (weights * weights);
+                }
+            }
+            lighting = filtered;
+            variance = filtered_variance;
+        }
+
+        lighting
+            .iter()
+            .zip(&albedo)
+            .map(|(l, a)| {
+                let remodulate = |l: f64, a: f64| if a > ALBEDO_EPSILON { l * a } else { l };
+                Color::new(
+                    remodulate(l.x, a.x),
+                    remodulate(l.y, a.y),
+                    remodulate(l.z, a.z),
+                )
+            })
+            .collect()
+    }
+}
+
+// Difference between two depths relative to the nearer one.
+fn depth_difference(a: f64, b: f64) -> f64 {
+    if a.is_infinite() && b.is_infinite() {
+        return 0.0;
+    }
+    if a.is_infinite() || b.is_infinite() {
+        return f64::INFINITY;
+    }
+    (a - b).abs() / a.min(b).max(1e-6)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::denoise::{Denoiser, FeatureBuffers};
+    use crate::integrator::Features;
+    use crate::util::random_f64;
+    use crate::vector3::{Vector3 as Color, Vector3};
+
+    #[test]
+    fn preserves_edges() {
+        // Two walls meeting in a crease down the middle of the image, lit differently and with
+        // plenty of noise.
+        let (width, height) = (32, 16);
+        let mut features = FeatureBuffers::new(width, height);
+        let mut beauty = Vec::new();
+        let mut clean = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                let left = x < width / 2;
+                let normal = if left {
+                    Vector3::new(1.0, 0.0, 0.0)
+                } else {
+                    Vector3::new(0.0, 0.0, 1.0)
+                };
+                let albedo = Color::new(0.8, 0.5, 0.2);
+                features.add_sample(
+                    x as f64 + 0.5,
+                    y as f64 + 0.5,
+                    &Features {
+                        albedo,
+                        normal,
+                        depth: 5.0,
+                    },
+                );
+                let light = if left { 0.2 } else { 0.8 };
+                clean.push(light * albedo);
+                beauty.push((light * (0.5 + random_f64())) * albedo);
+            }
+        }
+
+        let denoised = Denoiser::default().denoise(&beauty, &features);
+        let error = |image: &[Color]| {
+            image
+                .iter()
+                .zip(&clean)
+                .map(|(a, b)| (a - b).length_squared())
+                .sum::<f64>()
+                / image.len() as f64
+        };
+        assert!(error(&denoised) < error(&beauty) / 10.0);
+
+        // Pixels on either side of the crease keep their own brightness.
+        let row = 8 * width;
+        assert!((denoised[row + width / 2 - 1].x - 0.16).abs() < 0.03);
+        assert!((denoised[row + width / 2].x - 0.64).abs() < 0.1);
+    }
+
+    #[test]
+    fn features() {
+        let mut features = FeatureBuffers::new(1, 1);
+        let normal = Vector3::new(0.0, 1.0, 0.0);
+        for depth in [2.0, 4.0, f64::INFINITY] {
+            features.add_sample(
+                0.5,
+                0.5,
+                &Features {
+                    albedo: Color::new(0.3, 0.3, 0.3),
+                    normal: if depth.is_finite() {
+                        normal
+                    } else {
+                        Vector3::zero()
+                    },
+                    depth,
+                },
+            );
+        }
+        assert_eq!(3.0, features.depth(0));
+        assert_eq!(normal, features.normal(0));
+        assert!((features.albedo(0).x - 0.3).abs() < 1e-12);
+    }
+}
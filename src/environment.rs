@@ -0,0 +1,245 @@
+use std::f64::consts::PI;
+use std::path::Path;
+
+use crate::color::luminance;
+use crate::error::Result;
+use crate::image::RgbImage;
+use crate::sampling::Distribution2D;
+use crate::util::{degrees_to_radians, random_f64};
+use crate::vector3::{Vector3 as Color, Vector3};
+
+pub struct EnvironmentSample {
+    // Unit direction towards the environment.
+    pub direction: Vector3,
+    pub radiance: Color,
+    // Density of the direction with respect to solid angle.
+    pub pdf: f64,
+}
+
+// Radiance arriving from infinitely far away, seen by rays that escape the world.
+pub trait Environment {
+    fn radiance(&self, direction: &Vector3) -> Color;
+
+    // Picks a direction to send a shadow ray in. Environments that are too smooth to be worth
+    // sampling directly return None and are only found by rays scattered off surfaces.
+    fn sample(&self) -> Option<EnvironmentSample> {
+        Option::None
+    }
+
+    // Density with which `sample` returns `direction`.
+    fn pdf(&self, _direction: &Vector3) -> f64 {
+        0.0
+    }
+}
+
+pub struct ConstantEnvironment {
+    pub color: Color,
+}
+
+impl Environment for ConstantEnvironment {
+    fn radiance(&self, _: &Vector3) -> Color {
+        self.color
+    }
+}
+
+// Vertical blend between two colors, from straight down to straight up.
+pub struct GradientEnvironment {
+    pub bottom: Color,
+    pub top: Color,
+}
+
+impl Default for GradientEnvironment {
+    fn default() -> GradientEnvironment {
+        GradientEnvironment {
+            bottom: Color::new(1.0, 1.0, 1.0),
+            top: Color::new(0.5, 0.7, 1.0),
+        }
+    }
+}
+
+impl Environment for GradientEnvironment {
+    fn radiance(&self, direction: &Vector3) -> Color {
+        let unit_direction = direction.unit();
+        let t = 0.5 * (unit_direction.y + 1.0);
+        (1.0 - t) * self.bottom + t * self.top
+    }
+}
+
+// Equirectangular (latitude-longitude) environment map. The top row of the image looks
+// straight up and the center column looks down -z before rotation. Directions are importance
+// sampled in proportion to the luminance of the map.
+pub struct ImageEnvironment {
+    image: RgbImage,
+    distribution: Distribution2D,
+    // Rotation around the y axis, in radians.
+    rotation: f64,
+    intensity: f64,
+}
+
+impl ImageEnvironment {
+    // `rotation` turns the map around the y axis, in degrees. `intensity` scales the radiance.
+    pub fn new(image: RgbImage, rotation: f64, intensity: f64) -> Result<ImageEnvironment> {
+        let weights: Vec<f64> = image
+            .pixels
+            .iter()
+            .enumerate()
+            .map(|(i, color)| {
+                // Rows near the poles cover less solid angle.
+                let theta = PI * ((i / image.width) as f64 + 0.5) / image.height as f64;
+                luminance(color) * theta.sin()
+            })
+            .collect();
+        let distribution = Distribution2D::new(&weights, image.width, image.height)?;
+        Ok(ImageEnvironment {
+            image,
+            distribution,
+            rotation: degrees_to_radians(rotation),
+            intensity,
+        })
+    }
+
+    pub fn read<P: AsRef<Path>>(
+        path: P,
+        rotation: f64,
+        intensity: f64,
+    ) -> Result<ImageEnvironment> {
+        ImageEnvironment::new(RgbImage::read(path)?, rotation, intensity)
+    }
+
+    fn rotate(&self, direction: &Vector3, angle: f64) -> Vector3 {
+        let (sin, cos) = angle.sin_cos();
+        Vector3::new(
+            cos * direction.x + sin * direction.z,
+            direction.y,
+            -sin * direction.x + cos * direction.z,
+        )
+    }
+
+    // Maps a direction to image coordinates in [0, 1)^2.
+    fn uv(&self, direction: &Vector3) -> (f64, f64) {
+        let d = self.rotate(&direction.unit(), -self.rotation);
+        let theta = d.y.clamp(-1.0, 1.0).acos();
+        let phi = d.x.atan2(-d.z) + PI;
+        (phi / (2.0 * PI), theta / PI)
+    }
+
+    fn direction(&self, u: f64, v: f64) -> Vector3 {
+        let theta = v * PI;
+        let phi = u * 2.0 * PI - PI;
+        let d = Vector3::new(
+            theta.sin() * phi.sin(),
+            theta.cos(),
+            -theta.sin() * phi.cos(),
+        );
+        self.rotate(&d, self.rotation)
+    }
+}
+
+impl Environment for ImageEnvironment {
+    fn radiance(&self, direction: &Vector3) -> Color {
+        let (u, v) = self.uv(direction);
+        let x = ((u * self.image.width as f64) as usize).min(self.image.width - 1);
+        let y = ((v * self.image.height as f64) as usize).min(self.image.height - 1);
+        self.intensity * self.image.get(x, y)
+    }
+
+    fn sample(&self) -> Option<EnvironmentSample> {
+        let (u, v, map_pdf) = self.distribution.sample(random_f64(), random_f64());
+        let sin_theta = (v * PI).sin();
+        if map_pdf == 0.0 || sin_theta == 0.0 {
+            return Option::None;
+        }
+        let direction = self.direction(u, v);
+        Option::Some(EnvironmentSample {
+            direction,
+            radiance: self.radiance(&direction),
+            // Change of variables from the unit square to the sphere.
+            pdf: map_pdf / (2.0 * PI * PI * sin_theta),
+        })
+    }
+
+    fn pdf(&self, direction: &Vector3) -> f64 {
+        let (u, v) = self.uv(direction);
+        let sin_theta = (v * PI).sin();
+        if sin_theta == 0.0 {
+            return 0.0;
+        }
+        self.distribution.pdf(u, v) / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use crate::environment::{Environment, ImageEnvironment};
+    use crate::image::RgbImage;
+    use crate::vector3::{Vector3 as Color, Vector3};
+
+    // Dark map with a single bright texel.
+    fn map() -> ImageEnvironment {
+        let mut pixels = vec![Color::new(0.1, 0.1, 0.1); 32 * 16];
+        pixels[5 * 32 + 20] = Color::new(100.0, 100.0, 100.0);
+        let image = RgbImage {
+            width: 32,
+            height: 16,
+            pixels,
+        };
+        ImageEnvironment::new(image, 30.0, 2.0).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let environment = map();
+        for (u, v) in [(0.1, 0.2), (0.5, 0.5), (0.9, 0.7)] {
+            let (u2, v2) = environment.uv(&environment.direction(u, v));
+            assert!((u - u2).abs() < 1e-9 && (v - v2).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn samples_bright_texel() {
+        let environment = map();
+        let mut bright = 0;
+        for _ in 0..1000 {
+            let sample = environment.sample().unwrap();
+            assert!((sample.pdf - environment.pdf(&sample.direction)).abs() < 1e-6 * sample.pdf);
+            if sample.radiance.x > 1.0 {
+                assert_eq!(200.0, sample.radiance.x);
+                bright += 1;
+            }
+        }
+        assert!(bright > 600);
+    }
+
+    #[test]
+    fn pdf_integrates_to_one() {
+        let environment = map();
+        let (n_theta, n_phi) = (800, 1600);
+        let mut integral = 0.0;
+        for i in 0..n_theta {
+            let theta = PI * (i as f64 + 0.5) / n_theta as f64;
+            for j in 0..n_phi {
+                let phi = 2.0 * PI * (j as f64 + 0.5) / n_phi as f64;
+                let direction = Vector3::new(
+                    theta.sin() * phi.cos(),
+                    theta.cos(),
+                    theta.sin() * phi.sin(),
+                );
+                let solid_angle = theta.sin() * (PI / n_theta as f64) * (2.0 * PI / n_phi as f64);
+                integral += environment.pdf(&direction) * solid_angle;
+            }
+        }
+        assert!((integral - 1.0).abs() < 0.01, "{}", integral);
+    }
+
+    #[test]
+    fn empty() {
+        let image = RgbImage {
+            width: 0,
+            height: 0,
+            pixels: Vec::new(),
+        };
+        assert!(ImageEnvironment::new(image, 0.0, 1.0).is_err());
+    }
+}
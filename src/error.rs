@@ -0,0 +1,50 @@
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    // Malformed input, such as an image, lens table, photometric file or checkpoint.
+    Parse(String),
+    // Data that can't be written in the requested format.
+    Encode(String),
+    // A value that a constructor or setting can't work with.
+    InvalidParameter(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Parse(message) => write!(f, "invalid data: {}", message),
+            Error::Encode(message) => write!(f, "cannot encode image: {}", message),
+            Error::InvalidParameter(message) => write!(f, "invalid parameter: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Option::Some(e),
+            _ => Option::None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+// Fails with an invalid parameter error unless `condition` holds.
+pub(crate) fn check(condition: bool, message: &str) -> Result<()> {
+    if condition {
+        Ok(())
+    } else {
+        Err(Error::InvalidParameter(message.to_string()))
+    }
+}
@@ -0,0 +1,202 @@
+use std::io::Write;
+
+use crate::error::{Error, Result};
+
+// OpenEXR magic number and version 2, single-part scanline file.
+const MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+const VERSION: u32 = 2;
+// Set in the version field when attribute or channel names are longer than 31 bytes.
+const LONG_NAMES: u32 = 0x400;
+const PIXEL_TYPE_FLOAT: i32 = 2;
+
+// Writes an uncompressed scanline OpenEXR image with a 32-bit float channel for each of
+// `channels`, given as names and values stored row by row from the top. Names of the form
+// layer.channel group channels into layers, as compositors expect of multi-layer files.
+// `attributes` are added to the header as strings.
+pub fn write_exr<W, N, V, K, T>(
+    mut out: W,
+    width: usize,
+    height: usize,
+    channels: &[(N, V)],
+    attributes: &[(K, T)],
+) -> Result<()>
+where
+    W: Write,
+    N: AsRef<str>,
+    V: AsRef<[f64]>,
+    K: AsRef<str>,
+    T: AsRef<str>,
+{
+    // Channels are stored in alphabetical order.
+    let mut channels: Vec<(&str, &[f64])> = channels
+        .iter()
+        .map(|(name, values)| (name.as_ref(), values.as_ref()))
+        .collect();
+    channels.sort_by(|a, b| a.0.cmp(b.0));
+    if width == 0 || height == 0 {
+        return Err(Error::Encode(format!("image is {}x{}", width, height)));
+    }
+    for (name, values) in &channels {
+        if values.len() != width * height {
+            return Err(Error::Encode(format!(
+                "channel {} has {} values for {}x{} pixels",
+                name,
+                values.len(),
+                width,
+                height
+            )));
+        }
+    }
+    let names = channels
+        .iter()
+        .map(|(name, _)| *name)
+        .chain(attributes.iter().map(|(name, _)| name.as_ref()));
+    for name in names {
+        if name.is_empty() || name.len() > 255 || name.contains('\0') {
+            return Err(Error::Encode(format!("invalid name {:?}", name)));
+        }
+    }
+
+    let mut version = VERSION;
+    let names = channels
+        .iter()
+        .map(|(name, _)| *name)
+        .chain(attributes.iter().map(|(name, _)| name.as_ref()));
+    if names.map(str::len).max().unwrap_or(0) > 31 {
+        version |= LONG_NAMES;
+    }
+    out.write_all(&MAGIC)?;
+    out.write_all(&version.to_le_bytes())?;
+
+    let mut list = Vec::new();
+    for (name, _) in &channels {
+        list.extend_from_slice(name.as_bytes());
+        list.push(0);
+        list.extend_from_slice(&PIXEL_TYPE_FLOAT.to_le_bytes());
+        // Not perceptually linear, and three reserved bytes.
+        list.extend_from_slice(&[0, 0, 0, 0]);
+        // Sampled at every pixel in x and y.
+        list.extend_from_slice(&1_i32.to_le_bytes());
+        list.extend_from_slice(&1_i32.to_le_bytes());
+    }
+    list.push(0);
+    let mut window = Vec::new();
+    for value in [0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend_from_slice(&value.to_le_bytes());
+    }
+    let mut header = Vec::new();
+    attribute(&mut header, "channels", "chlist", &list);
+    attribute(&mut header, "compression", "compression", &[0]);
+    attribute(&mut header, "dataWindow", "box2i", &window);
+    attribute(&mut header, "displayWindow", "box2i", &window);
+    attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1.0_f32.to_le_bytes(),
+    );
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1.0_f32.to_le_bytes(),
+    );
+    for (name, value) in attributes {
+        attribute(
+            &mut header,
+            name.as_ref(),
+            "string",
+            value.as_ref().as_bytes(),
+        );
+    }
+    header.push(0);
+    out.write_all(&header)?;
+
+    // Without compression every scanline is its own chunk, and all chunks have the same size:
+    // the row number and data size, followed by the row of each channel in turn.
+    let line_size = width * channels.len() * 4;
+    let chunk_size = 8 + line_size;
+    let first_chunk = 8 + header.len() + 8 * height;
+    for y in 0..height {
+        out.write_all(&((first_chunk + y * chunk_size) as u64).to_le_bytes())?;
+    }
+    for y in 0..height {
+        out.write_all(&(y as i32).to_le_bytes())?;
+        out.write_all(&(line_size as i32).to_le_bytes())?;
+        for (_, values) in &channels {
+            for value in &values[y * width..(y + 1) * width] {
+                out.write_all(&(*value as f32).to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::exr::write_exr;
+
+    fn u32_at(bytes: &[u8], i: usize) -> u32 {
+        u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]])
+    }
+
+    fn f32_at(bytes: &[u8], i: usize) -> f32 {
+        f32::from_bits(u32_at(bytes, i))
+    }
+
+    #[test]
+    fn layout() {
+        let (width, height) = (3, 2);
+        let green = vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0];
+        let depth = vec![10.0, 11.0, 12.0, 13.0, 14.0, 15.0];
+        let mut out = Vec::new();
+        write_exr(
+            &mut out,
+            width,
+            height,
+            &[("depth.Z", depth), ("G", green)],
+            &[("owner", "nobody")],
+        )
+        .unwrap();
+
+        assert_eq!([0x76, 0x2f, 0x31, 0x01], out[..4]);
+        assert_eq!(2, u32_at(&out, 4));
+        // The channel list names the channels in alphabetical order.
+        let names = b"channels\0chlist\0";
+        assert_eq!(names, &out[8..8 + names.len()]);
+        assert_eq!(b"G\0", &out[28..30]);
+        let owner = b"owner\0string\0\x06\0\0\0nobody\0";
+        assert!(out.windows(owner.len()).any(|w| w == owner));
+
+        let header_end = out.len() - height * (8 + width * 2 * 4) - 8 * height;
+        let chunk = u32_at(&out, header_end) as usize;
+        assert_eq!(header_end + 8 * height, chunk);
+        // Row 1 holds G then depth.Z.
+        let chunk = u32_at(&out, header_end + 8) as usize;
+        assert_eq!(1, u32_at(&out, chunk));
+        assert_eq!(24, u32_at(&out, chunk + 4));
+        assert_eq!(3.0, f32_at(&out, chunk + 8));
+        assert_eq!(13.0, f32_at(&out, chunk + 8 + 12));
+        assert_eq!(out.len(), chunk + 8 + 24);
+    }
+
+    #[test]
+    fn invalid() {
+        let channel = |len| vec![("R", vec![0.0; len])];
+        let attributes: &[(&str, &str)] = &[];
+        assert!(write_exr(Vec::new(), 2, 2, &channel(3), attributes).is_err());
+        assert!(write_exr(Vec::new(), 0, 2, &channel(0), attributes).is_err());
+        assert!(write_exr(Vec::new(), 2, 2, &channel(4), attributes).is_ok());
+    }
+}
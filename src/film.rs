@@ -0,0 +1,683 @@
+use std::io::{Read, Write};
+use std::time::Duration;
+
+use crate::checkpoint::{read_f64, read_len, read_u64, write_f64, write_u64, Checkpoint};
+use crate::color::{luminance, write_rgb};
+use crate::error::Result;
+use crate::filter::Filter;
+use crate::vector3::Vector3 as Color;
+
+// Running mean and variance of the luminance of the samples taken for one pixel, updated with
+// Welford's algorithm.
+#[derive(Copy, Clone, Default)]
+struct Welford {
+    count: u32,
+    mean: f64,
+    // Sum of squared differences from the mean.
+    m2: f64,
+}
+
+impl Welford {
+    fn add(&mut self, x: f64) {
+        self.count += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    fn variance(&self) -> f64 {
+        if self.count < 2 {
+            return f64::INFINITY;
+        }
+        self.m2 / (self.count - 1) as f64
+    }
+}
+
+// When to stop sampling a pixel.
+#[derive(Copy, Clone, Debug)]
+pub struct AdaptiveSampling {
+    pub min_samples: u32,
+    pub max_samples: u32,
+    // Largest acceptable relative error of a pixel, as returned by `Film::error`.
+    pub threshold: f64,
+}
+
+impl AdaptiveSampling {
+    // A pixel whose every sample so far was discarded is done after the fewest samples, since
+    // taking more is unlikely to help.
+    pub fn done(&self, film: &Film, x: usize, y: usize) -> bool {
+        let count = film.sample_count(x, y);
+        count >= self.max_samples
+            || (count >= self.min_samples
+                && (film.error(x, y) < self.threshold || film.all_discarded(x, y)))
+    }
+}
+
+// When to stop adding passes over the image, whichever comes first. Without either limit, the
+// render runs until the adaptive sampling is done with every pixel.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Budget {
+    // Wall-clock time the render has to finish in.
+    pub time: Option<Duration>,
+    // Acceptable error of the whole image, as returned by `Film::estimated_error`.
+    pub error: Option<f64>,
+}
+
+impl Budget {
+    // Whether to stop after a pass that took `last_pass`, `elapsed` into the render. Passes
+    // are assumed to take as long as the last, and none is started that would overrun the time.
+    pub fn exhausted(&self, elapsed: Duration, last_pass: Duration, film: &Film) -> bool {
+        if let Some(time) = self.time {
+            if elapsed + last_pass > time {
+                return true;
+            }
+        }
+        match self.error {
+            Some(error) => film.estimated_error() <= error,
+            None => false,
+        }
+    }
+
+    // Whether the time is up `elapsed` into the render, for cutting a pass short when it takes
+    // longer than the last.
+    pub fn out_of_time(&self, elapsed: Duration) -> bool {
+        match self.time {
+            Some(time) => elapsed >= time,
+            None => false,
+        }
+    }
+}
+
+// How the samples of a channel taken within a pixel make its value.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ChannelFilter {
+    // Their average.
+    Mean,
+    // The smallest finite value, or zero if there is none, for depths where rays that escaped
+    // are infinitely far.
+    Nearest,
+    // The value of the most samples, the first seen on a tie, for IDs that mean nothing once
+    // averaged.
+    Majority,
+}
+
+// Accumulates radiance samples into pixels, weighting every sample by the reconstruction filter
+// of each pixel whose center is within the filter radius, so samples also count towards their
+// neighbors. Raster coordinates run from (0, 0) at the top left corner of the image to
+// (width, height), with pixel centers at half-integer positions.
+pub struct Film {
+    pub width: usize,
+    pub height: usize,
+    filter: Box<dyn Filter>,
+    sums: Vec<Color>,
+    weights: Vec<f64>,
+    // Statistics of the samples taken within each pixel, ignoring the filter.
+    statistics: Vec<Welford>,
+    // Samples taken within each pixel but left out of the image.
+    discarded: Vec<u32>,
+    // Named channels for render passes, such as depth or object IDs, which mustn't be blended
+    // across pixels and so are combined within each pixel alone. Sums, or the nearest values,
+    // are stored pixel by pixel with one value for each channel, and the values seen for the
+    // majority channels with how many samples saw each.
+    channel_names: Vec<String>,
+    channel_filters: Vec<ChannelFilter>,
+    channel_sums: Vec<f64>,
+    channel_counts: Vec<u32>,
+    channel_tallies: Vec<Vec<(f64, u32)>>,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize, filter: Box<dyn Filter>) -> Film {
+        Film {
+            width,
+            height,
+            filter,
+            sums: vec![Color::zero(); width * height],
+            weights: vec![0.0; width * height],
+            statistics: vec![Welford::default(); width * height],
+            discarded: vec![0; width * height],
+            channel_names: Vec::new(),
+            channel_filters: Vec::new(),
+            channel_sums: Vec::new(),
+            channel_counts: vec![0; width * height],
+            channel_tallies: Vec::new(),
+        }
+    }
+
+    // Adds channels to be filled by `add_channel_sample`, which must not have been called yet.
+    pub fn add_channels(&mut self, channels: &[(&str, ChannelFilter)]) {
+        assert!(
+            self.channel_counts.iter().all(|&count| count == 0),
+            "channels must be added before any channel samples"
+        );
+        for (name, filter) in channels {
+            self.channel_names.push(name.to_string());
+            self.channel_filters.push(*filter);
+        }
+        let pixels = self.width * self.height;
+        self.channel_sums = (0..pixels)
+            .flat_map(|_| self.channel_filters.iter())
+            .map(|filter| match filter {
+                ChannelFilter::Nearest => f64::INFINITY,
+                _ => 0.0,
+            })
+            .collect();
+        self.channel_tallies = vec![Vec::new(); pixels * self.majority_channels()];
+    }
+
+    pub fn channel_names(&self) -> &[String] {
+        &self.channel_names
+    }
+
+    fn majority_channels(&self) -> usize {
+        self.channel_filters
+            .iter()
+            .filter(|&&filter| filter == ChannelFilter::Majority)
+            .count()
+    }
+
+    // Adds a value for each channel, in the order they were added, for the pixel at raster
+    // position (x, y).
+    pub fn add_channel_sample(&mut self, x: f64, y: f64, values: &[f64]) {
+        assert_eq!(self.channel_names.len(), values.len());
+        let (column, row) = (x.floor(), y.floor());
+        if column < 0.0 || row < 0.0 || column >= self.width as f64 || row >= self.height as f64 {
+            return;
+        }
+        let i = row as usize * self.width + column as usize;
+        let n = values.len();
+        let m = self.majority_channels();
+        let mut tally = i * m;
+        for (c, value) in values.iter().enumerate() {
+            let sum = &mut self.channel_sums[i * n + c];
+            match self.channel_filters[c] {
+                ChannelFilter::Mean => *sum += value,
+                ChannelFilter::Nearest => {
+                    if value.is_finite() && *value < *sum {
+                        *sum = *value;
+                    }
+                }
+                ChannelFilter::Majority => {
+                    let tallies = &mut self.channel_tallies[tally];
+                    match tallies.iter_mut().find(|(seen, _)| seen == value) {
+                        Some((_, count)) => *count += 1,
+                        None => tallies.push((*value, 1)),
+                    }
+                    tally += 1;
+                }
+            }
+        }
+        self.channel_counts[i] += 1;
+    }
+
+    // Values of a channel, row by row from the top, or None if there is no such channel.
+    // Pixels without samples are zero.
+    pub fn channel(&self, name: &str) -> Option<Vec<f64>> {
+        let c = self.channel_names.iter().position(|n| n == name)?;
+        let n = self.channel_names.len();
+        let m = self.majority_channels();
+        // Position of the channel among the majority channels.
+        let k = self.channel_filters[..c]
+            .iter()
+            .filter(|&&filter| filter == ChannelFilter::Majority)
+            .count();
+        let values = self
+            .channel_counts
+            .iter()
+            .enumerate()
+            .map(|(i, &count)| {
+                if count == 0 {
+                    return 0.0;
+                }
+                let sum = self.channel_sums[i * n + c];
+                match self.channel_filters[c] {
+                    ChannelFilter::Mean => sum / count as f64,
+                    ChannelFilter::Nearest if sum.is_finite() => sum,
+                    ChannelFilter::Nearest => 0.0,
+                    ChannelFilter::Majority => {
+                        self.channel_tallies[i * m + k]
+                            .iter()
+                            .fold(
+                                (0.0, 0),
+                                |best, &(value, count)| {
+                                    if count > best.1 {
+                                        (value, count)
+                                    } else {
+                                        best
+                                    }
+                                },
+                            )
+                            .0
+                    }
+                }
+            })
+            .collect();
+        Option::Some(values)
+    }
+
+    pub fn add_sample(&mut self, x: f64, y: f64, color: Color) {
+        let (column, row) = (x.floor(), y.floor());
+        if column >= 0.0 && row >= 0.0 && column < self.width as f64 && row < self.height as f64 {
+            let i = row as usize * self.width + column as usize;
+            self.statistics[i].add(luminance(&color));
+        }
+
+        let radius = self.filter.radius();
+        // Pixels whose centers lie within the radius of the sample.
+        let x0 = (x - 0.5 - radius).ceil().max(0.0) as usize;
+        let x1 = ((x - 0.5 + radius).floor() + 1.0).clamp(0.0, self.width as f64) as usize;
+        let y0 = (y - 0.5 - radius).ceil().max(0.0) as usize;
+        let y1 = ((y - 0.5 + radius).floor() + 1.0).clamp(0.0, self.height as f64) as usize;
+        for py in y0..y1 {
+            for px in x0..x1 {
+                let weight = self
+                    .filter
+                    .evaluate(x - (px as f64 + 0.5), y - (py as f64 + 0.5));
+                if weight == 0.0 {
+                    continue;
+                }
+                let i = py * self.width + px;
+                self.sums[i] += weight * color;
+                self.weights[i] += weight;
+            }
+        }
+    }
+
+    // Counts a sample taken within the pixel at raster position (x, y) that is left out of the
+    // image, such as one whose radiance isn't finite.
+    pub fn discard_sample(&mut self, x: f64, y: f64) {
+        let (column, row) = (x.floor(), y.floor());
+        if column >= 0.0 && row >= 0.0 && column < self.width as f64 && row < self.height as f64 {
+            self.discarded[row as usize * self.width + column as usize] += 1;
+        }
+    }
+
+    // Whether samples were taken within a pixel and all of them discarded.
+    pub fn all_discarded(&self, x: usize, y: usize) -> bool {
+        let i = y * self.width + x;
+        self.statistics[i].count == 0 && self.discarded[i] > 0
+    }
+
+    // Filtered value of a pixel, or black if no sample has reached it.
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        let i = y * self.width + x;
+        if self.weights[i] == 0.0 {
+            return Color::zero();
+        }
+        self.sums[i] / self.weights[i]
+    }
+
+    // Filtered values of all pixels, row by row from the top.
+    pub fn pixels(&self) -> Vec<Color> {
+        (0..self.height)
+            .flat_map(|y| (0..self.width).map(move |x| self.pixel(x, y)))
+            .collect()
+    }
+
+    // Number of samples taken within a pixel, including those discarded.
+    pub fn sample_count(&self, x: usize, y: usize) -> u32 {
+        let i = y * self.width + x;
+        self.statistics[i].count + self.discarded[i]
+    }
+
+    // Standard error of the mean luminance of the samples taken within a pixel, relative to that
+    // mean. Dark pixels are measured against a floor, since their noise is hard to see. Infinite
+    // until there are at least two samples.
+    pub fn error(&self, x: usize, y: usize) -> f64 {
+        let statistics = &self.statistics[y * self.width + x];
+        let standard_error = (statistics.variance() / statistics.count as f64).sqrt();
+        standard_error / statistics.mean.max(0.01)
+    }
+
+    // Root mean square of the error of every pixel, as an estimate of the noise left in the
+    // image. Infinite until every pixel has at least two samples. Pixels whose every sample was
+    // discarded are left out, since no amount of sampling may bring their error down.
+    pub fn estimated_error(&self) -> f64 {
+        let (mut sum, mut pixels) = (0.0, 0);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if !self.all_discarded(x, y) {
+                    sum += self.error(x, y).powi(2);
+                    pixels += 1;
+                }
+            }
+        }
+        if pixels == 0 {
+            return 0.0;
+        }
+        (sum / pixels as f64).sqrt()
+    }
+
+    pub fn mean_sample_count(&self) -> f64 {
+        let total: u64 = (0..self.height)
+            .flat_map(|y| (0..self.width).map(move |x| self.sample_count(x, y) as u64))
+            .sum();
+        total as f64 / (self.width * self.height) as f64
+    }
+
+    // Writes the number of samples of every pixel as a plain PPM, from black for none through
+    // blue, green and red to white for `max_samples` or more.
+    pub fn write_sample_heatmap<W: Write>(&self, mut out: W, max_samples: u32) -> Result<()> {
+        write!(out, "P3\n{} {}\n255\n", self.width, self.height)?;
+        for (statistics, discarded) in self.statistics.iter().zip(&self.discarded) {
+            let count = statistics.count + discarded;
+            let t = (count as f64 / max_samples as f64).min(1.0);
+            let color = heat(t);
+            writeln!(
+                out,
+                "{} {} {}",
+                (255.0 * color.x) as u8,
+                (255.0 * color.y) as u8,
+                (255.0 * color.z) as u8
+            )?;
+        }
+        Ok(())
+    }
+
+    // Writes the image as a plain PPM, from the top row down.
+    pub fn write_ppm<W: Write>(&self, out: W) -> Result<()> {
+        write_ppm(out, self.width, self.height, &self.pixels())
+    }
+
+    // The image as R, G and B, followed by every channel.
+    pub fn exr_channels(&self) -> Vec<(String, Vec<f64>)> {
+        let pixels = self.pixels();
+        let mut channels = vec![
+            (String::from("R"), pixels.iter().map(|c| c.x).collect()),
+            (String::from("G"), pixels.iter().map(|c| c.y).collect()),
+            (String::from("B"), pixels.iter().map(|c| c.z).collect()),
+        ];
+        for name in &self.channel_names {
+            channels.push((name.clone(), self.channel(name).unwrap()));
+        }
+        channels
+    }
+}
+
+// The filter isn't saved, so a film must be restored with the one it was made with.
+impl Checkpoint for Film {
+    fn save(&self, out: &mut dyn Write) -> Result<()> {
+        write_u64(out, self.sums.len() as u64)?;
+        for (sum, weight) in self.sums.iter().zip(&self.weights) {
+            for value in [sum.x, sum.y, sum.z, *weight] {
+                write_f64(out, value)?;
+            }
+        }
+        for statistics in &self.statistics {
+            write_u64(out, statistics.count as u64)?;
+            write_f64(out, statistics.mean)?;
+            write_f64(out, statistics.m2)?;
+        }
+        for discarded in &self.discarded {
+            write_u64(out, *discarded as u64)?;
+        }
+        write_u64(out, self.channel_sums.len() as u64)?;
+        for sum in &self.channel_sums {
+            write_f64(out, *sum)?;
+        }
+        for count in &self.channel_counts {
+            write_u64(out, *count as u64)?;
+        }
+        write_u64(out, self.channel_tallies.len() as u64)?;
+        for tallies in &self.channel_tallies {
+            write_u64(out, tallies.len() as u64)?;
+            for (value, count) in tallies {
+                write_f64(out, *value)?;
+                write_u64(out, *count as u64)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, input: &mut dyn Read) -> Result<()> {
+        read_len(input, self.sums.len(), "pixels")?;
+        for (sum, weight) in self.sums.iter_mut().zip(self.weights.iter_mut()) {
+            *sum = Color::new(read_f64(input)?, read_f64(input)?, read_f64(input)?);
+            *weight = read_f64(input)?;
+        }
+        for statistics in self.statistics.iter_mut() {
+            statistics.count = read_u64(input)? as u32;
+            statistics.mean = read_f64(input)?;
+            statistics.m2 = read_f64(input)?;
+        }
+        for discarded in self.discarded.iter_mut() {
+            *discarded = read_u64(input)? as u32;
+        }
+        read_len(input, self.channel_sums.len(), "channel values")?;
+        for sum in self.channel_sums.iter_mut() {
+            *sum = read_f64(input)?;
+        }
+        for count in self.channel_counts.iter_mut() {
+            *count = read_u64(input)? as u32;
+        }
+        read_len(input, self.channel_tallies.len(), "channel tallies")?;
+        for tallies in self.channel_tallies.iter_mut() {
+            tallies.clear();
+            for _ in 0..read_u64(input)? {
+                tallies.push((read_f64(input)?, read_u64(input)? as u32));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Writes pixels stored row by row from the top as a plain PPM.
+pub fn write_ppm<W: Write>(
+    mut out: W,
+    width: usize,
+    height: usize,
+    pixels: &[Color],
+) -> Result<()> {
+    write!(out, "P3\n{} {}\n255\n", width, height)?;
+    for &pixel in pixels {
+        write_rgb(&mut out, pixel)?;
+    }
+    Ok(())
+}
+
+// Piecewise linear color ramp over [0, 1].
+fn heat(t: f64) -> Color {
+    let stops = [
+        Color::zero(),
+        Color::new(0.0, 0.0, 1.0),
+        Color::new(0.0, 1.0, 0.0),
+        Color::new(1.0, 0.0, 0.0),
+        Color::new(1.0, 1.0, 1.0),
+    ];
+    let position = t * (stops.len() - 1) as f64;
+    let i = (position as usize).min(stops.len() - 2);
+    let f = position - i as f64;
+    (1.0 - f) * stops[i] + f * stops[i + 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::film::{AdaptiveSampling, Budget, ChannelFilter, Film};
+    use crate::filter::{BoxFilter, MitchellFilter, TentFilter};
+    use crate::util::random_f64;
+    use crate::vector3::Vector3 as Color;
+
+    #[test]
+    fn box_average() {
+        let mut film = Film::new(2, 1, Box::new(BoxFilter { radius: 0.5 }));
+        film.add_sample(0.2, 0.5, Color::new(1.0, 1.0, 1.0));
+        film.add_sample(0.7, 0.3, Color::new(3.0, 3.0, 3.0));
+        film.add_sample(1.5, 0.5, Color::new(5.0, 5.0, 5.0));
+        assert_eq!(Color::new(2.0, 2.0, 2.0), film.pixel(0, 0));
+        assert_eq!(Color::new(5.0, 5.0, 5.0), film.pixel(1, 0));
+    }
+
+    #[test]
+    fn neighbors() {
+        let mut film = Film::new(3, 3, Box::new(TentFilter { radius: 1.0 }));
+        // Close to the corner shared by four pixels, so it counts for all of them.
+        film.add_sample(1.9, 1.9, Color::new(1.0, 1.0, 1.0));
+        for (x, y) in [(1, 1), (2, 1), (1, 2), (2, 2)] {
+            assert_eq!(Color::new(1.0, 1.0, 1.0), film.pixel(x, y));
+        }
+        assert_eq!(Color::zero(), film.pixel(0, 0));
+        assert_eq!(Color::zero(), film.pixel(0, 2));
+    }
+
+    #[test]
+    fn constant() {
+        // A constant image comes out unchanged, negative lobes and all.
+        let mut film = Film::new(
+            8,
+            8,
+            Box::new(MitchellFilter {
+                radius: 2.0,
+                b: 1.0 / 3.0,
+                c: 1.0 / 3.0,
+            }),
+        );
+        for _ in 0..10000 {
+            film.add_sample(
+                8.0 * random_f64(),
+                8.0 * random_f64(),
+                Color::new(0.5, 0.5, 0.5),
+            );
+        }
+        for y in 0..8 {
+            for x in 0..8 {
+                assert!((film.pixel(x, y).x - 0.5).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn statistics() {
+        let mut film = Film::new(2, 1, Box::new(BoxFilter { radius: 0.5 }));
+        for value in [1.0, 2.0, 3.0, 4.0] {
+            film.add_sample(0.5, 0.5, Color::new(value, value, value));
+        }
+        assert_eq!(4, film.sample_count(0, 0));
+        assert_eq!(0, film.sample_count(1, 0));
+        // Mean 2.5 and sample variance 5/3.
+        let expected = (5.0 / 3.0 / 4.0_f64).sqrt() / 2.5;
+        assert!((film.error(0, 0) - expected).abs() < 1e-9);
+        assert_eq!(f64::INFINITY, film.error(1, 0));
+    }
+
+    #[test]
+    fn adaptive() {
+        let adaptive = AdaptiveSampling {
+            min_samples: 4,
+            max_samples: 64,
+            threshold: 0.01,
+        };
+        let mut film = Film::new(2, 1, Box::new(BoxFilter { radius: 0.5 }));
+        // A flat pixel stops at the minimum, a noisy one runs to the maximum.
+        while !adaptive.done(&film, 0, 0) {
+            film.add_sample(0.5, 0.5, Color::new(0.5, 0.5, 0.5));
+        }
+        while !adaptive.done(&film, 1, 0) {
+            let value = random_f64();
+            film.add_sample(1.5, 0.5, Color::new(value, value, value));
+        }
+        assert_eq!(4, film.sample_count(0, 0));
+        assert_eq!(64, film.sample_count(1, 0));
+
+        let mut out = Vec::new();
+        film.write_sample_heatmap(&mut out, 64).unwrap();
+        assert_eq!(
+            "P3\n2 1\n255\n0 0 63\n255 255 255\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn discarded() {
+        let adaptive = AdaptiveSampling {
+            min_samples: 4,
+            max_samples: u32::MAX,
+            threshold: 0.01,
+        };
+        let mut film = Film::new(2, 1, Box::new(BoxFilter { radius: 0.5 }));
+        // A pixel whose samples are all discarded stops at the minimum, and is left out of the
+        // error of the image.
+        while !adaptive.done(&film, 0, 0) {
+            film.discard_sample(0.5, 0.5);
+        }
+        for _ in 0..4 {
+            film.add_sample(1.5, 0.5, Color::new(0.5, 0.5, 0.5));
+        }
+        assert_eq!(4, film.sample_count(0, 0));
+        assert!(film.all_discarded(0, 0));
+        assert!(!film.all_discarded(1, 0));
+        assert_eq!(Color::zero(), film.pixel(0, 0));
+        assert_eq!(0.0, film.estimated_error());
+        assert_eq!(4.0, film.mean_sample_count());
+
+        // Once one sample is kept, the pixel is sampled until it converges like any other.
+        film.add_sample(0.5, 0.5, Color::new(0.5, 0.5, 0.5));
+        assert!(!adaptive.done(&film, 0, 0));
+        assert_eq!(f64::INFINITY, film.estimated_error());
+    }
+
+    #[test]
+    fn budget() {
+        let mut film = Film::new(2, 1, Box::new(BoxFilter { radius: 0.5 }));
+        assert_eq!(f64::INFINITY, film.estimated_error());
+        for value in [1.0, 3.0, 1.0, 3.0] {
+            film.add_sample(0.5, 0.5, Color::new(value, value, value));
+            film.add_sample(1.5, 0.5, Color::new(2.0, 2.0, 2.0));
+        }
+        // Only the first pixel is noisy, with a relative standard error of 1/sqrt(12).
+        let expected = (1.0 / 24.0_f64).sqrt();
+        assert!((film.estimated_error() - expected).abs() < 1e-9);
+        assert_eq!(4.0, film.mean_sample_count());
+
+        let second = Duration::from_secs(1);
+        let budget = Budget {
+            time: Some(10 * second),
+            error: None,
+        };
+        assert!(!budget.exhausted(5 * second, 2 * second, &film));
+        assert!(budget.exhausted(9 * second, 2 * second, &film));
+        assert!(!budget.out_of_time(9 * second));
+        assert!(budget.out_of_time(10 * second));
+        let budget = Budget {
+            time: None,
+            error: Some(0.25),
+        };
+        assert!(budget.exhausted(5 * second, second, &film));
+        assert!(!Budget::default().exhausted(5 * second, second, &film));
+        assert!(!budget.out_of_time(100 * second));
+    }
+
+    #[test]
+    fn channels() {
+        let mut film = Film::new(2, 1, Box::new(BoxFilter { radius: 0.5 }));
+        film.add_channels(&[
+            ("u.U", ChannelFilter::Mean),
+            ("depth.Z", ChannelFilter::Nearest),
+            ("object.id", ChannelFilter::Majority),
+        ]);
+        film.add_channel_sample(0.2, 0.5, &[2.0, 4.0, 7.0]);
+        film.add_channel_sample(0.8, 0.5, &[4.0, 2.0, 1.0]);
+        film.add_channel_sample(0.5, 0.5, &[3.0, f64::INFINITY, 1.0]);
+        film.add_channel_sample(1.5, 0.5, &[1.0, f64::INFINITY, 0.0]);
+        // Samples don't spill into the neighboring pixel, the nearest depth is kept and escaped
+        // rays count only where nothing was hit, and the ID is that of most samples rather than
+        // a blend of them.
+        assert_eq!(Some(vec![3.0, 1.0]), film.channel("u.U"));
+        assert_eq!(Some(vec![2.0, 0.0]), film.channel("depth.Z"));
+        assert_eq!(Some(vec![1.0, 0.0]), film.channel("object.id"));
+        assert_eq!(None, film.channel("normal.X"));
+    }
+
+    #[test]
+    fn ppm() {
+        let mut film = Film::new(2, 1, Box::new(BoxFilter { radius: 0.5 }));
+        film.add_sample(0.5, 0.5, Color::new(0.25, 1.0, 0.0));
+        let mut out = Vec::new();
+        film.write_ppm(&mut out).unwrap();
+        assert_eq!(
+            "P3\n2 1\n255\n128 255 0\n0 0 0\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+// Pixel reconstruction filter. Samples are weighted by the filter centered on each pixel they
+// fall near, with offsets measured in pixels.
+pub trait Filter {
+    // Distance from the center beyond which the filter is zero, along either axis.
+    fn radius(&self) -> f64;
+
+    fn evaluate(&self, x: f64, y: f64) -> f64;
+}
+
+// Lets one filter be shared by several films.
+impl<F: Filter + ?Sized> Filter for Arc<F> {
+    fn radius(&self) -> f64 {
+        (**self).radius()
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        (**self).evaluate(x, y)
+    }
+}
+
+// Equal weight for every sample within the radius. A radius of half a pixel gives the plain
+// per-pixel average.
+pub struct BoxFilter {
+    pub radius: f64,
+}
+
+impl Filter for BoxFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        if x.abs() <= self.radius && y.abs() <= self.radius {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+// Weight falling off linearly to zero at the radius.
+pub struct TentFilter {
+    pub radius: f64,
+}
+
+impl Filter for TentFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        (self.radius - x.abs()).max(0.0) * (self.radius - y.abs()).max(0.0)
+    }
+}
+
+// Gaussian shifted down to reach zero at the radius.
+pub struct GaussianFilter {
+    pub radius: f64,
+    pub sigma: f64,
+}
+
+impl GaussianFilter {
+    fn gaussian(&self, x: f64) -> f64 {
+        let g = |x: f64| (-x * x / (2.0 * self.sigma * self.sigma)).exp();
+        (g(x) - g(self.radius)).max(0.0)
+    }
+}
+
+impl Filter for GaussianFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.gaussian(x) * self.gaussian(y)
+    }
+}
+
+// Mitchell and Netravali's cubic family. B = C = 1/3 is their recommended balance between
+// ringing and blurring.
+pub struct MitchellFilter {
+    pub radius: f64,
+    pub b: f64,
+    pub c: f64,
+}
+
+impl MitchellFilter {
+    // The cubic is defined over [-2, 2], which is stretched to the radius.
+    fn mitchell(&self, x: f64) -> f64 {
+        let x = (2.0 * x / self.radius).abs();
+        let (b, c) = (self.b, self.c);
+        let value = if x > 2.0 {
+            0.0
+        } else if x > 1.0 {
+            (-b - 6.0 * c) * x.powi(3)
+                + (6.0 * b + 30.0 * c) * x.powi(2)
+                + (-12.0 * b - 48.0 * c) * x
+                + (8.0 * b + 24.0 * c)
+        } else {
+            (12.0 - 9.0 * b - 6.0 * c) * x.powi(3)
+                + (-18.0 + 12.0 * b + 6.0 * c) * x.powi(2)
+                + (6.0 - 2.0 * b)
+        };
+        value / 6.0
+    }
+}
+
+impl Filter for MitchellFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.mitchell(x) * self.mitchell(y)
+    }
+}
+
+// Sinc windowed by a wider sinc, `tau` being the number of lobes of the sinc within the window.
+pub struct LanczosFilter {
+    pub radius: f64,
+    pub tau: f64,
+}
+
+impl LanczosFilter {
+    fn windowed_sinc(&self, x: f64) -> f64 {
+        if x.abs() > self.radius {
+            return 0.0;
+        }
+        sinc(x) * sinc(x / self.tau)
+    }
+}
+
+impl Filter for LanczosFilter {
+    fn radius(&self) -> f64 {
+        self.radius
+    }
+
+    fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.windowed_sinc(x) * self.windowed_sinc(y)
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-5 {
+        return 1.0;
+    }
+    (PI * x).sin() / (PI * x)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::{
+        BoxFilter, Filter, GaussianFilter, LanczosFilter, MitchellFilter, TentFilter,
+    };
+
+    fn filters() -> Vec<Box<dyn Filter>> {
+        vec![
+            Box::new(BoxFilter { radius: 0.5 }),
+            Box::new(TentFilter { radius: 1.0 }),
+            Box::new(GaussianFilter {
+                radius: 1.5,
+                sigma: 0.5,
+            }),
+            Box::new(MitchellFilter {
+                radius: 2.0,
+                b: 1.0 / 3.0,
+                c: 1.0 / 3.0,
+            }),
+            Box::new(LanczosFilter {
+                radius: 3.0,
+                tau: 3.0,
+            }),
+        ]
+    }
+
+    #[test]
+    fn support() {
+        for filter in filters() {
+            let r = filter.radius();
+            assert!(filter.evaluate(0.0, 0.0) > 0.0);
+            assert_eq!(filter.evaluate(0.3, -0.2), filter.evaluate(-0.3, 0.2));
+            assert!(filter.evaluate(r + 0.01, 0.0).abs() < 1e-12);
+            assert!(filter.evaluate(0.0, -r - 0.01).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn negative_lobes() {
+        let mitchell = MitchellFilter {
+            radius: 2.0,
+            b: 1.0 / 3.0,
+            c: 1.0 / 3.0,
+        };
+        assert!(mitchell.evaluate(1.5, 0.0) < 0.0);
+        let lanczos = LanczosFilter {
+            radius: 3.0,
+            tau: 3.0,
+        };
+        assert!(lanczos.evaluate(1.5, 0.0) < 0.0);
+        // Lanczos is interpolating: zero at every other pixel center.
+        assert!(lanczos.evaluate(1.0, 0.0).abs() < 1e-12);
+        assert!(lanczos.evaluate(2.0, 0.0).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,418 @@
+use crate::error::{check, Result};
+use crate::hittable::{HitRecord, Hittable};
+use crate::image::GrayImage;
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::stats::{count, count_test, Primitive};
+use crate::vector3::{Vector3 as Point3, Vector3};
+
+// Grid indices of the corners of a triangle.
+type Triangle = [(usize, usize); 3];
+
+// Terrain built from a regular grid of elevations. Every grid cell is split into two triangles,
+// and cells are visited in ray order with a 2D DDA so that only the cells under the ray are
+// tested.
+pub struct Heightfield<M: Material> {
+    // Number of samples along x and z.
+    nx: usize,
+    nz: usize,
+    // World space elevation of every sample, row by row along x.
+    heights: Vec<f64>,
+    normals: Vec<Vector3>,
+    // Elevation range of every cell, used to skip cells the ray passes above or below.
+    cell_bounds: Vec<(f64, f64)>,
+    min_height: f64,
+    max_height: f64,
+    origin: Point3,
+    cell_size_x: f64,
+    cell_size_z: f64,
+    pub material: M,
+}
+
+impl<M: Material> Heightfield<M> {
+    // Spans the grid from `origin` (the corner of the first sample) over `size.x` by `size.z`,
+    // with an elevation of `size.y` at the largest sample value.
+    pub fn new(
+        nx: usize,
+        nz: usize,
+        samples: &[f64],
+        origin: Point3,
+        size: Vector3,
+        material: M,
+    ) -> Result<Heightfield<M>> {
+        check(
+            nx >= 2 && nz >= 2,
+            "a heightfield needs at least 2x2 samples",
+        )?;
+        check(
+            nx.checked_mul(nz) == Option::Some(samples.len()),
+            "a heightfield needs a sample for every grid point",
+        )?;
+        check(
+            size.x > 0.0 && size.z > 0.0 && size.x.is_finite() && size.z.is_finite(),
+            "a heightfield must span a positive, finite size along x and z",
+        )?;
+        check(
+            size.y.is_finite(),
+            "a heightfield must have a finite elevation",
+        )?;
+
+        let heights: Vec<f64> = samples.iter().map(|h| origin.y + h * size.y).collect();
+        let min_height = heights.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max_height = heights.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+
+        let mut heightfield = Heightfield {
+            nx,
+            nz,
+            heights,
+            normals: Vec::new(),
+            cell_bounds: Vec::new(),
+            min_height,
+            max_height,
+            origin,
+            cell_size_x: size.x / (nx - 1) as f64,
+            cell_size_z: size.z / (nz - 1) as f64,
+            material,
+        };
+        heightfield.normals = (0..nx * nz)
+            .map(|k| heightfield.vertex_normal(k % nx, k / nx))
+            .collect();
+        heightfield.cell_bounds = (0..(nx - 1) * (nz - 1))
+            .map(|k| {
+                let (i, j) = (k % (nx - 1), k / (nx - 1));
+                let corners = [
+                    heightfield.height(i, j),
+                    heightfield.height(i + 1, j),
+                    heightfield.height(i, j + 1),
+                    heightfield.height(i + 1, j + 1),
+                ];
+                (
+                    corners.iter().cloned().fold(f64::INFINITY, f64::min),
+                    corners.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+                )
+            })
+            .collect();
+        Ok(heightfield)
+    }
+
+    // Image rows run along z and columns along x.
+    pub fn from_image(
+        image: &GrayImage,
+        origin: Point3,
+        size: Vector3,
+        material: M,
+    ) -> Result<Heightfield<M>> {
+        Heightfield::new(
+            image.width,
+            image.height,
+            &image.pixels,
+            origin,
+            size,
+            material,
+        )
+    }
+
+    fn height(&self, i: usize, j: usize) -> f64 {
+        self.heights[j * self.nx + i]
+    }
+
+    fn vertex(&self, i: usize, j: usize) -> Point3 {
+        Point3::new(
+            self.origin.x + i as f64 * self.cell_size_x,
+            self.height(i, j),
+            self.origin.z + j as f64 * self.cell_size_z,
+        )
+    }
+
+    // Central differences of the elevation, one-sided at the border.
+    fn vertex_normal(&self, i: usize, j: usize) -> Vector3 {
+        let (i0, i1) = (i.saturating_sub(1), (i + 1).min(self.nx - 1));
+        let (j0, j1) = (j.saturating_sub(1), (j + 1).min(self.nz - 1));
+        let dx = (self.height(i1, j) - self.height(i0, j)) / ((i1 - i0) as f64 * self.cell_size_x);
+        let dz = (self.height(i, j1) - self.height(i, j0)) / ((j1 - j0) as f64 * self.cell_size_z);
+        Vector3::new(-dx, 1.0, -dz).unit()
+    }
+
+    // Returns the first intersection with the two triangles of cell (i, j) within [t_min, t_max].
+    fn hit_cell(
+        &self,
+        r: &Ray,
+        i: usize,
+        j: usize,
+        t_min: f64,
+        t_max: f64,
+    ) -> Option<HitRecord<'_>> {
+        let corners = [(i, j), (i + 1, j), (i + 1, j + 1), (i, j + 1)];
+        let mut closest: Option<(f64, Triangle, f64, f64)> = Option::None;
+        for triangle in [
+            [corners[0], corners[1], corners[2]],
+            [corners[0], corners[2], corners[3]],
+        ] {
+            let t_limit = closest.map_or(t_max, |c| c.0);
+            let [a, b, c] = triangle.map(|(i, j)| self.vertex(i, j));
+            if let Some((t, beta, gamma)) = hit_triangle(r, a, b, c, t_min, t_limit) {
+                closest = Option::Some((t, triangle, beta, gamma));
+            }
+        }
+
+        closest.map(|(t, [a, b, c], beta, gamma)| {
+            let alpha = 1.0 - beta - gamma;
+            let p = r.at(t);
+            let (va, vb, vc) = (
+                self.vertex(a.0, a.1),
+                self.vertex(b.0, b.1),
+                self.vertex(c.0, c.1),
+            );
+            let geometric_normal = (vc - va).cross(&(vb - va)).unit();
+            let mut rec = HitRecord::from_normal(p, t, r, geometric_normal, &self.material);
+
+            // Shade with the interpolated vertex normal, on the same side as the geometric one.
+            let normal = |(i, j): (usize, usize)| self.normals[j * self.nx + i];
+            let shading_normal = (alpha * normal(a) + beta * normal(b) + gamma * normal(c)).unit();
+            rec.normal = if rec.front_face {
+                shading_normal
+            } else {
+                -shading_normal
+            };
+            rec.u = (p.x - self.origin.x) / (self.cell_size_x * (self.nx - 1) as f64);
+            rec.v = (p.z - self.origin.z) / (self.cell_size_z * (self.nz - 1) as f64);
+            rec
+        })
+    }
+}
+
+// Möller–Trumbore intersection, returning the distance and the barycentric weights of b and c.
+fn hit_triangle(
+    r: &Ray,
+    a: Point3,
+    b: Point3,
+    c: Point3,
+    t_min: f64,
+    t_max: f64,
+) -> Option<(f64, f64, f64)> {
+    let edge1 = b - a;
+    let edge2 = c - a;
+    let p = r.direction.cross(&edge2);
+    let det = edge1.dot(&p);
+    if det.abs() < 1e-12 {
+        return Option::None;
+    }
+    let inv_det = 1.0 / det;
+    let s = r.origin - a;
+    let beta = s.dot(&p) * inv_det;
+    if !(0.0..=1.0).contains(&beta) {
+        return Option::None;
+    }
+    let q = s.cross(&edge1);
+    let gamma = r.direction.dot(&q) * inv_det;
+    if gamma < 0.0 || beta + gamma > 1.0 {
+        return Option::None;
+    }
+    let t = edge2.dot(&q) * inv_det;
+    if t < t_min || t_max < t {
+        return Option::None;
+    }
+    Option::Some((t, beta, gamma))
+}
+
+// Clips the ray parameter range against the slab [min, max] along one axis.
+fn clip_slab(origin: f64, direction: f64, min: f64, max: f64, t0: &mut f64, t1: &mut f64) -> bool {
+    if direction == 0.0 {
+        return min <= origin && origin <= max;
+    }
+    let mut near = (min - origin) / direction;
+    let mut far = (max - origin) / direction;
+    if near > far {
+        std::mem::swap(&mut near, &mut far);
+    }
+    *t0 = t0.max(near);
+    *t1 = t1.min(far);
+    *t0 <= *t1
+}
+
+impl<M: Material> Hittable for Heightfield<M> {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let hit = self.traverse(r, t_min, t_max);
+        count_test(Primitive::Heightfield, hit.is_some());
+        hit
+    }
+}
+
+impl<M: Material> Heightfield<M> {
+    fn traverse(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        // Work in grid coordinates along x and z, where cells are unit squares.
+        let gx = (r.origin.x - self.origin.x) / self.cell_size_x;
+        let gz = (r.origin.z - self.origin.z) / self.cell_size_z;
+        let dx = r.direction.x / self.cell_size_x;
+        let dz = r.direction.z / self.cell_size_z;
+        let (cells_x, cells_z) = (self.nx - 1, self.nz - 1);
+
+        let mut t0 = t_min;
+        let mut t1 = t_max;
+        if !clip_slab(gx, dx, 0.0, cells_x as f64, &mut t0, &mut t1)
+            || !clip_slab(gz, dz, 0.0, cells_z as f64, &mut t0, &mut t1)
+            || !clip_slab(
+                r.origin.y,
+                r.direction.y,
+                self.min_height,
+                self.max_height,
+                &mut t0,
+                &mut t1,
+            )
+        {
+            return Option::None;
+        }
+
+        let cell = |g: f64, cells: usize| (g.floor().max(0.0) as usize).min(cells - 1);
+        let mut i = cell(gx + t0 * dx, cells_x);
+        let mut j = cell(gz + t0 * dz, cells_z);
+
+        // Ray distance to the next cell boundary along each axis, and between boundaries.
+        let axis = |g: f64, d: f64, index: usize| -> (f64, f64) {
+            if d > 0.0 {
+                (((index + 1) as f64 - g) / d, 1.0 / d)
+            } else if d < 0.0 {
+                ((index as f64 - g) / d, -1.0 / d)
+            } else {
+                (f64::INFINITY, f64::INFINITY)
+            }
+        };
+        let (mut next_x, delta_x) = axis(gx, dx, i);
+        let (mut next_z, delta_z) = axis(gz, dz, j);
+
+        let mut t_enter = t0;
+        loop {
+            count(|stats| stats.cells_visited += 1);
+            let t_exit = next_x.min(next_z).min(t1);
+            let (low, high) = self.cell_bounds[j * cells_x + i];
+            let y_enter = r.at(t_enter).y;
+            let y_exit = r.at(t_exit).y;
+            if y_enter.min(y_exit) <= high && y_enter.max(y_exit) >= low {
+                if let Some(rec) = self.hit_cell(r, i, j, t_min, t_max) {
+                    return Option::Some(rec);
+                }
+            }
+            if t_exit >= t1 {
+                return Option::None;
+            }
+
+            t_enter = t_exit;
+            if next_x < next_z {
+                if dx > 0.0 && i + 1 < cells_x {
+                    i += 1;
+                } else if dx < 0.0 && i > 0 {
+                    i -= 1;
+                } else {
+                    return Option::None;
+                }
+                next_x += delta_x;
+            } else {
+                if dz > 0.0 && j + 1 < cells_z {
+                    j += 1;
+                } else if dz < 0.0 && j > 0 {
+                    j -= 1;
+                } else {
+                    return Option::None;
+                }
+                next_z += delta_z;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::heightfield::Heightfield;
+    use crate::hittable::Hittable;
+    use crate::image::GrayImage;
+    use crate::material::Lambertian;
+    use crate::ray::Ray;
+    use crate::vector3::{Vector3 as Point3, Vector3};
+
+    fn assert_near(expected: f64, actual: f64) {
+        assert!(
+            (expected - actual).abs() < 1e-9,
+            "{} != {}",
+            expected,
+            actual
+        );
+    }
+
+    // A ramp rising along x from 0 to 1 over 4 units.
+    fn ramp() -> Heightfield<Lambertian> {
+        let samples: Vec<f64> = (0..25).map(|k| (k % 5) as f64 / 4.0).collect();
+        Heightfield::new(
+            5,
+            5,
+            &samples,
+            Point3::zero(),
+            Vector3::new(4.0, 1.0, 4.0),
+            Lambertian::new(Vector3::zero()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn vertical_ray() {
+        let heightfield = ramp();
+        let r = Ray::new(Point3::new(2.5, 5.0, 1.5), Vector3::new(0.0, -1.0, 0.0));
+        let hit = heightfield.hit(&r, 0.001, f64::INFINITY).unwrap();
+        assert_near(5.0 - 2.5 / 4.0, hit.t);
+        assert!(hit.front_face);
+        assert_near(0.625, hit.u);
+        assert_near(0.375, hit.v);
+
+        let slope = Vector3::new(-0.25, 1.0, 0.0).unit();
+        assert_near(1.0, hit.normal.dot(&slope));
+    }
+
+    #[test]
+    fn grazing_ray() {
+        // Travels along z at a height that the ramp only reaches at x = 2, so it must cross
+        // every cell along the way without hitting anything.
+        let heightfield = ramp();
+        let r = Ray::new(Point3::new(1.0, 0.6, -1.0), Vector3::new(0.0, 0.0, 1.0));
+        assert!(heightfield.hit(&r, 0.001, f64::INFINITY).is_none());
+
+        // Diagonal ray descending across several cells.
+        let r = Ray::new(Point3::new(-1.0, 2.0, -1.0), Vector3::new(1.0, -0.5, 1.0));
+        let hit = heightfield.hit(&r, 0.001, f64::INFINITY).unwrap();
+        assert_near(hit.p.x / 4.0, hit.p.y);
+        assert!(heightfield.hit(&r, 0.001, hit.t - 1e-6).is_none());
+    }
+
+    #[test]
+    fn outside() {
+        let heightfield = ramp();
+        let r = Ray::new(Point3::new(5.0, 5.0, 1.0), Vector3::new(0.0, -1.0, 0.0));
+        assert!(heightfield.hit(&r, 0.001, f64::INFINITY).is_none());
+    }
+
+    #[test]
+    fn invalid() {
+        let material = Lambertian::new(Vector3::zero());
+        let new = |nx, nz, samples: &[f64], size| {
+            Heightfield::new(nx, nz, samples, Point3::zero(), size, material.clone())
+        };
+        let unit = Vector3::new(1.0, 1.0, 1.0);
+        assert!(new(1, 2, &[0.0, 0.0], unit).is_err());
+        assert!(new(2, 2, &[0.0, 0.0, 0.0], unit).is_err());
+        let flat = [0.0; 4];
+        assert!(new(2, 2, &flat, unit).is_ok());
+        assert!(new(2, 2, &flat, Vector3::new(0.0, 1.0, 1.0)).is_err());
+        assert!(new(2, 2, &flat, Vector3::new(1.0, 1.0, -1.0)).is_err());
+        assert!(new(2, 2, &flat, Vector3::new(f64::INFINITY, 1.0, 1.0)).is_err());
+        assert!(new(2, 2, &flat, Vector3::new(1.0, 1.0, f64::NAN)).is_err());
+        assert!(new(2, 2, &flat, Vector3::new(1.0, f64::NAN, 1.0)).is_err());
+
+        // An image one pixel wide.
+        let image = GrayImage::from_pgm(&b"P2 1 3 255 0 1 2"[..]).unwrap();
+        assert!(Heightfield::from_image(
+            &image,
+            Point3::zero(),
+            Vector3::new(1.0, 1.0, 1.0),
+            material
+        )
+        .is_err());
+    }
+}
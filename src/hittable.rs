@@ -2,6 +2,11 @@ use crate::material::Material;
 use crate::ray::Ray;
 use crate::vector3::{Vector3 as Point3, Vector3};
 
+// Relative step taken past a surface before looking for the next one when marching a ray through
+// a solid.
+const MARCH_EPSILON: f64 = 1e-9;
+const MAX_MARCH_STEPS: usize = 1024;
+
 #[derive(Copy, Clone)]
 pub struct HitRecord<'a> {
     pub p: Point3,
@@ -9,6 +14,9 @@ pub struct HitRecord<'a> {
     pub material: &'a dyn Material,
     pub t: f64,
     pub front_face: bool,
+    // Surface parameterization for texturing. Zero for objects that don't define one.
+    pub u: f64,
+    pub v: f64,
 }
 
 impl HitRecord<'_> {
@@ -30,27 +38,186 @@ impl HitRecord<'_> {
             front_face,
             normal,
             material,
+            u: 0.0,
+            v: 0.0,
+        }
+    }
+}
+
+// A span of the ray along which it is inside a solid. Bounds that were clipped by the queried
+// range rather than produced by a surface carry no record.
+#[derive(Copy, Clone)]
+pub struct Interval<'a> {
+    pub t_enter: f64,
+    pub enter: Option<HitRecord<'a>>,
+    pub t_exit: f64,
+    pub exit: Option<HitRecord<'a>>,
+}
+
+// How `combine` merges the solids of two interval lists.
+pub enum Operation {
+    Union,
+    Intersection,
+    Difference,
+}
+
+impl Operation {
+    fn contains(&self, inside_left: bool, inside_right: bool) -> bool {
+        match self {
+            Operation::Union => inside_left || inside_right,
+            Operation::Intersection => inside_left && inside_right,
+            Operation::Difference => inside_left && !inside_right,
         }
     }
 }
 
 pub trait Hittable {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord>;
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>>;
+
+    // Returns the sorted, disjoint spans of [t_min, t_max] that lie inside the object. The
+    // default implementation marches the ray from surface to surface with `hit`, which is valid
+    // for any closed object.
+    fn intervals(&self, r: &Ray, t_min: f64, t_max: f64) -> Vec<Interval<'_>> {
+        let mut intervals = Vec::new();
+        let mut open: Option<(f64, Option<HitRecord>)> = Option::None;
+        let mut t = t_min;
+
+        for _ in 0..MAX_MARCH_STEPS {
+            let hit = match self.hit(r, t, t_max) {
+                Some(hit) => hit,
+                None => break,
+            };
+            if hit.front_face {
+                if open.is_none() {
+                    open = Option::Some((hit.t, Option::Some(hit)));
+                }
+            } else if open.is_some() || intervals.is_empty() {
+                // An exit with nothing open means the ray started inside the object.
+                let (t_enter, enter) = open.take().unwrap_or((t_min, Option::None));
+                intervals.push(Interval {
+                    t_enter,
+                    enter,
+                    t_exit: hit.t,
+                    exit: Option::Some(hit),
+                });
+            }
+            t = hit.t + MARCH_EPSILON * hit.t.abs().max(1.0);
+        }
+
+        if let Some((t_enter, enter)) = open {
+            intervals.push(Interval {
+                t_enter,
+                enter,
+                t_exit: t_max,
+                exit: Option::None,
+            });
+        }
+        intervals
+    }
 }
 
 impl Hittable for Vec<Box<dyn Hittable>> {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
         let mut output: Option<HitRecord> = Option::None;
         let mut closest_so_far = t_max;
 
         for object in self {
-            let maybe_hit = object.hit(r, t_min, closest_so_far);
-            if maybe_hit.is_some() {
-                closest_so_far = maybe_hit.unwrap().t;
-                output = maybe_hit;
+            if let Some(hit) = object.hit(r, t_min, closest_so_far) {
+                closest_so_far = hit.t;
+                output = Option::Some(hit);
             }
         }
 
         output
     }
+
+    fn intervals(&self, r: &Ray, t_min: f64, t_max: f64) -> Vec<Interval<'_>> {
+        // Objects in a list may overlap, so the list is the union of its members.
+        self.iter().fold(Vec::new(), |acc, object| {
+            combine(acc, object.intervals(r, t_min, t_max), &Operation::Union)
+        })
+    }
+}
+
+struct Event<'a> {
+    t: f64,
+    record: Option<HitRecord<'a>>,
+    right: bool,
+    entering: bool,
+}
+
+fn events<'a>(intervals: Vec<Interval<'a>>, right: bool) -> impl Iterator<Item = Event<'a>> {
+    intervals.into_iter().flat_map(move |interval| {
+        vec![
+            Event {
+                t: interval.t_enter,
+                record: interval.enter,
+                right,
+                entering: true,
+            },
+            Event {
+                t: interval.t_exit,
+                record: interval.exit,
+                right,
+                entering: false,
+            },
+        ]
+    })
+}
+
+// A surface of the right operand bounds the difference from the other side: the solid lies
+// behind it rather than in front of it. The normal already faces against the ray, so only the
+// side the ray is on changes.
+fn flip(record: HitRecord) -> HitRecord {
+    HitRecord {
+        front_face: !record.front_face,
+        ..record
+    }
+}
+
+// Sweeps the bounds of both interval lists in ray order and keeps the spans where the operation
+// holds.
+pub fn combine<'a>(
+    left: Vec<Interval<'a>>,
+    right: Vec<Interval<'a>>,
+    operation: &Operation,
+) -> Vec<Interval<'a>> {
+    let mut events: Vec<Event> = events(left, false).chain(events(right, true)).collect();
+    events.sort_by(|a, b| a.t.total_cmp(&b.t));
+
+    let mut result = Vec::new();
+    let mut inside_left = false;
+    let mut inside_right = false;
+    let mut open: Option<(f64, Option<HitRecord>)> = Option::None;
+
+    for event in events {
+        let was_inside = operation.contains(inside_left, inside_right);
+        if event.right {
+            inside_right = event.entering;
+        } else {
+            inside_left = event.entering;
+        }
+        let is_inside = operation.contains(inside_left, inside_right);
+        if was_inside == is_inside {
+            continue;
+        }
+
+        let mut record = event.record;
+        if let Operation::Difference = operation {
+            if event.right {
+                record = record.map(flip);
+            }
+        }
+        if is_inside {
+            open = Option::Some((event.t, record));
+        } else if let Some((t_enter, enter)) = open.take() {
+            result.push(Interval {
+                t_enter,
+                enter,
+                t_exit: event.t,
+                exit: record,
+            });
+        }
+    }
+    result
 }
@@ -0,0 +1,533 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::error::{Error, Result};
+use crate::vector3::Vector3;
+
+// Parser for IES LM-63 photometric data files (the 1986, 1991, 1995 and 2002 revisions), which
+// describe how the luminous intensity of a real fixture varies with direction.
+
+// Error for malformed data at a line of the file, counting from 1.
+fn parse_error(line: usize, message: String) -> Error {
+    Error::Parse(format!("IES line {}: {}", line, message))
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum PhotometricType {
+    // Polar axis along the luminaire's vertical axis (architectural fixtures).
+    C,
+    // Polar axis along the luminaire's lateral axis (floodlights).
+    B,
+    // Polar axis along the luminaire's longitudinal axis (automotive lamps).
+    A,
+}
+
+// Multipliers for the lamp output depending on how it is tilted in the luminaire.
+pub struct Tilt {
+    pub lamp_to_luminaire_geometry: u32,
+    pub angles: Vec<f64>,
+    pub multipliers: Vec<f64>,
+}
+
+pub struct IesProfile {
+    pub keywords: Vec<(String, String)>,
+    pub tilt: Option<Tilt>,
+    pub photometric_type: PhotometricType,
+    pub lumens_per_lamp: f64,
+    pub input_watts: f64,
+    pub vertical_angles: Vec<f64>,
+    pub horizontal_angles: Vec<f64>,
+    // Candela for every horizontal angle, each listing a value per vertical angle. Multipliers
+    // and ballast factors are already applied.
+    pub candela: Vec<Vec<f64>>,
+}
+
+// Numbers after the TILT line, which may be split across lines arbitrarily.
+struct Tokens<'a> {
+    tokens: Vec<(usize, &'a str)>,
+    position: usize,
+}
+
+impl Tokens<'_> {
+    fn last_line(&self) -> usize {
+        self.tokens.last().map_or(0, |t| t.0)
+    }
+
+    fn number(&mut self, what: &str) -> Result<f64> {
+        let (line, token) = *self
+            .tokens
+            .get(self.position)
+            .ok_or_else(|| parse_error(self.last_line(), format!("missing {}", what)))?;
+        self.position += 1;
+        token
+            .parse()
+            .map_err(|_| parse_error(line, format!("invalid {} '{}'", what, token)))
+    }
+
+    fn count(&mut self, what: &str) -> Result<usize> {
+        let line = self.tokens.get(self.position).map_or(0, |t| t.0);
+        let value = self.number(what)?;
+        if value < 0.0 || value.fract() != 0.0 {
+            return Err(parse_error(line, format!("invalid {} {}", what, value)));
+        }
+        Ok(value as usize)
+    }
+
+    fn numbers(&mut self, n: usize, what: &str) -> Result<Vec<f64>> {
+        (0..n).map(|_| self.number(what)).collect()
+    }
+}
+
+impl IesProfile {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<IesProfile> {
+        // Older files are often Latin-1 encoded, so don't insist on UTF-8.
+        let bytes = fs::read(path)?;
+        IesProfile::parse(&String::from_utf8_lossy(&bytes))
+    }
+
+    pub fn parse(text: &str) -> Result<IesProfile> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, line.trim()));
+        let mut keywords: Vec<(String, String)> = Vec::new();
+
+        // Header: optional format identifier and keywords, up to the TILT line.
+        let (tilt_line, tilt) = loop {
+            let (number, line) = lines
+                .next()
+                .ok_or_else(|| parse_error(0, "missing TILT line".to_string()))?;
+            if let Some(tilt) = line.strip_prefix("TILT=") {
+                break (number, tilt.trim().to_string());
+            }
+            if let Some(keyword) = line.strip_prefix('[') {
+                if let Some((key, value)) = keyword.split_once(']') {
+                    let value = value.trim().to_string();
+                    match (key, keywords.last_mut()) {
+                        ("MORE", Some((_, previous))) => {
+                            previous.push('\n');
+                            previous.push_str(&value);
+                        }
+                        _ => keywords.push((key.to_string(), value)),
+                    }
+                }
+            }
+        };
+
+        let mut tokens = Tokens {
+            tokens: lines
+                .flat_map(|(number, line)| {
+                    line.split(|c: char| c.is_whitespace() || c == ',')
+                        .filter(|t| !t.is_empty())
+                        .map(move |t| (number, t))
+                })
+                .collect(),
+            position: 0,
+        };
+
+        let tilt = match tilt.as_str() {
+            "NONE" => Option::None,
+            "INCLUDE" => {
+                let geometry = tokens.count("lamp-to-luminaire geometry")? as u32;
+                let pairs_line = tokens.tokens.get(tokens.position).map_or(0, |t| t.0);
+                let pairs = tokens.count("number of tilt angles")?;
+                if pairs == 0 {
+                    return Err(parse_error(
+                        pairs_line,
+                        "a tilt table needs at least one angle".to_string(),
+                    ));
+                }
+                let start = tokens.position;
+                let angles = tokens.numbers(pairs, "tilt angle")?;
+                if let Some(i) = angles.windows(2).position(|w| w[0] >= w[1]) {
+                    return Err(parse_error(
+                        tokens.tokens[start + i + 1].0,
+                        "tilt angles must be strictly increasing".to_string(),
+                    ));
+                }
+                Option::Some(Tilt {
+                    lamp_to_luminaire_geometry: geometry,
+                    angles,
+                    multipliers: tokens.numbers(pairs, "tilt multiplier")?,
+                })
+            }
+            file => {
+                return Err(parse_error(
+                    tilt_line,
+                    format!("external tilt file {} is not supported", file),
+                ))
+            }
+        };
+
+        let _lamps = tokens.number("number of lamps")?;
+        let lumens_per_lamp = tokens.number("lumens per lamp")?;
+        let multiplier = tokens.number("candela multiplier")?;
+        let vertical_count = tokens.count("number of vertical angles")?;
+        let horizontal_count = tokens.count("number of horizontal angles")?;
+        let type_line = tokens.tokens.get(tokens.position).map_or(0, |t| t.0);
+        let photometric_type = match tokens.count("photometric type")? {
+            1 => PhotometricType::C,
+            2 => PhotometricType::B,
+            3 => PhotometricType::A,
+            other => {
+                return Err(parse_error(
+                    type_line,
+                    format!("unknown photometric type {}", other),
+                ))
+            }
+        };
+        let _units = tokens.number("units type")?;
+        let _dimensions = tokens.numbers(3, "luminous opening dimension")?;
+        let ballast_factor = tokens.number("ballast factor")?;
+        let ballast_lamp_factor = tokens.number("ballast-lamp photometric factor")?;
+        let input_watts = tokens.number("input watts")?;
+
+        let vertical_start = tokens.position;
+        let vertical_angles = tokens.numbers(vertical_count, "vertical angle")?;
+        let horizontal_start = tokens.position;
+        let horizontal_angles = tokens.numbers(horizontal_count, "horizontal angle")?;
+        for (angles, start, what) in [
+            (&vertical_angles, vertical_start, "vertical"),
+            (&horizontal_angles, horizontal_start, "horizontal"),
+        ] {
+            // Blame the first angle out of order, or where the list should have been.
+            let position = match angles.windows(2).position(|w| w[0] > w[1]) {
+                Some(i) => start + i + 1,
+                None if angles.is_empty() => start,
+                None => continue,
+            };
+            let line = tokens
+                .tokens
+                .get(position)
+                .map_or(tokens.last_line(), |t| t.0);
+            return Err(parse_error(
+                line,
+                format!("{} angles must be a non-empty increasing list", what),
+            ));
+        }
+
+        let scale = multiplier * ballast_factor * ballast_lamp_factor;
+        let candela = (0..horizontal_count)
+            .map(|_| {
+                tokens
+                    .numbers(vertical_count, "candela value")
+                    .map(|values| values.iter().map(|c| c * scale).collect())
+            })
+            .collect::<Result<Vec<Vec<f64>>>>()?;
+
+        Ok(IesProfile {
+            keywords,
+            tilt,
+            photometric_type,
+            lumens_per_lamp,
+            input_watts,
+            vertical_angles,
+            horizontal_angles,
+            candela,
+        })
+    }
+
+    pub fn keyword(&self, key: &str) -> Option<&str> {
+        self.keywords
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn max_candela(&self) -> f64 {
+        self.candela.iter().flatten().cloned().fold(0.0, f64::max)
+    }
+
+    // Candela at the given photometric angles in degrees, interpolated bilinearly over the
+    // table. Angles outside the measured range are folded by the symmetry the table implies.
+    // The tilt multiplier is not applied, since it depends on how the luminaire is mounted.
+    pub fn candela_at(&self, horizontal: f64, vertical: f64) -> f64 {
+        let (horizontal, vertical) = self.fold(horizontal, vertical);
+        let (h0, h1, th) = bracket(&self.horizontal_angles, horizontal);
+        let (v0, v1, tv) = bracket(&self.vertical_angles, vertical);
+        let value = |h: usize, v: usize| self.candela[h][v];
+        let near = value(h0, v0) * (1.0 - tv) + value(h0, v1) * tv;
+        let far = value(h1, v0) * (1.0 - tv) + value(h1, v1) * tv;
+        near * (1.0 - th) + far * th
+    }
+
+    // Multiplier for the lamp output with the luminaire tilted by `angle` degrees from the
+    // orientation it was measured in, interpolated over the tilt table.
+    pub fn tilt_multiplier(&self, angle: f64) -> f64 {
+        self.tilt.as_ref().map_or(1.0, |tilt| {
+            let (a0, a1, t) = bracket(&tilt.angles, angle);
+            tilt.multipliers[a0] * (1.0 - t) + tilt.multipliers[a1] * t
+        })
+    }
+
+    // Candela towards a unit direction in the luminaire's frame: it points at -y, its length
+    // runs along x and its width along z.
+    pub fn candela(&self, w: &Vector3) -> f64 {
+        let (horizontal, vertical) = match self.photometric_type {
+            // Vertical angle from the nadir, horizontal angle around the vertical axis.
+            PhotometricType::C => (
+                w.z.atan2(w.x).to_degrees().rem_euclid(360.0),
+                (-w.y).clamp(-1.0, 1.0).acos().to_degrees(),
+            ),
+            // Horizontal angle around the lateral axis, vertical angle off the x-y plane.
+            PhotometricType::B => (
+                w.x.atan2(-w.y).to_degrees(),
+                w.z.clamp(-1.0, 1.0).asin().to_degrees(),
+            ),
+            // Horizontal angle around the longitudinal axis, vertical angle off the y-z plane.
+            PhotometricType::A => (
+                w.z.atan2(-w.y).to_degrees(),
+                w.x.clamp(-1.0, 1.0).asin().to_degrees(),
+            ),
+        };
+        self.candela_at(horizontal, vertical)
+    }
+
+    fn fold(&self, horizontal: f64, vertical: f64) -> (f64, f64) {
+        let first = self.horizontal_angles[0];
+        let last = *self.horizontal_angles.last().unwrap();
+        match self.photometric_type {
+            PhotometricType::C => {
+                let h = horizontal.rem_euclid(360.0);
+                let h = if last == 0.0 {
+                    // Axially symmetric.
+                    0.0
+                } else if last == 90.0 {
+                    // Symmetric in each quadrant.
+                    let h = h % 180.0;
+                    if h > 90.0 {
+                        180.0 - h
+                    } else {
+                        h
+                    }
+                } else if last == 180.0 {
+                    // Symmetric about the 0-180 degree plane.
+                    if h > 180.0 {
+                        360.0 - h
+                    } else {
+                        h
+                    }
+                } else if first == 90.0 && last == 270.0 {
+                    // Symmetric about the 90-270 degree plane.
+                    if h < 90.0 {
+                        180.0 - h
+                    } else if h > 270.0 {
+                        540.0 - h
+                    } else {
+                        h
+                    }
+                } else {
+                    h
+                };
+                (h, vertical)
+            }
+            PhotometricType::A | PhotometricType::B => {
+                // Tables starting at zero only cover one side of a symmetric distribution.
+                let h = if first == 0.0 {
+                    horizontal.abs()
+                } else {
+                    horizontal
+                };
+                let v = if self.vertical_angles[0] == 0.0 {
+                    vertical.abs()
+                } else {
+                    vertical
+                };
+                (h, v)
+            }
+        }
+    }
+}
+
+// Finds the two table entries around x and the interpolation weight between them, clamping to
+// the ends of the table.
+fn bracket(angles: &[f64], x: f64) -> (usize, usize, f64) {
+    let upper = angles.partition_point(|a| *a <= x);
+    if upper == 0 {
+        return (0, 0, 0.0);
+    }
+    if upper == angles.len() {
+        return (upper - 1, upper - 1, 0.0);
+    }
+    let (a0, a1) = (angles[upper - 1], angles[upper]);
+    (upper - 1, upper, (x - a0) / (a1 - a0))
+}
+
+// A profile placed in the world, for lights to look their emission up in.
+#[derive(Clone)]
+pub struct PhotometricWeb {
+    pub profile: Arc<IesProfile>,
+    // Unit axes of the luminaire frame in world space.
+    nadir: Vector3,
+    length: Vector3,
+    width: Vector3,
+    // Angle in degrees between the nadir and straight down, which the tilt table is indexed by.
+    tilt_angle: f64,
+}
+
+impl PhotometricWeb {
+    // Aims the fixture at `nadir`, turning it so that its length lies along `length` as far as
+    // possible.
+    pub fn new(profile: Arc<IesProfile>, nadir: Vector3, length: Vector3) -> PhotometricWeb {
+        let nadir = nadir.unit();
+        let width = nadir.cross(&length).unit();
+        let length = width.cross(&nadir);
+        PhotometricWeb {
+            profile,
+            nadir,
+            length,
+            width,
+            tilt_angle: (-nadir.y).clamp(-1.0, 1.0).acos().to_degrees(),
+        }
+    }
+
+    pub fn tilt_angle(&self) -> f64 {
+        self.tilt_angle
+    }
+
+    // Peak candela as mounted.
+    pub fn max_candela(&self) -> f64 {
+        self.profile.tilt_multiplier(self.tilt_angle) * self.profile.max_candela()
+    }
+
+    // Candela towards a world space direction pointing away from the fixture.
+    pub fn candela(&self, w: &Vector3) -> f64 {
+        let w = w.unit();
+        let local = Vector3::new(w.dot(&self.length), -w.dot(&self.nadir), w.dot(&self.width));
+        self.profile.tilt_multiplier(self.tilt_angle) * self.profile.candela(&local)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::ies::{IesProfile, PhotometricType, PhotometricWeb};
+    use crate::vector3::Vector3;
+
+    fn sample() -> IesProfile {
+        IesProfile::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/assets/ies/downlight.ies"
+        ))
+        .unwrap()
+    }
+
+    fn assert_near(expected: f64, actual: f64) {
+        assert!(
+            (expected - actual).abs() < 1e-6,
+            "{} != {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn load_sample() {
+        let profile = sample();
+        assert_eq!(PhotometricType::C, profile.photometric_type);
+        assert_eq!(Some("DL-100"), profile.keyword("LUMCAT"));
+        assert!(profile.keyword("_NOTE").unwrap().contains("wider beam"));
+        assert_eq!(7, profile.vertical_angles.len());
+        assert_eq!(vec![0.0, 45.0, 90.0], profile.horizontal_angles);
+        assert_eq!(3, profile.tilt.as_ref().unwrap().angles.len());
+        assert_eq!(1000.0, profile.max_candela());
+        assert_eq!(1000.0, profile.lumens_per_lamp);
+        assert_eq!(10.0, profile.input_watts);
+    }
+
+    #[test]
+    fn interpolation() {
+        let profile = sample();
+        assert_near(1000.0, profile.candela_at(0.0, 0.0));
+        assert_near(875.0, profile.candela_at(0.0, 22.5));
+        assert_near(0.5 * (800.0 + 830.0), profile.candela_at(22.5, 30.0));
+        // Quadrant symmetry maps 135 and 315 degrees back onto 45.
+        assert_near(
+            profile.candela_at(45.0, 40.0),
+            profile.candela_at(135.0, 40.0),
+        );
+        assert_near(
+            profile.candela_at(45.0, 40.0),
+            profile.candela_at(315.0, 40.0),
+        );
+        // Nothing is emitted above the horizon.
+        assert_near(0.0, profile.candela_at(0.0, 120.0));
+    }
+
+    #[test]
+    fn directions() {
+        let profile = Arc::new(sample());
+        let web = PhotometricWeb::new(
+            profile,
+            Vector3::new(0.0, -1.0, 0.0),
+            Vector3::new(1.0, 0.0, 0.0),
+        );
+        assert_near(1000.0, web.candela(&Vector3::new(0.0, -1.0, 0.0)));
+        let along = web.candela(&Vector3::new(1.0, -1.0, 0.0));
+        let across = web.candela(&Vector3::new(0.0, -1.0, 1.0));
+        assert_near(500.0, along);
+        assert_near(580.0, across);
+    }
+
+    // Message of the error parsing `text`, which must fail.
+    fn parse_error(text: &str) -> String {
+        match IesProfile::parse(text) {
+            Err(e) => e.to_string(),
+            Ok(_) => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(parse_error("IESNA:LM-63-2002\n[TEST] x\n").contains("TILT"));
+        let message = parse_error("TILT=NONE\n1 1000 1 2 1 1 2 0 0 0\n1 1 10\n0 90\n0\n100 abc\n");
+        assert!(message.contains("IES line 6:"));
+        assert!(message.contains("abc"));
+        let message = parse_error("TILT=NONE\n1 1000 1 2 1 7 2 0 0 0\n");
+        assert!(message.contains("IES line 2:"));
+        assert!(message.contains("photometric type"));
+        assert!(
+            IesProfile::parse("TILT=NONE\n1 1000 1 2 1 1 2 0 0 0\n1 1 10\n0 90\n0\n100\n").is_err()
+        );
+        // The line with the angle out of order, not the one with the photometric type.
+        let message =
+            parse_error("TILT=NONE\n1 1000 1 3 1 1 2 0 0 0\n1 1 10\n0\n90 45\n0\n100 50 0\n");
+        assert!(message.contains("IES line 5:"));
+        assert!(message.contains("vertical"));
+
+        // A tilt table must have angles, in strictly increasing order.
+        let photometry = "1 1000 1 2 1 1 2 0 0 0\n1 1 10\n0 90\n0\n100 100\n";
+        let message = parse_error(&format!("TILT=INCLUDE\n1\n0\n{}", photometry));
+        assert!(message.contains("IES line 3:"));
+        assert!(message.contains("at least one"));
+        let message = parse_error(&format!(
+            "TILT=INCLUDE\n1\n3\n0\n45 45\n1 1 1\n{}",
+            photometry
+        ));
+        assert!(message.contains("IES line 5:"));
+        assert!(message.contains("strictly increasing"));
+    }
+
+    #[test]
+    fn tilt() {
+        // Half the output with the lamp on its side.
+        let text = "TILT=INCLUDE\n1\n2\n0 90\n1 0.5\n\
+                    1 1000 1 2 1 1 2 0 0 0\n1 1 10\n0 90\n0\n100 100\n";
+        let profile = Arc::new(IesProfile::parse(text).unwrap());
+        assert_near(0.75, profile.tilt_multiplier(45.0));
+        // The table alone is unaffected.
+        assert_near(100.0, profile.candela_at(0.0, 0.0));
+
+        let length = Vector3::new(0.0, 0.0, 1.0);
+        let down = PhotometricWeb::new(profile.clone(), Vector3::new(0.0, -1.0, 0.0), length);
+        assert_near(0.0, down.tilt_angle());
+        assert_near(100.0, down.candela(&Vector3::new(0.0, -1.0, 0.0)));
+        let sideways = PhotometricWeb::new(profile.clone(), Vector3::new(1.0, 0.0, 0.0), length);
+        assert_near(90.0, sideways.tilt_angle());
+        assert_near(50.0, sideways.candela(&Vector3::new(1.0, 0.0, 0.0)));
+        let aimed = PhotometricWeb::new(profile, Vector3::new(1.0, -1.0, 0.0), length);
+        assert_near(75.0, aimed.candela(&Vector3::new(1.0, -1.0, 0.0)));
+    }
+}
@@ -0,0 +1,462 @@
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::vector3::Vector3 as Color;
+
+// Single channel image with samples normalized to [0, 1], stored row by row from the top.
+pub struct GrayImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<f64>,
+}
+
+impl GrayImage {
+    pub fn get(&self, x: usize, y: usize) -> f64 {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn read_pgm<P: AsRef<Path>>(path: P) -> Result<GrayImage> {
+        GrayImage::from_pgm(BufReader::new(File::open(path)?))
+    }
+
+    // Parses binary (P5) or plain (P2) PGM. Samples wider than a byte are big-endian, as the
+    // format requires.
+    pub fn from_pgm<R: Read>(mut reader: R) -> Result<GrayImage> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let mut header = PnmHeader::new(&bytes);
+
+        let magic = header.token()?;
+        let width = header.number()?;
+        let height = header.number()?;
+        let max_value = header.number()?;
+        if max_value == 0 || max_value > 65535 {
+            return Err(Error::Parse(format!("invalid PGM max value {}", max_value)));
+        }
+        let scale = 1.0 / max_value as f64;
+        let count = width
+            .checked_mul(height)
+            .ok_or_else(|| Error::Parse(format!("PGM size {}x{} is too large", width, height)))?;
+
+        let pixels = match magic.as_str() {
+            "P5" => {
+                // A single whitespace byte separates the header from the samples.
+                let data = bytes
+                    .get(header.position + 1..)
+                    .ok_or_else(|| Error::Parse("PGM has no data".to_string()))?;
+                let sample_size = if max_value < 256 { 1 } else { 2 };
+                if data.len() / sample_size < count {
+                    return Err(Error::Parse("truncated PGM data".to_string()));
+                }
+                data.chunks(sample_size)
+                    .take(count)
+                    .map(|s| match s {
+                        [b] => *b as f64 * scale,
+                        [hi, lo] => u16::from_be_bytes([*hi, *lo]) as f64 * scale,
+                        _ => unreachable!(),
+                    })
+                    .collect()
+            }
+            "P2" => (0..count)
+                .map(|_| header.number().map(|n| n as f64 * scale))
+                .collect::<Result<Vec<f64>>>()?,
+            _ => return Err(Error::Parse(format!("unsupported PGM type {}", magic))),
+        };
+
+        Ok(GrayImage {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    // Reads headerless 16-bit little-endian samples, the usual layout of DEM exports.
+    pub fn read_raw16<P: AsRef<Path>>(path: P, width: usize, height: usize) -> Result<GrayImage> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        if width == 0 || height == 0 {
+            return Err(Error::Parse(format!("empty raw size {}x{}", width, height)));
+        }
+        let count = width
+            .checked_mul(height)
+            .ok_or_else(|| Error::Parse(format!("raw size {}x{} is too large", width, height)))?;
+        if bytes.len() / 2 < count {
+            return Err(Error::Parse(
+                "raw file is smaller than its dimensions".to_string(),
+            ));
+        }
+        let pixels = bytes
+            .chunks(2)
+            .take(count)
+            .map(|s| u16::from_le_bytes([s[0], s[1]]) as f64 / 65535.0)
+            .collect();
+        Ok(GrayImage {
+            width,
+            height,
+            pixels,
+        })
+    }
+}
+
+// Linear, high dynamic range color image stored row by row from the top.
+pub struct RgbImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Color>,
+}
+
+impl RgbImage {
+    pub fn get(&self, x: usize, y: usize) -> Color {
+        self.pixels[y * self.width + x]
+    }
+
+    // Reads a Radiance .hdr or a PFM file, chosen by extension.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<RgbImage> {
+        let extension = path
+            .as_ref()
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        let reader = BufReader::new(File::open(&path)?);
+        match extension.as_deref() {
+            Some("hdr") | Some("pic") => RgbImage::from_hdr(reader),
+            Some("pfm") => RgbImage::from_pfm(reader),
+            _ => Err(Error::Parse(format!(
+                "unsupported image format {}",
+                path.as_ref().display()
+            ))),
+        }
+    }
+
+    // Parses a Radiance RGBE image, either flat or with per-scanline run length encoding.
+    pub fn from_hdr<R: Read>(mut reader: R) -> Result<RgbImage> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        if !bytes.starts_with(b"#?") {
+            return Err(Error::Parse("missing Radiance signature".to_string()));
+        }
+
+        // The header is a list of lines terminated by an empty one, followed by the resolution.
+        let mut position = 0;
+        let mut line = || -> Result<String> {
+            let start = position;
+            while *bytes
+                .get(position)
+                .ok_or_else(|| Error::Parse("truncated header".to_string()))?
+                != b'\n'
+            {
+                position += 1;
+            }
+            position += 1;
+            Ok(String::from_utf8_lossy(&bytes[start..position - 1]).into_owned())
+        };
+        loop {
+            let header_line = line()?;
+            if header_line.is_empty() {
+                break;
+            }
+            if let Some(format) = header_line.strip_prefix("FORMAT=") {
+                if format != "32-bit_rle_rgbe" {
+                    return Err(Error::Parse(format!("unsupported format {}", format)));
+                }
+            }
+        }
+        let resolution = line()?;
+        let fields: Vec<&str> = resolution.split_whitespace().collect();
+        let (height, width) = match fields.as_slice() {
+            ["-Y", height, "+X", width] => (height.parse().ok(), width.parse().ok()),
+            _ => (Option::None, Option::None),
+        };
+        let (height, width): (usize, usize) = match (height, width) {
+            (Some(height), Some(width)) => (height, width),
+            _ => {
+                return Err(Error::Parse(format!(
+                    "unsupported resolution {}",
+                    resolution
+                )))
+            }
+        };
+
+        let mut data = bytes[position..].iter().cloned();
+        let mut next = || {
+            data.next()
+                .ok_or_else(|| Error::Parse("truncated pixel data".to_string()))
+        };
+        let mut pixels = Vec::with_capacity(width * height);
+        let mut scanline = vec![[0u8; 4]; width];
+        for _ in 0..height {
+            let first = [next()?, next()?, next()?, next()?];
+            let encoded = (8..0x8000).contains(&width)
+                && first[0] == 2
+                && first[1] == 2
+                && first[2] & 0x80 == 0;
+            if encoded {
+                if ((first[2] as usize) << 8 | first[3] as usize) != width {
+                    return Err(Error::Parse("scanline width mismatch".to_string()));
+                }
+                // Each of the four components is run length encoded separately.
+                for component in 0..4 {
+                    let mut x = 0;
+                    while x < width {
+                        let count = next()? as usize;
+                        if count > 128 {
+                            let count = count - 128;
+                            let value = next()?;
+                            for texel in scanline.iter_mut().skip(x).take(count) {
+                                texel[component] = value;
+                            }
+                            x += count;
+                        } else {
+                            for texel in scanline.iter_mut().skip(x).take(count) {
+                                texel[component] = next()?;
+                            }
+                            x += count;
+                        }
+                        if count == 0 || x > width {
+                            return Err(Error::Parse("bad scanline run".to_string()));
+                        }
+                    }
+                }
+            } else {
+                scanline[0] = first;
+                for texel in scanline.iter_mut().skip(1) {
+                    *texel = [next()?, next()?, next()?, next()?];
+                }
+            }
+            pixels.extend(scanline.iter().map(|&[r, g, b, e]| {
+                if e == 0 {
+                    return Color::zero();
+                }
+                let f = 2.0_f64.powi(e as i32 - 136);
+                Color::new(r as f64 * f, g as f64 * f, b as f64 * f)
+            }));
+        }
+
+        Ok(RgbImage {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    // Parses a color (PF) or grayscale (Pf) portable float map. A negative scale marks
+    // little-endian data, and rows are stored from the bottom.
+    pub fn from_pfm<R: Read>(mut reader: R) -> Result<RgbImage> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let mut header = PnmHeader::new(&bytes);
+
+        let channels = match header.token()?.as_str() {
+            "PF" => 3,
+            "Pf" => 1,
+            magic => return Err(Error::Parse(format!("unsupported PFM type {}", magic))),
+        };
+        let width = header.number()?;
+        let height = header.number()?;
+        let scale_token = header.token()?;
+        let scale: f64 = scale_token
+            .parse()
+            .map_err(|_| Error::Parse(format!("invalid PFM scale {}", scale_token)))?;
+
+        if width == 0 || height == 0 {
+            return Err(Error::Parse(format!("empty PFM size {}x{}", width, height)));
+        }
+        let count = width
+            .checked_mul(height)
+            .and_then(|n| n.checked_mul(channels))
+            .ok_or_else(|| Error::Parse(format!("PFM size {}x{} is too large", width, height)))?;
+
+        // A single whitespace byte separates the header from the samples.
+        let data = bytes
+            .get(header.position + 1..)
+            .ok_or_else(|| Error::Parse("PFM has no data".to_string()))?;
+        if data.len() / 4 < count {
+            return Err(Error::Parse("truncated PFM data".to_string()));
+        }
+        let samples: Vec<f64> = data
+            .chunks(4)
+            .take(count)
+            .map(|s| {
+                let s = [s[0], s[1], s[2], s[3]];
+                let value = if scale < 0.0 {
+                    f32::from_le_bytes(s)
+                } else {
+                    f32::from_be_bytes(s)
+                };
+                value as f64
+            })
+            .collect();
+
+        let mut pixels = Vec::with_capacity(width * height);
+        for row in samples.chunks(width * channels).rev() {
+            pixels.extend(row.chunks(channels).map(|c| match c {
+                [v] => Color::new(*v, *v, *v),
+                [r, g, b] => Color::new(*r, *g, *b),
+                _ => unreachable!(),
+            }));
+        }
+
+        Ok(RgbImage {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    // Writes a little-endian color PFM, which keeps the full range of the image.
+    pub fn write_pfm<W: Write>(&self, mut out: W) -> Result<()> {
+        write!(out, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        for row in self.pixels.chunks(self.width).rev() {
+            for pixel in row {
+                for value in [pixel.x, pixel.y, pixel.z] {
+                    out.write_all(&(value as f32).to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Tokenizer for the whitespace separated header shared by the PNM family of formats.
+pub(crate) struct PnmHeader<'a> {
+    bytes: &'a [u8],
+    pub position: usize,
+}
+
+impl PnmHeader<'_> {
+    pub fn new(bytes: &[u8]) -> PnmHeader<'_> {
+        PnmHeader { bytes, position: 0 }
+    }
+
+    pub fn token(&mut self) -> Result<String> {
+        loop {
+            match self.bytes.get(self.position) {
+                Some(b'#') => {
+                    while !matches!(self.bytes.get(self.position), Some(b'\n') | None) {
+                        self.position += 1;
+                    }
+                }
+                Some(b) if b.is_ascii_whitespace() => self.position += 1,
+                Some(_) => break,
+                None => return Err(Error::Parse("unexpected end of header".to_string())),
+            }
+        }
+        let start = self.position;
+        while matches!(self.bytes.get(self.position), Some(b) if !b.is_ascii_whitespace()) {
+            self.position += 1;
+        }
+        Ok(String::from_utf8_lossy(&self.bytes[start..self.position]).into_owned())
+    }
+
+    pub fn number(&mut self) -> Result<usize> {
+        let token = self.token()?;
+        token
+            .parse()
+            .map_err(|_| Error::Parse(format!("expected a number, found {}", token)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use crate::image::{GrayImage, RgbImage};
+    use crate::vector3::Vector3 as Color;
+
+    #[test]
+    fn binary_pgm() {
+        let mut bytes = b"P5\n# elevation\n2 1\n65535\n".to_vec();
+        bytes.extend_from_slice(&[0x00, 0x00, 0xff, 0xff]);
+        let image = GrayImage::from_pgm(&bytes[..]).unwrap();
+        assert_eq!(2, image.width);
+        assert_eq!(1, image.height);
+        assert_eq!(vec![0.0, 1.0], image.pixels);
+    }
+
+    #[test]
+    fn plain_pgm() {
+        let image = GrayImage::from_pgm(&b"P2 2 2 4 0 1\n2 4"[..]).unwrap();
+        assert_eq!(vec![0.0, 0.25, 0.5, 1.0], image.pixels);
+        assert_eq!(0.5, image.get(0, 1));
+    }
+
+    #[test]
+    fn truncated_pgm() {
+        assert!(GrayImage::from_pgm(&b"P5 4 4 255\n\x00"[..]).is_err());
+        // Nothing at all after the header.
+        assert!(GrayImage::from_pgm(&b"P5 4 4 255"[..]).is_err());
+        assert!(GrayImage::from_pgm(&b"P5 99999999999 99999999999 255\n"[..]).is_err());
+    }
+
+    #[test]
+    fn raw16() {
+        let path = env::temp_dir().join(format!("raytracer-{}.raw", std::process::id()));
+        fs::write(&path, [0x00, 0x00, 0xff, 0xff, 0x00]).unwrap();
+        let image = GrayImage::read_raw16(&path, 2, 1).unwrap();
+        assert_eq!(vec![0.0, 1.0], image.pixels);
+        assert!(GrayImage::read_raw16(&path, 2, 2).is_err());
+        assert!(GrayImage::read_raw16(&path, 0, 1).is_err());
+        assert!(GrayImage::read_raw16(&path, usize::MAX, 2).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn flat_hdr() {
+        let mut bytes = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 2\n".to_vec();
+        bytes.extend_from_slice(&[128, 64, 0, 129, 0, 0, 0, 0]);
+        let image = RgbImage::from_hdr(&bytes[..]).unwrap();
+        assert_eq!(vec![Color::new(1.0, 0.5, 0.0), Color::zero()], image.pixels);
+    }
+
+    #[test]
+    fn encoded_hdr() {
+        let mut bytes = b"#?RGBE\n\n-Y 1 +X 8\n".to_vec();
+        bytes.extend_from_slice(&[2, 2, 0, 8]);
+        // Red as a run, green and blue as literals, exponent as a run.
+        bytes.extend_from_slice(&[136, 128]);
+        bytes.extend_from_slice(&[8, 0, 0, 0, 0, 64, 64, 64, 64]);
+        bytes.extend_from_slice(&[8, 0, 0, 0, 0, 0, 0, 0, 0]);
+        bytes.extend_from_slice(&[136, 129]);
+        let image = RgbImage::from_hdr(&bytes[..]).unwrap();
+        assert_eq!(8, image.width);
+        assert_eq!(Color::new(1.0, 0.0, 0.0), image.get(0, 0));
+        assert_eq!(Color::new(1.0, 0.5, 0.0), image.get(7, 0));
+    }
+
+    #[test]
+    fn pfm() {
+        let mut bytes = b"Pf\n1 2\n-1.0\n".to_vec();
+        bytes.extend_from_slice(&1.0f32.to_le_bytes());
+        bytes.extend_from_slice(&2.0f32.to_le_bytes());
+        let image = RgbImage::from_pfm(&bytes[..]).unwrap();
+        // The first row in the file is the bottom one.
+        assert_eq!(Color::new(2.0, 2.0, 2.0), image.get(0, 0));
+        assert_eq!(Color::new(1.0, 1.0, 1.0), image.get(0, 1));
+
+        let image = RgbImage {
+            width: 2,
+            height: 2,
+            pixels: vec![
+                Color::new(1.0, 2.0, 3.0),
+                Color::zero(),
+                Color::new(0.5, 100.0, 0.25),
+                Color::new(4.0, 4.0, 4.0),
+            ],
+        };
+        let mut bytes = Vec::new();
+        image.write_pfm(&mut bytes).unwrap();
+        assert_eq!(image.pixels, RgbImage::from_pfm(&bytes[..]).unwrap().pixels);
+    }
+
+    #[test]
+    fn truncated_pfm() {
+        assert!(RgbImage::from_pfm(&b"PF\n1 1\n-1.0\n\x00\x00"[..]).is_err());
+        // Nothing at all after the header.
+        assert!(RgbImage::from_pfm(&b"PF\n1 1\n-1.0"[..]).is_err());
+        assert!(RgbImage::from_pfm(&b"PF\n0 1\n-1.0\n"[..]).is_err());
+        assert!(RgbImage::from_pfm(&b"PF 99999999999 99999999999 -1.0\n"[..]).is_err());
+    }
+}
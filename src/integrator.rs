@@ -0,0 +1,576 @@
+use std::cell::Cell;
+
+use crate::color::luminance;
+use crate::cryptomatte::name_id;
+use crate::film::ChannelFilter;
+use crate::hittable::{HitRecord, Hittable};
+use crate::light::Light;
+use crate::ray::Ray;
+use crate::sampling::power_heuristic;
+use crate::scene::Scene;
+use crate::stats::count;
+use crate::util::random_f64;
+use crate::vector3::{Vector3 as Color, Vector3 as Point3, Vector3};
+
+// Keeps shadow rays from hitting the surface of the light they are aimed at.
+const SHADOW_EPSILON: f64 = 1e-6;
+
+thread_local! {
+    // Remaining depth at the bounce where the path being traced first turned out radiance that
+    // isn't finite. Bounces are shaded deepest first, so this is where the bad value came from.
+    static NONFINITE_DEPTH: Cell<Option<u8>> = const { Cell::new(Option::None) };
+}
+
+// Notes the bounce at `depth` as the source of a bad value unless a deeper one already is.
+fn check_finite(color: Color, depth: u8) -> Color {
+    if !color.is_finite() {
+        NONFINITE_DEPTH.with(|d| {
+            if d.get().is_none() {
+                d.set(Option::Some(depth));
+            }
+        });
+    }
+    color
+}
+
+pub fn ray_color(r: &Ray, scene: &Scene, depth: u8) -> Color {
+    count(|stats| stats.camera_rays += 1);
+    trace(r, scene, depth, Option::None)
+}
+
+// Properties of the first surface a camera ray hits, which guide the denoiser.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Features {
+    pub albedo: Color,
+    // Shading normal facing the ray, or zero if the ray escaped.
+    pub normal: Vector3,
+    // Distance along the ray, infinite if it escaped.
+    pub depth: f64,
+}
+
+// Everything the integrator can tell about a camera ray, for compositing: the first surface it
+// hits and the light it carries, split by the kind of lobe at that surface and by whether the
+// light arrives directly or after further bounces. The light components add up to the radiance
+// returned by `ray_color`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aovs {
+    // Distance along the ray, infinite if it escaped.
+    pub depth: f64,
+    // Shading normal facing the ray, or zero if the ray escaped.
+    pub normal: Vector3,
+    pub albedo: Color,
+    // Index of the object in the world, or None if the ray escaped.
+    pub object: Option<usize>,
+    // Hashed names of the object and its material, or 0 if the ray escaped.
+    pub object_id: f32,
+    pub material_id: f32,
+    pub u: f64,
+    pub v: f64,
+    pub position: Point3,
+    // Light reflected by a diffuse surface straight from a light or the environment, and after
+    // bouncing off further surfaces.
+    pub direct_diffuse: Color,
+    pub indirect_diffuse: Color,
+    // The same for specular surfaces, which don't sample lights.
+    pub direct_specular: Color,
+    pub indirect_specular: Color,
+    // Light seen directly, which is the environment for rays that escape.
+    pub emission: Color,
+    // Bounce at which the radiance stopped being finite, such as from a degenerate direction,
+    // counting the camera ray as 0. None if the radiance is finite.
+    pub nonfinite_bounce: Option<u8>,
+}
+
+// Names of the values returned by `Aovs::values`, as layer.channel, with how the samples of a
+// pixel are combined.
+pub const AOV_CHANNELS: [(&str, ChannelFilter); 29] = [
+    ("depth.Z", ChannelFilter::Nearest),
+    ("normal.X", ChannelFilter::Mean),
+    ("normal.Y", ChannelFilter::Mean),
+    ("normal.Z", ChannelFilter::Mean),
+    ("albedo.R", ChannelFilter::Mean),
+    ("albedo.G", ChannelFilter::Mean),
+    ("albedo.B", ChannelFilter::Mean),
+    ("object.id", ChannelFilter::Majority),
+    ("material.id", ChannelFilter::Majority),
+    ("uv.U", ChannelFilter::Mean),
+    ("uv.V", ChannelFilter::Mean),
+    ("position.X", ChannelFilter::Mean),
+    ("position.Y", ChannelFilter::Mean),
+    ("position.Z", ChannelFilter::Mean),
+    ("direct_diffuse.R", ChannelFilter::Mean),
+    ("direct_diffuse.G", ChannelFilter::Mean),
+    ("direct_diffuse.B", ChannelFilter::Mean),
+    ("indirect_diffuse.R", ChannelFilter::Mean),
+    ("indirect_diffuse.G", ChannelFilter::Mean),
+    ("indirect_diffuse.B", ChannelFilter::Mean),
+    ("direct_specular.R", ChannelFilter::Mean),
+    ("direct_specular.G", ChannelFilter::Mean),
+    ("direct_specular.B", ChannelFilter::Mean),
+    ("indirect_specular.R", ChannelFilter::Mean),
+    ("indirect_specular.G", ChannelFilter::Mean),
+    ("indirect_specular.B", ChannelFilter::Mean),
+    ("emission.R", ChannelFilter::Mean),
+    ("emission.G", ChannelFilter::Mean),
+    ("emission.B", ChannelFilter::Mean),
+];
+
+impl Aovs {
+    // What the denoiser needs to know of the first surface, without tracing the ray again.
+    pub fn features(&self) -> Features {
+        match self.object {
+            Some(_) => Features {
+                albedo: self.albedo,
+                normal: self.normal,
+                depth: self.depth,
+            },
+            None => Features {
+                // The environment is all lighting, which an albedo of one leaves as it is.
+                albedo: Color::new(1.0, 1.0, 1.0),
+                normal: Vector3::zero(),
+                depth: f64::INFINITY,
+            },
+        }
+    }
+
+    pub fn beauty(&self) -> Color {
+        self.direct_diffuse
+            + self.indirect_diffuse
+            + self.direct_specular
+            + self.indirect_specular
+            + self.emission
+    }
+
+    // Scales the light of every component, leaving the other values alone.
+    pub fn scale_light(&mut self, weight: f64) {
+        for color in [
+            &mut self.direct_diffuse,
+            &mut self.indirect_diffuse,
+            &mut self.direct_specular,
+            &mut self.indirect_specular,
+            &mut self.emission,
+        ] {
+            *color = weight * *color;
+        }
+    }
+
+    // Scales each of the indirect components down so that none of its channels exceeds `max`,
+    // which suppresses fireflies from rare bright paths at the cost of some energy. Returns the
+    // luminance removed.
+    pub fn clamp_indirect(&mut self, max: f64) -> f64 {
+        let mut removed = 0.0;
+        for color in [&mut self.indirect_diffuse, &mut self.indirect_specular] {
+            let peak = color.max_component();
+            if peak > max {
+                let clamped = max / peak * *color;
+                removed += luminance(color) - luminance(&clamped);
+                *color = clamped;
+            }
+        }
+        removed
+    }
+
+    pub fn values(&self) -> [f64; 29] {
+        let rgb = |c: Color| [c.x, c.y, c.z];
+        let mut values = [0.0; 29];
+        values[0] = self.depth;
+        values[1..4].copy_from_slice(&rgb(self.normal));
+        values[4..7].copy_from_slice(&rgb(self.albedo));
+        values[7] = self.object_id as f64;
+        values[8] = self.material_id as f64;
+        values[9] = self.u;
+        values[10] = self.v;
+        values[11..14].copy_from_slice(&rgb(self.position));
+        values[14..17].copy_from_slice(&rgb(self.direct_diffuse));
+        values[17..20].copy_from_slice(&rgb(self.indirect_diffuse));
+        values[20..23].copy_from_slice(&rgb(self.direct_specular));
+        values[23..26].copy_from_slice(&rgb(self.indirect_specular));
+        values[26..29].copy_from_slice(&rgb(self.emission));
+        values
+    }
+}
+
+pub fn ray_aovs(r: &Ray, scene: &Scene, depth: u8) -> Aovs {
+    NONFINITE_DEPTH.with(|d| d.set(Option::None));
+    count(|stats| stats.camera_rays += 1);
+    let mut aovs = shade_aovs(r, scene, depth);
+    if !aovs.beauty().is_finite() {
+        let bad = NONFINITE_DEPTH.with(|d| d.take()).unwrap_or(depth);
+        aovs.nonfinite_bounce = Option::Some(depth - bad);
+    }
+    aovs
+}
+
+fn shade_aovs(r: &Ray, scene: &Scene, depth: u8) -> Aovs {
+    let mut aovs = Aovs {
+        depth: f64::INFINITY,
+        normal: Vector3::zero(),
+        albedo: Color::zero(),
+        object: Option::None,
+        object_id: 0.0,
+        material_id: 0.0,
+        u: 0.0,
+        v: 0.0,
+        position: Point3::zero(),
+        direct_diffuse: Color::zero(),
+        indirect_diffuse: Color::zero(),
+        direct_specular: Color::zero(),
+        indirect_specular: Color::zero(),
+        emission: Color::zero(),
+        nonfinite_bounce: Option::None,
+    };
+    if depth == 0 {
+        return aovs;
+    }
+    let (index, hit) = match hit_object(r, scene) {
+        Some(hit) => hit,
+        None => {
+            aovs.emission = escaped(r, scene, Option::None);
+            return aovs;
+        }
+    };
+    count(|stats| stats.path_vertices += 1);
+    aovs.depth = hit.t * r.direction.length();
+    aovs.normal = hit.normal;
+    aovs.albedo = hit.material.albedo();
+    aovs.object = Option::Some(index);
+    aovs.object_id = name_id(&scene.object_name(index));
+    aovs.material_id = name_id(&scene.material_name(index));
+    aovs.u = hit.u;
+    aovs.v = hit.v;
+    aovs.position = hit.p;
+
+    // The same estimate as `shade`, taken apart.
+    let scatter = match hit.material.scatter(r, &hit.p, &hit.normal, hit.front_face) {
+        Some(scatter) => scatter,
+        None => return aovs,
+    };
+    let pdf = hit
+        .material
+        .scattering_pdf(r, &hit.normal, &scatter.scattered_ray.direction);
+    let bounce = &scatter.scattered_ray;
+    let (mut direct, mut indirect) = (Color::zero(), Color::zero());
+    if depth > 1 {
+        count(|stats| stats.scatter_rays += 1);
+        match scene.world.hit(bounce, 0.001, f64::INFINITY) {
+            Some(next) => indirect = scatter.attenuation * shade(bounce, &next, scene, depth - 1),
+            None => {
+                let scattering_pdf = if pdf > 0.0 { Option::Some(pdf) } else { None };
+                let radiance = check_finite(escaped(bounce, scene, scattering_pdf), depth - 1);
+                direct = scatter.attenuation * radiance;
+            }
+        }
+    }
+    if pdf > 0.0 {
+        aovs.direct_diffuse =
+            direct + sample_environment(r, &hit, scene) + sample_lights(r, &hit, scene);
+        aovs.indirect_diffuse = indirect;
+    } else {
+        aovs.direct_specular = direct;
+        aovs.indirect_specular = indirect;
+    }
+    aovs
+}
+
+// Closest hit along the ray, with the index of the object of the world that was hit.
+fn hit_object<'a>(r: &Ray, scene: &'a Scene) -> Option<(usize, HitRecord<'a>)> {
+    let mut output = Option::None;
+    let mut closest_so_far = f64::INFINITY;
+    for (index, object) in scene.world.iter().enumerate() {
+        if let Some(hit) = object.hit(r, 0.001, closest_so_far) {
+            closest_so_far = hit.t;
+            output = Option::Some((index, hit));
+        }
+    }
+    output
+}
+
+// `scattering_pdf` is the density with which the previous bounce picked this ray, or None if it
+// wasn't picked in a way that the environment could have been sampled from as well.
+fn trace(r: &Ray, scene: &Scene, depth: u8, scattering_pdf: Option<f64>) -> Color {
+    // If we've exceeded the ray bounce limit, no more light is gathered.
+    if depth == 0 {
+        return Color::zero();
+    }
+    match scene.world.hit(r, 0.001, f64::INFINITY) {
+        Some(hit) => shade(r, &hit, scene, depth),
+        None => check_finite(escaped(r, scene, scattering_pdf), depth),
+    }
+}
+
+// Light leaving the hit along the ray.
+fn shade(r: &Ray, hit: &HitRecord, scene: &Scene, depth: u8) -> Color {
+    count(|stats| stats.path_vertices += 1);
+    let scatter_rec = hit.material.scatter(r, &hit.p, &hit.normal, hit.front_face);
+    if let Some(scatter) = scatter_rec {
+        if depth > 1 {
+            count(|stats| stats.scatter_rays += 1);
+        }
+        let pdf = hit
+            .material
+            .scattering_pdf(r, &hit.normal, &scatter.scattered_ray.direction);
+        if pdf > 0.0 {
+            let direct = sample_environment(r, hit, scene) + sample_lights(r, hit, scene);
+            let indirect = trace(&scatter.scattered_ray, scene, depth - 1, Option::Some(pdf));
+            return check_finite(direct + scatter.attenuation * indirect, depth);
+        }
+        let indirect = trace(&scatter.scattered_ray, scene, depth - 1, None);
+        check_finite(scatter.attenuation * indirect, depth)
+    } else {
+        Color::zero()
+    }
+}
+
+// Light from the environment along a ray that hit nothing.
+fn escaped(r: &Ray, scene: &Scene, scattering_pdf: Option<f64>) -> Color {
+    let radiance = scene.environment.radiance(&r.direction);
+    match scattering_pdf {
+        // Weight against the chance that the environment sample found this direction instead.
+        Some(pdf) => power_heuristic(pdf, scene.environment.pdf(&r.direction)) * radiance,
+        None => radiance,
+    }
+}
+
+// Next event estimation: shades the hit with a direction sampled from the environment.
+fn sample_environment(r: &Ray, hit: &HitRecord, scene: &Scene) -> Color {
+    let sample = match scene.environment.sample() {
+        Some(sample) if sample.pdf > 0.0 => sample,
+        _ => return Color::zero(),
+    };
+    let scattering = hit.material.scattering(r, &hit.normal, &sample.direction);
+    if scattering.near_zero() {
+        return Color::zero();
+    }
+    let shadow_ray = Ray::new(hit.p, sample.direction);
+    count(|stats| stats.shadow_rays += 1);
+    if scene.world.hit(&shadow_ray, 0.001, f64::INFINITY).is_some() {
+        return Color::zero();
+    }
+    let scattering_pdf = hit
+        .material
+        .scattering_pdf(r, &hit.normal, &sample.direction);
+    let weight = power_heuristic(sample.pdf, scattering_pdf) / sample.pdf;
+    weight * scattering * sample.radiance
+}
+
+// Next event estimation for the scene lights. With a light sampler one light is picked per hit,
+// otherwise a shadow ray is cast to each of them.
+fn sample_lights(r: &Ray, hit: &HitRecord, scene: &Scene) -> Color {
+    match &scene.light_sampler {
+        Some(sampler) => match sampler.sample(&hit.p, &hit.normal, random_f64()) {
+            Some((index, pmf)) if pmf > 0.0 => {
+                sample_light(r, hit, scene, scene.lights[index].as_ref(), pmf)
+            }
+            _ => Color::zero(),
+        },
+        None => scene.lights.iter().fold(Color::zero(), |color, light| {
+            color + sample_light(r, hit, scene, light.as_ref(), 1.0)
+        }),
+    }
+}
+
+// `pmf` is the probability with which the light was picked.
+fn sample_light(r: &Ray, hit: &HitRecord, scene: &Scene, light: &dyn Light, pmf: f64) -> Color {
+    let sample = match light.sample_li(&hit.p) {
+        Some(sample) if sample.pdf > 0.0 => sample,
+        _ => return Color::zero(),
+    };
+    let scattering = hit.material.scattering(r, &hit.normal, &sample.direction);
+    if scattering.near_zero() {
+        return Color::zero();
+    }
+    let shadow_ray = Ray::new(hit.p, sample.direction);
+    count(|stats| stats.shadow_rays += 1);
+    if scene
+        .world
+        .hit(&shadow_ray, 0.001, sample.distance * (1.0 - SHADOW_EPSILON))
+        .is_some()
+    {
+        return Color::zero();
+    }
+    let pdf = pmf * sample.pdf;
+    let mut weight = 1.0 / pdf;
+    if !light.is_delta() {
+        let scattering_pdf = hit
+            .material
+            .scattering_pdf(r, &hit.normal, &sample.direction);
+        weight *= power_heuristic(pdf, scattering_pdf);
+    }
+    weight * scattering * sample.radiance
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use crate::cryptomatte::name_id;
+    use crate::environment::ConstantEnvironment;
+    use crate::integrator::{ray_aovs, ray_color};
+    use crate::light::PointLight;
+    use crate::material::{Lambertian, Material, Metal, ScatterRecord};
+    use crate::ray::Ray;
+    use crate::scene::Scene;
+    use crate::sphere::Sphere;
+    use crate::vector3::{Vector3 as Color, Vector3 as Point3, Vector3};
+
+    #[test]
+    fn point_light() {
+        let mut scene = Scene::new(vec![Box::new(
+            Sphere::new(
+                Point3::zero(),
+                1.0,
+                Lambertian::new(Color::new(0.5, 0.5, 0.5)),
+            )
+            .unwrap(),
+        )]);
+        scene.environment = Box::new(ConstantEnvironment {
+            color: Color::zero(),
+        });
+        scene.lights.push(Box::new(PointLight::new(
+            Point3::new(0.0, 3.0, 0.0),
+            Color::new(8.0, 8.0, 8.0),
+        )));
+
+        // Looking straight down at the top of the sphere, two units below the light.
+        let r = Ray::new(Point3::new(0.0, 5.0, 0.0), Vector3::new(0.0, -1.0, 0.0));
+        let expected = 0.5 / PI * 8.0 / 4.0;
+        assert!((ray_color(&r, &scene, 10).x - expected).abs() < 1e-9);
+
+        // The bottom of the sphere is in its own shadow.
+        let r = Ray::new(Point3::new(0.0, -5.0, 0.0), Vector3::new(0.0, 1.0, 0.0));
+        assert_eq!(Color::zero(), ray_color(&r, &scene, 10));
+    }
+
+    #[test]
+    fn aovs() {
+        let mut scene = Scene::new(vec![
+            Box::new(
+                Sphere::new(
+                    Point3::new(0.0, -100.0, 0.0),
+                    99.0,
+                    Lambertian::new(Color::new(0.1, 0.1, 0.1)),
+                )
+                .unwrap(),
+            ),
+            Box::new(
+                Sphere::new(
+                    Point3::zero(),
+                    1.0,
+                    Lambertian::new(Color::new(0.5, 0.5, 0.5)),
+                )
+                .unwrap(),
+            ),
+        ]);
+        scene.environment = Box::new(ConstantEnvironment {
+            color: Color::zero(),
+        });
+        scene.material_names = vec![String::from("ground"), String::from("chalk")];
+        scene.lights.push(Box::new(PointLight::new(
+            Point3::new(0.0, 3.0, 0.0),
+            Color::new(8.0, 8.0, 8.0),
+        )));
+
+        // Only the light reaches the top of the sphere, as nothing is above it.
+        let r = Ray::new(Point3::new(0.0, 5.0, 0.0), Vector3::new(0.0, -2.0, 0.0));
+        let aovs = ray_aovs(&r, &scene, 10);
+        assert_eq!(Some(1), aovs.object);
+        assert_eq!(name_id("object1"), aovs.object_id);
+        assert_eq!(name_id("chalk"), aovs.material_id);
+        assert!((aovs.depth - 4.0).abs() < 1e-9);
+        assert_eq!(Vector3::new(0.0, 1.0, 0.0), aovs.normal);
+        assert_eq!(Color::new(0.5, 0.5, 0.5), aovs.albedo);
+        assert!((aovs.position - Point3::new(0.0, 1.0, 0.0)).length() < 1e-9);
+        assert_eq!(ray_color(&r, &scene, 10), aovs.direct_diffuse);
+        assert_eq!(Color::zero(), aovs.indirect_diffuse);
+        assert_eq!(aovs.direct_diffuse, aovs.beauty());
+        let features = aovs.features();
+        assert_eq!(aovs.albedo, features.albedo);
+        assert_eq!(aovs.normal, features.normal);
+        assert_eq!(aovs.depth, features.depth);
+
+        // The ground under the sphere is in its shadow, and lit only by light bouncing off it.
+        let r = Ray::new(Point3::new(0.0, -0.5, 5.0), Vector3::new(0.0, -0.5, -4.5));
+        let aovs = ray_aovs(&r, &scene, 10);
+        assert_eq!(Some(0), aovs.object);
+        assert_eq!(Color::zero(), aovs.direct_diffuse);
+        assert_eq!(Color::zero(), aovs.emission);
+
+        // Escaped rays see only the environment.
+        let r = Ray::new(Point3::new(0.0, 5.0, 0.0), Vector3::new(0.0, 1.0, 0.0));
+        let aovs = ray_aovs(&r, &scene, 10);
+        assert_eq!(None, aovs.object);
+        assert_eq!(0.0, aovs.object_id);
+        assert_eq!(f64::INFINITY, aovs.depth);
+    }
+
+    #[test]
+    fn escaped_features() {
+        // Whatever the color of the sky.
+        let scene = Scene::new(Vec::new());
+        let r = Ray::new(Point3::zero(), Vector3::new(0.0, 1.0, 0.0));
+        let features = ray_aovs(&r, &scene, 10).features();
+        assert_eq!(Color::new(1.0, 1.0, 1.0), features.albedo);
+        assert_eq!(Vector3::zero(), features.normal);
+        assert_eq!(f64::INFINITY, features.depth);
+    }
+
+    // Scatters along the normal with an attenuation that isn't a number, as a degenerate sample
+    // would.
+    struct Broken;
+
+    impl Material for Broken {
+        fn scatter(
+            &self,
+            _r_in: &Ray,
+            p: &Point3,
+            normal: &Vector3,
+            _front_face: bool,
+        ) -> Option<ScatterRecord> {
+            Option::Some(ScatterRecord {
+                attenuation: Color::new(f64::NAN, 0.0, 0.0),
+                scattered_ray: Ray::new(*p, *normal),
+            })
+        }
+    }
+
+    #[test]
+    fn nonfinite() {
+        // A mirror that reflects the camera ray into a broken sphere.
+        let scene = Scene::new(vec![
+            Box::new(
+                Sphere::new(
+                    Point3::new(0.0, -100.0, 0.0),
+                    100.0,
+                    Metal::new(Color::new(0.5, 0.5, 0.5), 0.0),
+                )
+                .unwrap(),
+            ),
+            Box::new(Sphere::new(Point3::new(2.0, 2.0, 0.0), 0.5, Broken).unwrap()),
+        ]);
+        let r = Ray::new(Point3::new(-1.0, 1.0, 0.0), Vector3::new(1.0, -1.0, 0.0));
+        let aovs = ray_aovs(&r, &scene, 10);
+        assert!(!aovs.beauty().is_finite());
+        assert_eq!(Some(1), aovs.nonfinite_bounce);
+
+        let r = Ray::new(Point3::new(2.0, 5.0, 0.0), Vector3::new(0.0, -1.0, 0.0));
+        assert_eq!(Some(0), ray_aovs(&r, &scene, 10).nonfinite_bounce);
+
+        // Looking away from both, nothing goes wrong.
+        let r = Ray::new(Point3::new(-1.0, 1.0, 0.0), Vector3::new(-1.0, 1.0, 0.0));
+        assert_eq!(None, ray_aovs(&r, &scene, 10).nonfinite_bounce);
+    }
+
+    #[test]
+    fn clamp_indirect() {
+        let scene = Scene::new(Vec::new());
+        let r = Ray::new(Point3::zero(), Vector3::new(0.0, 1.0, 0.0));
+        let mut aovs = ray_aovs(&r, &scene, 10);
+        aovs.indirect_diffuse = Color::new(8.0, 4.0, 0.0);
+        aovs.indirect_specular = Color::new(0.5, 0.5, 0.5);
+        let removed = aovs.clamp_indirect(2.0);
+        // The hue is kept, and light below the limit is untouched.
+        assert_eq!(Color::new(2.0, 1.0, 0.0), aovs.indirect_diffuse);
+        assert_eq!(Color::new(0.5, 0.5, 0.5), aovs.indirect_specular);
+        assert!((removed - (0.2126 * 6.0 + 0.7152 * 3.0)).abs() < 1e-9);
+    }
+}
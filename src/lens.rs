@@ -0,0 +1,610 @@
+use std::fs;
+use std::path::Path;
+
+use crate::camera::{basis, Camera};
+use crate::error::{check, Error, Result};
+use crate::ray::Ray;
+use crate::util::random_f64;
+use crate::vector3::{Vector3 as Point3, Vector3};
+
+// Lens tables are in millimeters and scenes in meters.
+const MILLIMETERS: f64 = 0.001;
+// Film is divided into this many rings, each with its own bounds on the exit pupil.
+const EXIT_PUPIL_RINGS: usize = 32;
+const EXIT_PUPIL_SAMPLES: usize = 16384;
+
+// One refracting surface of a lens, or the aperture stop.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LensElement {
+    // Radius of the spherical surface, positive if its center is behind it. Zero for the stop.
+    pub curvature_radius: f64,
+    // Distance along the axis to the next element, or to the film after the last one.
+    pub thickness: f64,
+    // Index of refraction of the medium behind the surface, with zero meaning air.
+    pub eta: f64,
+    pub aperture_radius: f64,
+}
+
+impl LensElement {
+    fn is_stop(&self) -> bool {
+        self.curvature_radius == 0.0
+    }
+}
+
+// Reads a lens prescription with one row per surface from the front of the lens to the back:
+// curvature radius, thickness, index of refraction and aperture diameter, all lengths in
+// millimeters. Lines starting with # are comments.
+pub fn read_lens_table<P: AsRef<Path>>(path: P) -> Result<Vec<LensElement>> {
+    parse_lens_table(&fs::read_to_string(path)?)
+}
+
+pub fn parse_lens_table(text: &str) -> Result<Vec<LensElement>> {
+    let mut elements = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let values = line
+            .split_whitespace()
+            .map(|token| token.parse::<f64>())
+            .collect::<std::result::Result<Vec<f64>, _>>()
+            .map_err(|e| Error::Parse(format!("line {}: {}", number + 1, e)))?;
+        if values.len() != 4 {
+            return Err(Error::Parse(format!(
+                "line {}: expected 4 values, found {}",
+                number + 1,
+                values.len()
+            )));
+        }
+        elements.push(LensElement {
+            curvature_radius: values[0] * MILLIMETERS,
+            thickness: values[1] * MILLIMETERS,
+            eta: values[2],
+            aperture_radius: values[3] * MILLIMETERS / 2.0,
+        });
+    }
+    if elements.is_empty() {
+        return Err(Error::Parse("empty lens table".to_string()));
+    }
+    Ok(elements)
+}
+
+#[derive(Copy, Clone, Debug)]
+struct PupilBounds {
+    min_x: f64,
+    min_y: f64,
+    max_x: f64,
+    max_y: f64,
+}
+
+impl PupilBounds {
+    fn empty() -> PupilBounds {
+        PupilBounds {
+            min_x: f64::INFINITY,
+            min_y: f64::INFINITY,
+            max_x: f64::NEG_INFINITY,
+            max_y: f64::NEG_INFINITY,
+        }
+    }
+
+    fn contains(&self, x: f64, y: f64) -> bool {
+        self.min_x <= x && x <= self.max_x && self.min_y <= y && y <= self.max_y
+    }
+
+    fn area(&self) -> f64 {
+        (self.max_x - self.min_x).max(0.0) * (self.max_y - self.min_y).max(0.0)
+    }
+}
+
+// Camera that traces rays from the film through a real lens prescription, following PBRT's
+// RealisticCamera. Distortion, vignetting and focus breathing come out of the optics. Lens space
+// has the film at z = 0 and the lens in front of it towards +z.
+pub struct RealisticCamera {
+    origin: Point3,
+    u: Vector3,
+    v: Vector3,
+    w: Vector3,
+    elements: Vec<LensElement>,
+    film_width: f64,
+    film_height: f64,
+    exit_pupil_bounds: Vec<PupilBounds>,
+    max_pupil_area: f64,
+}
+
+impl RealisticCamera {
+    // The stop is narrowed to `aperture_diameter` if that's smaller than in the prescription.
+    // `focus_distance` is measured from the film, and the film size is given by its diagonal
+    // and aspect ratio. All lengths are in millimeters except the focus distance, which is in
+    // scene units.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        look_from: Point3,
+        look_at: Point3,
+        v_up: Vector3,
+        elements: Vec<LensElement>,
+        aperture_diameter: f64,
+        focus_distance: f64,
+        film_diagonal: f64,
+        aspect_ratio: f64,
+    ) -> Result<RealisticCamera> {
+        check(!elements.is_empty(), "lens has no elements")?;
+        check(
+            aperture_diameter > 0.0,
+            "aperture_diameter must be positive",
+        )?;
+        check(film_diagonal > 0.0, "film_diagonal must be positive")?;
+        check(aspect_ratio > 0.0, "aspect_ratio must be positive")?;
+        let (u, v, w) = basis(look_from, look_at, v_up)?;
+        let film_diagonal = film_diagonal * MILLIMETERS;
+        let film_height = film_diagonal / (1.0 + aspect_ratio * aspect_ratio).sqrt();
+        let mut camera = RealisticCamera {
+            origin: look_from,
+            u,
+            v,
+            w,
+            elements,
+            film_width: aspect_ratio * film_height,
+            film_height,
+            exit_pupil_bounds: Vec::new(),
+            max_pupil_area: 0.0,
+        };
+        for element in camera.elements.iter_mut().filter(|e| e.is_stop()) {
+            element.aperture_radius = element
+                .aperture_radius
+                .min(aperture_diameter * MILLIMETERS / 2.0);
+        }
+
+        let film_distance = camera.focus_thick_lens(focus_distance)?;
+        camera.elements.last_mut().unwrap().thickness = film_distance;
+
+        let ring_width = film_diagonal / 2.0 / EXIT_PUPIL_RINGS as f64;
+        camera.exit_pupil_bounds = (0..EXIT_PUPIL_RINGS)
+            .map(|i| camera.bound_exit_pupil(i as f64 * ring_width, (i + 1) as f64 * ring_width))
+            .collect();
+        camera.max_pupil_area = camera
+            .exit_pupil_bounds
+            .iter()
+            .map(|b| b.area())
+            .fold(0.0, f64::max);
+        Ok(camera)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn read<P: AsRef<Path>>(
+        look_from: Point3,
+        look_at: Point3,
+        v_up: Vector3,
+        path: P,
+        aperture_diameter: f64,
+        focus_distance: f64,
+        film_diagonal: f64,
+        aspect_ratio: f64,
+    ) -> Result<RealisticCamera> {
+        RealisticCamera::new(
+            look_from,
+            look_at,
+            v_up,
+            read_lens_table(path)?,
+            aperture_diameter,
+            focus_distance,
+            film_diagonal,
+            aspect_ratio,
+        )
+    }
+
+    // Distance from the film to the rear element, which the camera sets to focus the lens.
+    pub fn film_distance(&self) -> f64 {
+        self.lens_rear_z()
+    }
+
+    // Effective focal length from the paraxial thick lens approximation.
+    pub fn focal_length(&self) -> Result<f64> {
+        let (pz, fz) = self.thick_lens_approximation()?;
+        Ok(pz[0] - fz[0])
+    }
+
+    fn lens_rear_z(&self) -> f64 {
+        self.elements.last().unwrap().thickness
+    }
+
+    fn lens_front_z(&self) -> f64 {
+        self.elements.iter().map(|e| e.thickness).sum()
+    }
+
+    fn rear_element_radius(&self) -> f64 {
+        self.elements.last().unwrap().aperture_radius
+    }
+
+    // Follows a ray leaving the film through every element, returning it where it leaves the
+    // front of the lens or None if it's blocked on the way.
+    fn trace_from_film(&self, r: &Ray) -> Option<Ray> {
+        let mut z = 0.0;
+        let mut r = Ray::new(r.origin, r.direction);
+        for (i, element) in self.elements.iter().enumerate().rev() {
+            z += element.thickness;
+            let (t, normal) = if element.is_stop() {
+                if r.direction.z <= 0.0 {
+                    return Option::None;
+                }
+                ((z - r.origin.z) / r.direction.z, Option::None)
+            } else {
+                let z_center = z - element.curvature_radius;
+                let (t, n) = intersect_spherical(element.curvature_radius, z_center, &r)?;
+                (t, Option::Some(n))
+            };
+            let p = r.at(t);
+            if p.x * p.x + p.y * p.y > element.aperture_radius * element.aperture_radius {
+                return Option::None;
+            }
+            r.origin = p;
+            if let Some(n) = normal {
+                let eta_i = ior(element.eta);
+                let eta_t = if i > 0 {
+                    ior(self.elements[i - 1].eta)
+                } else {
+                    1.0
+                };
+                r.direction = refract(&(-r.direction.unit()), &n, eta_i / eta_t)?;
+            }
+        }
+        Option::Some(r)
+    }
+
+    // The reverse of `trace_from_film`, for rays entering the front of the lens.
+    fn trace_from_scene(&self, r: &Ray) -> Option<Ray> {
+        let mut z = self.lens_front_z();
+        let mut r = Ray::new(r.origin, r.direction);
+        for (i, element) in self.elements.iter().enumerate() {
+            let (t, normal) = if element.is_stop() {
+                ((z - r.origin.z) / r.direction.z, Option::None)
+            } else {
+                let z_center = z - element.curvature_radius;
+                let (t, n) = intersect_spherical(element.curvature_radius, z_center, &r)?;
+                (t, Option::Some(n))
+            };
+            let p = r.at(t);
+            if p.x * p.x + p.y * p.y > element.aperture_radius * element.aperture_radius {
+                return Option::None;
+            }
+            r.origin = p;
+            if let Some(n) = normal {
+                let eta_i = if i > 0 {
+                    ior(self.elements[i - 1].eta)
+                } else {
+                    1.0
+                };
+                let eta_t = ior(element.eta);
+                r.direction = refract(&(-r.direction.unit()), &n, eta_i / eta_t)?;
+            }
+            z -= element.thickness;
+        }
+        Option::Some(r)
+    }
+
+    // Positions of the principal planes and focal points along the axis, on the scene side
+    // first and the film side second, found by tracing rays parallel to the axis near it. Fails
+    // if the lens blocks those rays, such as with a stop narrower than their distance from it.
+    fn thick_lens_approximation(&self) -> Result<([f64; 2], [f64; 2])> {
+        let blocked = || Error::InvalidParameter("paraxial ray blocked by the lens".to_string());
+        let x = 0.001
+            * (self.film_width * self.film_width + self.film_height * self.film_height).sqrt();
+        let from_scene = Ray::new(
+            Point3::new(x, 0.0, self.lens_front_z() + 1.0),
+            Vector3::new(0.0, 0.0, -1.0),
+        );
+        let to_film = self.trace_from_scene(&from_scene).ok_or_else(blocked)?;
+        let (pz0, fz0) = cardinal_points(&from_scene, &to_film);
+
+        let from_film = Ray::new(
+            Point3::new(x, 0.0, self.lens_rear_z() - 1.0),
+            Vector3::new(0.0, 0.0, 1.0),
+        );
+        let to_scene = self.trace_from_film(&from_film).ok_or_else(blocked)?;
+        let (pz1, fz1) = cardinal_points(&from_film, &to_scene);
+        Ok(([pz0, pz1], [fz0, fz1]))
+    }
+
+    // Film distance that brings a plane `focus_distance` in front of the film into focus, by
+    // moving the lens along the axis until the thick lens equation holds.
+    fn focus_thick_lens(&self, focus_distance: f64) -> Result<f64> {
+        let (pz, fz) = self.thick_lens_approximation()?;
+        let f = pz[0] - fz[0];
+        let z = focus_distance;
+        let c = (z + pz[0] - pz[1]) * (z + pz[0] - pz[1] - 4.0 * f);
+        check(
+            c > 0.0,
+            &format!("focus distance {} is too close", focus_distance),
+        )?;
+        let delta = 0.5 * (z - pz[0] - pz[1] - c.sqrt());
+        Ok(self.lens_rear_z() + delta)
+    }
+
+    // Bounds in the plane of the rear element of the directions through which light reaches
+    // points at distances between `r0` and `r1` from the center of the film, along +x.
+    fn bound_exit_pupil(&self, r0: f64, r1: f64) -> PupilBounds {
+        let extent = 1.5 * self.rear_element_radius();
+        let mut bounds = PupilBounds::empty();
+        for i in 0..EXIT_PUPIL_SAMPLES {
+            let x = r0 + (r1 - r0) * (i as f64 + 0.5) / EXIT_PUPIL_SAMPLES as f64;
+            let rear_x = -extent + 2.0 * extent * radical_inverse(2, i);
+            let rear_y = -extent + 2.0 * extent * radical_inverse(3, i);
+            if bounds.contains(rear_x, rear_y) {
+                continue;
+            }
+            let p_film = Point3::new(x, 0.0, 0.0);
+            let p_rear = Point3::new(rear_x, rear_y, self.lens_rear_z());
+            if self
+                .trace_from_film(&Ray::new(p_film, p_rear - p_film))
+                .is_some()
+            {
+                bounds.min_x = bounds.min_x.min(rear_x);
+                bounds.min_y = bounds.min_y.min(rear_y);
+                bounds.max_x = bounds.max_x.max(rear_x);
+                bounds.max_y = bounds.max_y.max(rear_y);
+            }
+        }
+        if bounds.area() == 0.0 {
+            return PupilBounds {
+                min_x: -extent,
+                min_y: -extent,
+                max_x: extent,
+                max_y: extent,
+            };
+        }
+        // Leave room for what fell between the samples.
+        let margin = 2.0 * (2.0 * extent) * 2.0_f64.sqrt() / (EXIT_PUPIL_SAMPLES as f64).sqrt();
+        PupilBounds {
+            min_x: bounds.min_x - margin,
+            min_y: bounds.min_y - margin,
+            max_x: bounds.max_x + margin,
+            max_y: bounds.max_y + margin,
+        }
+    }
+
+    // Point on the rear element plane within the exit pupil of the film point, together with the
+    // area of the bounds it was drawn from.
+    fn sample_exit_pupil(&self, film_x: f64, film_y: f64) -> (Point3, f64) {
+        let r = (film_x * film_x + film_y * film_y).sqrt();
+        let diagonal =
+            (self.film_width * self.film_width + self.film_height * self.film_height).sqrt();
+        let ring =
+            ((r / (diagonal / 2.0) * EXIT_PUPIL_RINGS as f64) as usize).min(EXIT_PUPIL_RINGS - 1);
+        let bounds = &self.exit_pupil_bounds[ring];
+        let x = bounds.min_x + (bounds.max_x - bounds.min_x) * random_f64();
+        let y = bounds.min_y + (bounds.max_y - bounds.min_y) * random_f64();
+        // The bounds were found along +x, so rotate them to the film point.
+        let (sin, cos) = if r > 0.0 {
+            (film_y / r, film_x / r)
+        } else {
+            (0.0, 1.0)
+        };
+        (
+            Point3::new(cos * x - sin * y, sin * x + cos * y, self.lens_rear_z()),
+            bounds.area(),
+        )
+    }
+}
+
+impl Camera for RealisticCamera {
+    fn get_ray(&self, s: f64, t: f64) -> Option<Ray> {
+        // Drop rays in proportion to their weight, so that the image darkens the same.
+        let (r, weight) = self.get_weighted_ray(s, t)?;
+        if random_f64() >= weight {
+            return Option::None;
+        }
+        Option::Some(r)
+    }
+
+    // None where the sampled ray is blocked inside the lens, which is how the lens vignettes.
+    fn get_weighted_ray(&self, s: f64, t: f64) -> Option<(Ray, f64)> {
+        // The lens forms an inverted image, so the film is flipped both ways.
+        let p_film = Point3::new(
+            (0.5 - s) * self.film_width,
+            (0.5 - t) * self.film_height,
+            0.0,
+        );
+        let (p_rear, area) = self.sample_exit_pupil(p_film.x, p_film.y);
+        let r_film = Ray::new(p_film, p_rear - p_film);
+        let r = self.trace_from_film(&r_film)?;
+
+        // The film receives less light at an angle and where the pupil is smaller.
+        let cos_theta = r_film.direction.unit().z;
+        let weight = cos_theta.powi(4) * area / self.max_pupil_area;
+
+        let to_world = |d: &Vector3| d.x * self.u + d.y * self.v - d.z * self.w;
+        Option::Some((
+            Ray::new(
+                self.origin + to_world(&r.origin),
+                to_world(&r.direction).unit(),
+            ),
+            weight,
+        ))
+    }
+}
+
+fn ior(eta: f64) -> f64 {
+    if eta == 0.0 {
+        1.0
+    } else {
+        eta
+    }
+}
+
+// Intersects a spherical surface centered on the axis, picking whichever of the two hits lies
+// on the side of the sphere that forms the lens surface. The normal faces the incoming ray.
+fn intersect_spherical(radius: f64, z_center: f64, r: &Ray) -> Option<(f64, Vector3)> {
+    let o = r.origin - Vector3::new(0.0, 0.0, z_center);
+    let a = r.direction.length_squared();
+    let b = 2.0 * r.direction.dot(&o);
+    let c = o.length_squared() - radius * radius;
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return Option::None;
+    }
+    let root = discriminant.sqrt();
+    let (t0, t1) = ((-b - root) / (2.0 * a), (-b + root) / (2.0 * a));
+    let use_closer = (r.direction.z < 0.0) ^ (radius < 0.0);
+    let t = if use_closer { t0.min(t1) } else { t0.max(t1) };
+    if t < 0.0 {
+        return Option::None;
+    }
+    let mut n = (o + t * r.direction).unit();
+    if n.dot(&r.direction) > 0.0 {
+        n = -n;
+    }
+    Option::Some((t, n))
+}
+
+// Refracts `wi`, which points away from the surface on the side of `n`, with the ratio of the
+// indices of refraction on either side. None on total internal reflection.
+fn refract(wi: &Vector3, n: &Vector3, eta: f64) -> Option<Vector3> {
+    let cos_theta_i = n.dot(wi);
+    let sin2_theta_i = (1.0 - cos_theta_i * cos_theta_i).max(0.0);
+    let sin2_theta_t = eta * eta * sin2_theta_i;
+    if sin2_theta_t >= 1.0 {
+        return Option::None;
+    }
+    let cos_theta_t = (1.0 - sin2_theta_t).sqrt();
+    Option::Some(eta * -wi + (eta * cos_theta_i - cos_theta_t) * n)
+}
+
+// Where a ray leaving the lens parallel to the axis at the height of `r_in` crosses the axis
+// (the focal point), and where it reaches the height of `r_in` (the principal plane).
+fn cardinal_points(r_in: &Ray, r_out: &Ray) -> (f64, f64) {
+    let tf = -r_out.origin.x / r_out.direction.x;
+    let tp = (r_in.origin.x - r_out.origin.x) / r_out.direction.x;
+    (r_out.at(tp).z, r_out.at(tf).z)
+}
+
+fn radical_inverse(base: usize, mut i: usize) -> f64 {
+    let inverse_base = 1.0 / base as f64;
+    let mut factor = inverse_base;
+    let mut result = 0.0;
+    while i > 0 {
+        result += (i % base) as f64 * factor;
+        i /= base;
+        factor *= inverse_base;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::camera::Camera;
+    use crate::lens::{parse_lens_table, read_lens_table, RealisticCamera};
+    use crate::ray::Ray;
+    use crate::vector3::{Vector3 as Point3, Vector3};
+
+    fn camera(focus_distance: f64) -> RealisticCamera {
+        RealisticCamera::new(
+            Point3::zero(),
+            Point3::new(0.0, 0.0, -1.0),
+            Vector3::new(0.0, 1.0, 0.0),
+            read_lens_table(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/assets/lenses/dgauss.50mm.dat"
+            ))
+            .unwrap(),
+            12.5,
+            focus_distance,
+            43.27,
+            1.5,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn table() {
+        let elements =
+            parse_lens_table("# comment\n29.475 3.76 1.67 25.2\n0 4.5 0 17.1\n").unwrap();
+        assert_eq!(2, elements.len());
+        assert!((elements[0].curvature_radius - 0.029475).abs() < 1e-12);
+        assert!((elements[1].aperture_radius - 0.00855).abs() < 1e-12);
+        assert!(parse_lens_table("1 2 3\n").is_err());
+        assert!(parse_lens_table("1 2 x 4\n").is_err());
+    }
+
+    #[test]
+    fn focal_length() {
+        let focal_length = camera(10.0).focal_length().unwrap();
+        assert!((focal_length - 0.05).abs() < 0.002, "{}", focal_length);
+
+        // A stop narrower than the paraxial rays are from the axis blocks them.
+        let pinhole = RealisticCamera::new(
+            Point3::zero(),
+            Point3::new(0.0, 0.0, -1.0),
+            Vector3::new(0.0, 1.0, 0.0),
+            read_lens_table(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/assets/lenses/dgauss.50mm.dat"
+            ))
+            .unwrap(),
+            0.01,
+            10.0,
+            43.27,
+            1.5,
+        );
+        assert!(pinhole.err().unwrap().to_string().contains("blocked"));
+    }
+
+    #[test]
+    fn focus() {
+        // Rays from the center of the film meet again on the axis at the focus distance, whatever
+        // part of the lens they pass through.
+        for focus_distance in [1.0, 5.0] {
+            let camera = camera(focus_distance);
+            let mut count = 0;
+            for i in 0..100 {
+                let p_rear = Point3::new(0.0005 * (i % 10) as f64, 0.0005 * (i / 10) as f64, 0.0);
+                let p_rear = p_rear + Vector3::new(0.0, 0.0, camera.lens_rear_z());
+                let r = match camera.trace_from_film(&Ray::new(Point3::zero(), p_rear)) {
+                    Some(r) => r,
+                    None => continue,
+                };
+                let p = r.at((focus_distance - r.origin.z) / r.direction.z);
+                assert!(
+                    (p.x * p.x + p.y * p.y).sqrt() < 0.002 * focus_distance,
+                    "{:?}",
+                    p
+                );
+                count += 1;
+            }
+            assert!(count > 50);
+        }
+        // Focusing closer moves the lens away from the film.
+        assert!(camera(1.0).film_distance() > camera(5.0).film_distance());
+    }
+
+    #[test]
+    fn vignetting() {
+        let camera = camera(5.0);
+        let mut center = 0;
+        let mut corner = 0;
+        for _ in 0..2000 {
+            if let Some(r) = camera.get_ray(0.5, 0.5) {
+                assert!(r.direction.dot(&Vector3::new(0.0, 0.0, -1.0)) > 0.999);
+                center += 1;
+            }
+            if let Some(r) = camera.get_ray(1.0, 1.0) {
+                // The upper right of the image looks up and to the right.
+                assert!(r.direction.x > 0.0 && r.direction.y > 0.0);
+                corner += 1;
+            }
+        }
+        assert!(corner > 0 && corner < center, "{} {}", corner, center);
+
+        // Weighted rays darken the corner without dropping them.
+        let (mut center, mut corner) = (0.0, 0.0);
+        for _ in 0..2000 {
+            center += camera.get_weighted_ray(0.5, 0.5).map_or(0.0, |(_, w)| w);
+            corner += camera.get_weighted_ray(1.0, 1.0).map_or(0.0, |(_, w)| w);
+        }
+        assert!(corner > 0.0 && corner < center, "{} {}", corner, center);
+        // Some rays through the middle are blocked too.
+        let (_, weight) = (0..100)
+            .find_map(|_| camera.get_weighted_ray(0.5, 0.5))
+            .unwrap();
+        assert!(weight > 0.5 && weight <= 1.0, "{}", weight);
+    }
+}
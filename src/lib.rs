@@ -1,8 +1,33 @@
+pub mod aperture;
 pub mod camera;
+pub mod checkpoint;
 pub mod color;
+pub mod cryptomatte;
+pub mod csg;
+pub mod denoise;
+pub mod environment;
+pub mod error;
+pub mod exr;
+pub mod film;
+pub mod filter;
+pub mod heightfield;
 pub mod hittable;
+pub mod ies;
+pub mod image;
+pub mod integrator;
+pub mod lens;
+pub mod light;
+pub mod light_sampler;
 pub mod material;
 pub mod ray;
+pub mod renderer;
+pub mod sampling;
+pub mod scene;
+pub mod scenes;
+pub mod sdf;
+pub mod sky;
 pub mod sphere;
+pub mod stats;
+pub mod stereo;
 pub mod util;
 pub mod vector3;
@@ -0,0 +1,406 @@
+use std::f64::consts::PI;
+
+use crate::color::luminance;
+use crate::error::{check, Result};
+use crate::ies::PhotometricWeb;
+use crate::light_sampler::{DirectionCone, LightBounds};
+use crate::util::degrees_to_radians;
+use crate::vector3::{Vector3 as Color, Vector3 as Point3, Vector3};
+
+// Radiometric units are arbitrary, but the photometric constructors below treat one unit of
+// radiance as one nit (cd/m^2), so that lights specified in lumens, candela or lux agree with
+// each other.
+
+pub struct LightSample {
+    // Unit direction from the shaded point towards the light.
+    pub direction: Vector3,
+    // Light arriving at the shaded point along `direction`.
+    pub radiance: Color,
+    // Density of the direction with respect to solid angle. Delta lights report one.
+    pub pdf: f64,
+    // Distance to the light, which shadow rays must not reach past.
+    pub distance: f64,
+}
+
+pub trait Light {
+    // Samples light arriving at `p`, or None if the light can't illuminate it.
+    fn sample_li(&self, p: &Point3) -> Option<LightSample>;
+
+    // Density with which `sample_li` picks `direction` from `p`. Zero for delta lights, which
+    // no scattered ray can ever hit.
+    fn pdf_li(&self, p: &Point3, direction: &Vector3) -> f64;
+
+    // Whether the light is a delta distribution in position or direction.
+    fn is_delta(&self) -> bool {
+        true
+    }
+
+    // Total emitted power as luminance, used to pick between lights. Lights that don't know
+    // theirs are all given the same.
+    fn power(&self) -> f64 {
+        1.0
+    }
+
+    // Where and in which directions the light emits, or None for lights at infinity. Lights
+    // without bounds are picked with no regard to where the shading point is.
+    fn bounds(&self) -> Option<LightBounds> {
+        Option::None
+    }
+}
+
+// Emits equally in all directions from a single point, unless shaped by a photometric profile.
+pub struct PointLight {
+    pub position: Point3,
+    // Radiant intensity, power per unit solid angle. With a profile, it is the intensity per
+    // candela of the profile.
+    pub intensity: Color,
+    pub profile: Option<PhotometricWeb>,
+}
+
+impl PointLight {
+    pub fn new(position: Point3, intensity: Color) -> PointLight {
+        PointLight {
+            position,
+            intensity,
+            profile: Option::None,
+        }
+    }
+    pub fn from_candela(position: Point3, color: Color, candela: f64) -> PointLight {
+        PointLight::new(position, candela * normalize_luminance(&color))
+    }
+    pub fn from_lumens(position: Point3, color: Color, lumens: f64) -> PointLight {
+        PointLight::from_candela(position, color, lumens / (4.0 * PI))
+    }
+    // Emits the candela distribution measured for a real fixture.
+    pub fn from_ies(position: Point3, color: Color, profile: PhotometricWeb) -> PointLight {
+        let mut light = PointLight::from_candela(position, color, 1.0);
+        light.profile = Option::Some(profile);
+        light
+    }
+}
+
+impl Light for PointLight {
+    fn sample_li(&self, p: &Point3) -> Option<LightSample> {
+        let scale = self
+            .profile
+            .as_ref()
+            .map_or(1.0, |profile| profile.candela(&(p - self.position)));
+        if scale <= 0.0 {
+            return Option::None;
+        }
+        sample_point(&self.position, p, scale * self.intensity)
+    }
+
+    fn pdf_li(&self, _: &Point3, _: &Vector3) -> f64 {
+        0.0
+    }
+
+    // With a profile this is an upper bound, as if the peak candela was emitted everywhere.
+    fn power(&self) -> f64 {
+        let scale = self
+            .profile
+            .as_ref()
+            .map_or(1.0, |profile| profile.max_candela());
+        4.0 * PI * scale * luminance(&self.intensity)
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        Option::Some(LightBounds {
+            p_min: self.position,
+            p_max: self.position,
+            phi: self.power(),
+            normals: DirectionCone::entire_sphere(),
+            cos_theta_e: 0.0,
+            two_sided: false,
+        })
+    }
+}
+
+// Point light restricted to a cone, fading out smoothly between the falloff start angle and the
+// total width of the cone. A photometric profile further shapes the light within the cone.
+pub struct SpotLight {
+    pub position: Point3,
+    // Unit axis of the cone.
+    pub direction: Vector3,
+    // Intensity along the axis, or the intensity per candela of the profile if there is one.
+    pub intensity: Color,
+    pub profile: Option<PhotometricWeb>,
+    cos_falloff_start: f64,
+    cos_total_width: f64,
+}
+
+impl SpotLight {
+    // The angles are measured from the axis in degrees.
+    pub fn new(
+        position: Point3,
+        look_at: Point3,
+        intensity: Color,
+        falloff_start: f64,
+        total_width: f64,
+    ) -> Result<SpotLight> {
+        let direction = look_at - position;
+        check(
+            direction.is_finite() && direction.length_squared() > 0.0,
+            "a spot light must look at a point other than its position",
+        )?;
+        check(
+            0.0 <= falloff_start && falloff_start <= total_width && total_width <= 180.0,
+            "the falloff start of a spot light must lie between 0 and its total width, which \
+             is at most 180 degrees",
+        )?;
+        Ok(SpotLight {
+            position,
+            direction: direction.unit(),
+            intensity,
+            profile: Option::None,
+            cos_falloff_start: degrees_to_radians(falloff_start).cos(),
+            cos_total_width: degrees_to_radians(total_width).cos(),
+        })
+    }
+
+    // Candela is the intensity along the axis.
+    pub fn from_candela(
+        position: Point3,
+        look_at: Point3,
+        color: Color,
+        candela: f64,
+        falloff_start: f64,
+        total_width: f64,
+    ) -> Result<SpotLight> {
+        let intensity = candela * normalize_luminance(&color);
+        SpotLight::new(position, look_at, intensity, falloff_start, total_width)
+    }
+
+    pub fn from_lumens(
+        position: Point3,
+        look_at: Point3,
+        color: Color,
+        lumens: f64,
+        falloff_start: f64,
+        total_width: f64,
+    ) -> Result<SpotLight> {
+        let mut light =
+            SpotLight::from_candela(position, look_at, color, 1.0, falloff_start, total_width)?;
+        // Solid angle of the cone, counting the falloff region at half weight.
+        let solid_angle =
+            2.0 * PI * (1.0 - 0.5 * (light.cos_falloff_start + light.cos_total_width));
+        light.intensity = lumens / solid_angle * light.intensity;
+        Ok(light)
+    }
+
+    // Fraction of the axial intensity emitted towards `w`, which points away from the light.
+    pub fn falloff(&self, w: &Vector3) -> f64 {
+        let cos_theta = self.direction.dot(w);
+        smooth_step(cos_theta, self.cos_total_width, self.cos_falloff_start)
+    }
+}
+
+impl Light for SpotLight {
+    fn sample_li(&self, p: &Point3) -> Option<LightSample> {
+        let w = (p - self.position).unit();
+        let mut scale = self.falloff(&w);
+        if let Some(profile) = &self.profile {
+            scale *= profile.candela(&w);
+        }
+        if scale <= 0.0 {
+            return Option::None;
+        }
+        sample_point(&self.position, p, scale * self.intensity)
+    }
+
+    fn pdf_li(&self, _: &Point3, _: &Vector3) -> f64 {
+        0.0
+    }
+
+    fn power(&self) -> f64 {
+        let scale = self
+            .profile
+            .as_ref()
+            .map_or(1.0, |profile| profile.max_candela());
+        let solid_angle = 2.0 * PI * (1.0 - 0.5 * (self.cos_falloff_start + self.cos_total_width));
+        solid_angle * scale * luminance(&self.intensity)
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        let scale = self
+            .profile
+            .as_ref()
+            .map_or(1.0, |profile| profile.max_candela());
+        // The emission cone covers the full-intensity part of the spot, and the falloff
+        // region is the extra angle past it.
+        let theta_e = self.cos_total_width.acos() - self.cos_falloff_start.acos();
+        Option::Some(LightBounds {
+            p_min: self.position,
+            p_max: self.position,
+            // Like the importance function, as if it emitted the axial intensity everywhere.
+            phi: 4.0 * PI * scale * luminance(&self.intensity),
+            normals: DirectionCone {
+                w: self.direction,
+                cos_theta: self.cos_falloff_start,
+            },
+            cos_theta_e: theta_e.cos(),
+            two_sided: false,
+        })
+    }
+}
+
+// Parallel light from an infinitely distant source, such as the sun.
+pub struct DirectionalLight {
+    // Unit direction the light travels in.
+    pub direction: Vector3,
+    // Irradiance on a surface facing the light.
+    pub irradiance: Color,
+}
+
+impl DirectionalLight {
+    pub fn new(direction: Vector3, irradiance: Color) -> Result<DirectionalLight> {
+        check(
+            direction.is_finite() && direction.length_squared() > 0.0,
+            "a directional light needs a nonzero direction",
+        )?;
+        Ok(DirectionalLight {
+            direction: direction.unit(),
+            irradiance,
+        })
+    }
+
+    pub fn from_lux(direction: Vector3, color: Color, lux: f64) -> Result<DirectionalLight> {
+        DirectionalLight::new(direction, lux * normalize_luminance(&color))
+    }
+}
+
+impl Light for DirectionalLight {
+    fn sample_li(&self, _: &Point3) -> Option<LightSample> {
+        Option::Some(LightSample {
+            direction: -self.direction,
+            radiance: self.irradiance,
+            pdf: 1.0,
+            distance: f64::INFINITY,
+        })
+    }
+
+    fn pdf_li(&self, _: &Point3, _: &Vector3) -> f64 {
+        0.0
+    }
+
+    // The extent of the scene isn't known here, so this is the power per unit area facing the
+    // light.
+    fn power(&self) -> f64 {
+        luminance(&self.irradiance)
+    }
+
+    fn bounds(&self) -> Option<LightBounds> {
+        Option::None
+    }
+}
+
+fn sample_point(position: &Point3, p: &Point3, intensity: Color) -> Option<LightSample> {
+    let to_light = position - p;
+    let distance_squared = to_light.length_squared();
+    if distance_squared == 0.0 {
+        return Option::None;
+    }
+    let distance = distance_squared.sqrt();
+    Option::Some(LightSample {
+        direction: to_light / distance,
+        radiance: intensity / distance_squared,
+        pdf: 1.0,
+        distance,
+    })
+}
+
+// Scales a color to unit luminance, so that photometric quantities set its brightness.
+fn normalize_luminance(color: &Color) -> Color {
+    let y = luminance(color);
+    if y > 0.0 {
+        color / y
+    } else {
+        Color::zero()
+    }
+}
+
+fn smooth_step(x: f64, a: f64, b: f64) -> f64 {
+    if a == b {
+        return if x < a { 0.0 } else { 1.0 };
+    }
+    let t = ((x - a) / (b - a)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::ies::{IesProfile, PhotometricWeb};
+    use crate::light::{DirectionalLight, Light, PointLight, SpotLight};
+    use crate::vector3::{Vector3 as Color, Vector3 as Point3, Vector3};
+
+    #[test]
+    fn point_light() {
+        let light = PointLight::new(Point3::new(0.0, 2.0, 0.0), Color::new(4.0, 4.0, 4.0));
+        let sample = light.sample_li(&Point3::zero()).unwrap();
+        assert_eq!(Vector3::new(0.0, 1.0, 0.0), sample.direction);
+        assert_eq!(Color::new(1.0, 1.0, 1.0), sample.radiance);
+        assert_eq!(2.0, sample.distance);
+        assert!(light.is_delta());
+    }
+
+    #[test]
+    fn photometric() {
+        let light = PointLight::from_candela(Point3::zero(), Color::new(1.0, 1.0, 1.0), 100.0);
+        assert!((light.intensity.y - 100.0).abs() < 1e-9);
+        let light = PointLight::from_lumens(Point3::zero(), Color::new(2.0, 2.0, 2.0), 400.0);
+        assert!((light.intensity.x - 100.0 / std::f64::consts::PI).abs() < 1e-9);
+    }
+
+    #[test]
+    fn spot_falloff() {
+        let light = SpotLight::new(
+            Point3::new(0.0, 1.0, 0.0),
+            Point3::zero(),
+            Color::new(1.0, 1.0, 1.0),
+            10.0,
+            30.0,
+        )
+        .unwrap();
+        assert_eq!(1.0, light.falloff(&Vector3::new(0.0, -1.0, 0.0)));
+        assert_eq!(0.0, light.falloff(&Vector3::new(1.0, -1.0, 0.0).unit()));
+        let inside = light.falloff(&Vector3::new(0.35, -1.0, 0.0).unit());
+        assert!(0.0 < inside && inside < 1.0);
+        assert!(light.sample_li(&Point3::new(5.0, 0.0, 0.0)).is_none());
+
+        let (at, white) = (Point3::zero(), Color::new(1.0, 1.0, 1.0));
+        assert!(SpotLight::new(at, at, white, 10.0, 30.0).is_err());
+        let below = Point3::new(0.0, -1.0, 0.0);
+        assert!(SpotLight::new(at, below, white, 40.0, 30.0).is_err());
+        assert!(SpotLight::new(at, below, white, -10.0, 30.0).is_err());
+        assert!(SpotLight::new(at, below, white, 10.0, 190.0).is_err());
+    }
+
+    #[test]
+    fn profile() {
+        let text = "TILT=NONE\n1 1000 1 2 1 1 2 0 0 0\n1 1 10\n0 90\n0\n100 0\n";
+        let web = PhotometricWeb::new(
+            Arc::new(IesProfile::parse(text).unwrap()),
+            Vector3::new(0.0, -1.0, 0.0),
+            Vector3::new(1.0, 0.0, 0.0),
+        );
+        let light =
+            PointLight::from_ies(Point3::new(0.0, 1.0, 0.0), Color::new(1.0, 1.0, 1.0), web);
+        let below = light.sample_li(&Point3::zero()).unwrap();
+        assert!((below.radiance.y - 100.0).abs() < 1e-9);
+        let side = light.sample_li(&Point3::new(1.0, 0.0, 0.0)).unwrap();
+        assert!((side.radiance.y - 25.0).abs() < 1e-9);
+        assert!(light.sample_li(&Point3::new(0.0, 2.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn directional_light() {
+        let white = Color::new(1.0, 1.0, 1.0);
+        let light = DirectionalLight::new(Vector3::new(0.0, -2.0, 0.0), white).unwrap();
+        let sample = light.sample_li(&Point3::zero()).unwrap();
+        assert_eq!(Vector3::new(0.0, 1.0, 0.0), sample.direction);
+        assert_eq!(f64::INFINITY, sample.distance);
+        assert!(DirectionalLight::new(Vector3::zero(), white).is_err());
+    }
+}
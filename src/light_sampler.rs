@@ -0,0 +1,598 @@
+use std::f64::consts::PI;
+
+use crate::light::Light;
+use crate::sampling::AliasTable;
+use crate::stats::count;
+use crate::vector3::{Vector3 as Point3, Vector3};
+
+// Chooses which light next event estimation should sample at a shading point.
+pub trait LightSampler {
+    // Picks a light to shade a point with normal `n`, returning its index in the scene light
+    // list together with the probability of having picked it. None if no light can contribute.
+    fn sample(&self, p: &Point3, n: &Vector3, u: f64) -> Option<(usize, f64)>;
+
+    // Probability that `sample` picks the given light at this shading point.
+    fn pmf(&self, p: &Point3, n: &Vector3, light: usize) -> f64;
+}
+
+pub struct UniformLightSampler {
+    count: usize,
+}
+
+impl UniformLightSampler {
+    pub fn new(lights: &[Box<dyn Light>]) -> UniformLightSampler {
+        UniformLightSampler {
+            count: lights.len(),
+        }
+    }
+}
+
+impl LightSampler for UniformLightSampler {
+    fn sample(&self, _: &Point3, _: &Vector3, u: f64) -> Option<(usize, f64)> {
+        if self.count == 0 {
+            return Option::None;
+        }
+        let index = ((u * self.count as f64) as usize).min(self.count - 1);
+        Option::Some((index, 1.0 / self.count as f64))
+    }
+
+    fn pmf(&self, _: &Point3, _: &Vector3, _: usize) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        1.0 / self.count as f64
+    }
+}
+
+// Picks lights in proportion to their emitted power, regardless of where the shading point is.
+pub struct PowerLightSampler {
+    table: Option<AliasTable>,
+}
+
+impl PowerLightSampler {
+    pub fn new(lights: &[Box<dyn Light>]) -> PowerLightSampler {
+        if lights.is_empty() {
+            return PowerLightSampler {
+                table: Option::None,
+            };
+        }
+        let powers: Vec<f64> = lights.iter().map(|light| light.power()).collect();
+        PowerLightSampler {
+            table: Option::Some(AliasTable::new(&powers)),
+        }
+    }
+}
+
+impl LightSampler for PowerLightSampler {
+    fn sample(&self, _: &Point3, _: &Vector3, u: f64) -> Option<(usize, f64)> {
+        self.table.as_ref().map(|table| table.sample(u))
+    }
+
+    fn pmf(&self, _: &Point3, _: &Vector3, light: usize) -> f64 {
+        self.table.as_ref().map_or(0.0, |table| table.pmf(light))
+    }
+}
+
+// Bounding cone of a set of directions around a central axis.
+#[derive(Copy, Clone, Debug)]
+pub struct DirectionCone {
+    pub w: Vector3,
+    pub cos_theta: f64,
+}
+
+impl DirectionCone {
+    pub fn entire_sphere() -> DirectionCone {
+        DirectionCone {
+            w: Vector3::new(0.0, 0.0, 1.0),
+            cos_theta: -1.0,
+        }
+    }
+
+    pub fn union(a: &DirectionCone, b: &DirectionCone) -> DirectionCone {
+        let theta_a = safe_acos(a.cos_theta);
+        let theta_b = safe_acos(b.cos_theta);
+        let theta_d = angle_between(&a.w, &b.w);
+        // One cone may already contain the other.
+        if (theta_d + theta_b).min(PI) <= theta_a {
+            return *a;
+        }
+        if (theta_d + theta_a).min(PI) <= theta_b {
+            return *b;
+        }
+
+        let theta_o = (theta_a + theta_d + theta_b) / 2.0;
+        if theta_o >= PI {
+            return DirectionCone::entire_sphere();
+        }
+        // Rotate a's axis towards b's so that the new cone just covers both.
+        let axis = a.w.cross(&b.w);
+        if axis.length_squared() == 0.0 {
+            return DirectionCone::entire_sphere();
+        }
+        DirectionCone {
+            w: rotate(&a.w, &axis.unit(), theta_o - theta_a),
+            cos_theta: theta_o.cos(),
+        }
+    }
+}
+
+// Spatial and directional extent of the emission of a light or group of lights, following
+// PBRT-v4's light BVH.
+#[derive(Copy, Clone, Debug)]
+pub struct LightBounds {
+    pub p_min: Point3,
+    pub p_max: Point3,
+    // Total emitted power.
+    pub phi: f64,
+    // Cone of the surface normals (or emission axes) of the lights...
+    pub normals: DirectionCone,
+    // ...and the angle past those normals over which they emit.
+    pub cos_theta_e: f64,
+    pub two_sided: bool,
+}
+
+impl LightBounds {
+    pub fn centroid(&self) -> Point3 {
+        0.5 * (self.p_min + self.p_max)
+    }
+
+    pub fn union(a: &LightBounds, b: &LightBounds) -> LightBounds {
+        if a.phi == 0.0 {
+            return *b;
+        }
+        if b.phi == 0.0 {
+            return *a;
+        }
+        LightBounds {
+            p_min: component_min(&a.p_min, &b.p_min),
+            p_max: component_max(&a.p_max, &b.p_max),
+            phi: a.phi + b.phi,
+            normals: DirectionCone::union(&a.normals, &b.normals),
+            cos_theta_e: a.cos_theta_e.min(b.cos_theta_e),
+            two_sided: a.two_sided || b.two_sided,
+        }
+    }
+
+    // Conservative estimate of the light arriving at a point with normal `n` from everything
+    // inside the bounds. A zero normal skips the cosine at the receiver.
+    pub fn importance(&self, p: &Point3, n: &Vector3) -> f64 {
+        let pc = self.centroid();
+        let diagonal = (self.p_max - self.p_min).length();
+        let d2 = (p - pc).length_squared().max(diagonal / 2.0);
+
+        // Angle between the emission axis and the direction to the point.
+        let offset = p - pc;
+        let wi = if offset.length_squared() > 0.0 {
+            offset.unit()
+        } else {
+            Vector3::zero()
+        };
+        let mut cos_theta_w = self.normals.w.dot(&wi);
+        if self.two_sided {
+            cos_theta_w = cos_theta_w.abs();
+        }
+        let sin_theta_w = safe_sqrt(1.0 - cos_theta_w * cos_theta_w);
+
+        // Angle subtended by the bounds as seen from the point.
+        let radius_squared = (diagonal / 2.0).powi(2);
+        let distance_squared = offset.length_squared();
+        let cos_theta_b = if distance_squared < radius_squared {
+            -1.0
+        } else {
+            safe_sqrt(1.0 - radius_squared / distance_squared)
+        };
+        let sin_theta_b = safe_sqrt(1.0 - cos_theta_b * cos_theta_b);
+
+        // Smallest possible angle between an emission direction and the point.
+        let cos_theta_o = self.normals.cos_theta;
+        let sin_theta_o = safe_sqrt(1.0 - cos_theta_o * cos_theta_o);
+        let cos_theta_x = cos_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, cos_theta_o);
+        let sin_theta_x = sin_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, cos_theta_o);
+        let cos_theta_p = cos_sub_clamped(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+        if cos_theta_p <= self.cos_theta_e {
+            return 0.0;
+        }
+
+        let mut importance = self.phi * cos_theta_p / d2;
+        if n.length_squared() > 0.0 {
+            // Smallest possible angle of incidence at the receiver.
+            let cos_theta_i = wi.dot(n).abs();
+            let sin_theta_i = safe_sqrt(1.0 - cos_theta_i * cos_theta_i);
+            importance *= cos_sub_clamped(sin_theta_i, cos_theta_i, sin_theta_b, cos_theta_b);
+        }
+        importance.max(0.0)
+    }
+}
+
+enum Node {
+    Interior {
+        bounds: LightBounds,
+        // The first child directly follows its parent.
+        second_child: usize,
+    },
+    Leaf {
+        bounds: LightBounds,
+        light: usize,
+    },
+}
+
+impl Node {
+    fn bounds(&self) -> &LightBounds {
+        match self {
+            Node::Interior { bounds, .. } => bounds,
+            Node::Leaf { bounds, .. } => bounds,
+        }
+    }
+}
+
+// Samples lights by descending a bounding volume hierarchy over them, choosing children in
+// proportion to their estimated contribution at the shading point. Lights without bounds, such
+// as directional lights, are sampled uniformly alongside the hierarchy.
+pub struct BvhLightSampler {
+    nodes: Vec<Node>,
+    infinite_lights: Vec<usize>,
+    // Branches taken from the root to reach each light's leaf, one bit per level with the
+    // first level in the lowest bit. Set means the second child.
+    light_paths: Vec<Option<u64>>,
+}
+
+impl BvhLightSampler {
+    pub fn new(lights: &[Box<dyn Light>]) -> BvhLightSampler {
+        let mut infinite_lights = Vec::new();
+        let mut bounded = Vec::new();
+        for (i, light) in lights.iter().enumerate() {
+            match light.bounds() {
+                Some(bounds) if bounds.phi > 0.0 => bounded.push((i, bounds)),
+                Some(_) => {}
+                None => infinite_lights.push(i),
+            }
+        }
+
+        let mut sampler = BvhLightSampler {
+            nodes: Vec::new(),
+            infinite_lights,
+            light_paths: vec![Option::None; lights.len()],
+        };
+        if !bounded.is_empty() {
+            sampler.build(&mut bounded, 0, 0);
+        }
+        sampler
+    }
+
+    // Splits the lights at the median of their centroids along the widest axis.
+    fn build(&mut self, lights: &mut [(usize, LightBounds)], path: u64, depth: u32) -> LightBounds {
+        assert!(depth < 64, "light BVH is too deep");
+        if lights.len() == 1 {
+            let (light, bounds) = lights[0];
+            self.nodes.push(Node::Leaf { bounds, light });
+            self.light_paths[light] = Option::Some(path);
+            return bounds;
+        }
+
+        let (low, high) = lights.iter().fold(
+            (
+                Vector3::new(f64::INFINITY, f64::INFINITY, f64::INFINITY),
+                Vector3::new(f64::NEG_INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+            ),
+            |(low, high), (_, b)| {
+                let c = b.centroid();
+                (component_min(&low, &c), component_max(&high, &c))
+            },
+        );
+        let extent = high - low;
+        let axis = |v: &Vector3| {
+            if extent.x >= extent.y && extent.x >= extent.z {
+                v.x
+            } else if extent.y >= extent.z {
+                v.y
+            } else {
+                v.z
+            }
+        };
+        lights.sort_by(|a, b| axis(&a.1.centroid()).total_cmp(&axis(&b.1.centroid())));
+
+        let index = self.nodes.len();
+        self.nodes.push(Node::Leaf {
+            bounds: lights[0].1,
+            light: lights[0].0,
+        });
+        let mid = lights.len() / 2;
+        let (first, second) = lights.split_at_mut(mid);
+        let first_bounds = self.build(first, path, depth + 1);
+        let second_child = self.nodes.len();
+        let second_bounds = self.build(second, path | (1 << depth), depth + 1);
+        let bounds = LightBounds::union(&first_bounds, &second_bounds);
+        self.nodes[index] = Node::Interior {
+            bounds,
+            second_child,
+        };
+        bounds
+    }
+
+    fn infinite_probability(&self) -> f64 {
+        let bvh = if self.nodes.is_empty() { 0.0 } else { 1.0 };
+        let infinite = self.infinite_lights.len() as f64;
+        if infinite + bvh == 0.0 {
+            return 0.0;
+        }
+        infinite / (infinite + bvh)
+    }
+}
+
+impl LightSampler for BvhLightSampler {
+    fn sample(&self, p: &Point3, n: &Vector3, u: f64) -> Option<(usize, f64)> {
+        let p_infinite = self.infinite_probability();
+        if u < p_infinite {
+            let count = self.infinite_lights.len();
+            let index = ((u / p_infinite * count as f64) as usize).min(count - 1);
+            return Option::Some((self.infinite_lights[index], p_infinite / count as f64));
+        }
+        if self.nodes.is_empty() {
+            return Option::None;
+        }
+
+        let mut u = ((u - p_infinite) / (1.0 - p_infinite)).min(1.0 - f64::EPSILON);
+        let mut pmf = 1.0 - p_infinite;
+        let mut node = 0;
+        loop {
+            count(|stats| stats.light_nodes_visited += 1);
+            match &self.nodes[node] {
+                Node::Interior { second_child, .. } => {
+                    let first = self.nodes[node + 1].bounds().importance(p, n);
+                    let second = self.nodes[*second_child].bounds().importance(p, n);
+                    if first == 0.0 && second == 0.0 {
+                        return Option::None;
+                    }
+                    let p_first = first / (first + second);
+                    if u < p_first {
+                        node += 1;
+                        u = (u / p_first).min(1.0 - f64::EPSILON);
+                        pmf *= p_first;
+                    } else {
+                        node = *second_child;
+                        u = ((u - p_first) / (1.0 - p_first)).min(1.0 - f64::EPSILON);
+                        pmf *= 1.0 - p_first;
+                    }
+                }
+                Node::Leaf { bounds, light } => {
+                    // A lone light is only skipped if it can't reach the point at all.
+                    if node > 0 || bounds.importance(p, n) > 0.0 {
+                        return Option::Some((*light, pmf));
+                    }
+                    return Option::None;
+                }
+            }
+        }
+    }
+
+    fn pmf(&self, p: &Point3, n: &Vector3, light: usize) -> f64 {
+        let p_infinite = self.infinite_probability();
+        let mut path = match self.light_paths[light] {
+            Some(path) => path,
+            None if self.infinite_lights.contains(&light) => {
+                return p_infinite / self.infinite_lights.len() as f64;
+            }
+            None => return 0.0,
+        };
+
+        let mut pmf = 1.0 - p_infinite;
+        let mut node = 0;
+        loop {
+            count(|stats| stats.light_nodes_visited += 1);
+            match &self.nodes[node] {
+                Node::Interior { second_child, .. } => {
+                    let first = self.nodes[node + 1].bounds().importance(p, n);
+                    let second = self.nodes[*second_child].bounds().importance(p, n);
+                    if first == 0.0 && second == 0.0 {
+                        return 0.0;
+                    }
+                    if path & 1 == 0 {
+                        pmf *= first / (first + second);
+                        node += 1;
+                    } else {
+                        pmf *= second / (first + second);
+                        node = *second_child;
+                    }
+                    path >>= 1;
+                }
+                Node::Leaf { bounds, .. } => {
+                    if node > 0 || bounds.importance(p, n) > 0.0 {
+                        return pmf;
+                    }
+                    return 0.0;
+                }
+            }
+        }
+    }
+}
+
+fn safe_sqrt(x: f64) -> f64 {
+    x.max(0.0).sqrt()
+}
+
+fn safe_acos(x: f64) -> f64 {
+    x.clamp(-1.0, 1.0).acos()
+}
+
+fn angle_between(a: &Vector3, b: &Vector3) -> f64 {
+    safe_acos(a.unit().dot(&b.unit()))
+}
+
+// cos(max(0, a - b)) from the sines and cosines of a and b.
+fn cos_sub_clamped(sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64) -> f64 {
+    if cos_a > cos_b {
+        return 1.0;
+    }
+    cos_a * cos_b + sin_a * sin_b
+}
+
+// sin(max(0, a - b)) from the sines and cosines of a and b.
+fn sin_sub_clamped(sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64) -> f64 {
+    if cos_a > cos_b {
+        return 0.0;
+    }
+    sin_a * cos_b - cos_a * sin_b
+}
+
+// Rodrigues' rotation of v by `angle` radians around a unit axis.
+fn rotate(v: &Vector3, axis: &Vector3, angle: f64) -> Vector3 {
+    let (sin, cos) = angle.sin_cos();
+    cos * v + sin * axis.cross(v) + (1.0 - cos) * axis.dot(v) * axis
+}
+
+fn component_min(a: &Vector3, b: &Vector3) -> Vector3 {
+    Vector3::new(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z))
+}
+
+fn component_max(a: &Vector3, b: &Vector3) -> Vector3 {
+    Vector3::new(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::color::luminance;
+    use crate::light::{DirectionalLight, Light, LightSample, PointLight, SpotLight};
+    use crate::light_sampler::{
+        BvhLightSampler, DirectionCone, LightSampler, PowerLightSampler, UniformLightSampler,
+    };
+    use crate::util::{random_f64, seed_random};
+    use crate::vector3::{Vector3 as Color, Vector3 as Point3, Vector3};
+
+    // A street of lights along x, with the shading point next to the first one.
+    fn street() -> Vec<Box<dyn Light>> {
+        let mut lights: Vec<Box<dyn Light>> = Vec::new();
+        for i in 0..200 {
+            let position = Point3::new(i as f64 * 5.0, 4.0, (i % 3) as f64);
+            let power = 1.0 + (i % 7) as f64;
+            lights.push(Box::new(PointLight::new(
+                position,
+                Color::new(power, power, power),
+            )));
+        }
+        lights.push(Box::new(
+            SpotLight::new(
+                Point3::new(2.0, 3.0, 0.0),
+                Point3::new(2.0, 0.0, 0.0),
+                Color::new(20.0, 20.0, 20.0),
+                20.0,
+                30.0,
+            )
+            .unwrap(),
+        ));
+        lights.push(Box::new(
+            DirectionalLight::new(Vector3::new(0.0, -1.0, 0.0), Color::new(0.1, 0.1, 0.1)).unwrap(),
+        ));
+        lights
+    }
+
+    // Unshadowed irradiance at the point from one light.
+    fn contribution(light: &dyn Light, p: &Point3, n: &Vector3) -> f64 {
+        light.sample_li(p).map_or(0.0, |sample| {
+            luminance(&sample.radiance) * sample.direction.dot(n).max(0.0) / sample.pdf
+        })
+    }
+
+    #[test]
+    fn pmf_matches_sample() {
+        let lights = street();
+        let sampler = BvhLightSampler::new(&lights);
+        let p = Point3::new(1.0, 0.0, 0.5);
+        let n = Vector3::new(0.0, 1.0, 0.0);
+        for _ in 0..1000 {
+            let (light, pmf) = sampler.sample(&p, &n, random_f64()).unwrap();
+            assert!((pmf - sampler.pmf(&p, &n, light)).abs() < 1e-12);
+        }
+        let total: f64 = (0..lights.len()).map(|i| sampler.pmf(&p, &n, i)).sum();
+        assert!((total - 1.0).abs() < 1e-9, "{}", total);
+    }
+
+    #[test]
+    fn power_proportional() {
+        let lights = street();
+        let sampler = PowerLightSampler::new(&lights);
+        let p = Point3::zero();
+        let ratio = sampler.pmf(&p, &p, 6) / sampler.pmf(&p, &p, 0);
+        assert!((ratio - 7.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn cone_union() {
+        let a = DirectionCone {
+            w: Vector3::new(1.0, 0.0, 0.0),
+            cos_theta: 1.0,
+        };
+        let b = DirectionCone {
+            w: Vector3::new(0.0, 1.0, 0.0),
+            cos_theta: 1.0,
+        };
+        let union = DirectionCone::union(&a, &b);
+        let diagonal = Vector3::new(1.0, 1.0, 0.0).unit();
+        assert!((union.w - diagonal).length() < 1e-9);
+        assert!((union.cos_theta - std::f64::consts::FRAC_PI_4.cos()).abs() < 1e-9);
+    }
+
+    // Variance of the one-light estimate of direct lighting with each sampler, from the same
+    // number of samples. The light samplers bench compares them in the same time instead, which
+    // also accounts for the cost of walking the BVH.
+    fn variance(sampler: &dyn LightSampler, lights: &[Box<dyn Light>]) -> f64 {
+        let p = Point3::new(1.0, 0.0, 0.5);
+        let n = Vector3::new(0.0, 1.0, 0.0);
+        let reference: f64 = lights
+            .iter()
+            .map(|light| contribution(light.as_ref(), &p, &n))
+            .sum();
+
+        seed_random(7);
+        let count = 20000;
+        let (mut sum, mut squared_error) = (0.0, 0.0);
+        for _ in 0..count {
+            let estimate = match sampler.sample(&p, &n, random_f64()) {
+                Some((light, pmf)) => contribution(lights[light].as_ref(), &p, &n) / pmf,
+                None => 0.0,
+            };
+            sum += estimate;
+            squared_error += (estimate - reference).powi(2);
+        }
+        assert!(((sum / count as f64) - reference).abs() < 0.2 * reference);
+        squared_error / count as f64
+    }
+
+    #[test]
+    fn variance_comparison() {
+        let lights = street();
+        let uniform = variance(&UniformLightSampler::new(&lights), &lights);
+        let power = variance(&PowerLightSampler::new(&lights), &lights);
+        let bvh = variance(&BvhLightSampler::new(&lights), &lights);
+        assert!(bvh < uniform / 10.0, "bvh {} uniform {}", bvh, uniform);
+        assert!(bvh < power / 10.0, "bvh {} power {}", bvh, power);
+    }
+
+    // Implements only what the trait requires.
+    struct Minimal(PointLight);
+
+    impl Light for Minimal {
+        fn sample_li(&self, p: &Point3) -> Option<LightSample> {
+            self.0.sample_li(p)
+        }
+
+        fn pdf_li(&self, p: &Point3, direction: &Vector3) -> f64 {
+            self.0.pdf_li(p, direction)
+        }
+    }
+
+    #[test]
+    fn default_bounds() {
+        let mut lights = street();
+        lights.push(Box::new(Minimal(PointLight::new(
+            Point3::zero(),
+            Color::new(1.0, 1.0, 1.0),
+        ))));
+        let light = lights.len() - 1;
+        let p = Point3::new(1.0, 0.0, 0.5);
+        let n = Vector3::new(0.0, 1.0, 0.0);
+        // Picked like the directional light, the other light without bounds.
+        let sampler = BvhLightSampler::new(&lights);
+        assert_eq!(sampler.pmf(&p, &n, light), sampler.pmf(&p, &n, light - 1));
+        assert!(PowerLightSampler::new(&lights).pmf(&p, &n, light) > 0.0);
+    }
+}
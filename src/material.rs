@@ -1,3 +1,5 @@
+use std::f64::consts::PI;
+
 use crate::ray::Ray;
 use crate::util::random_f64;
 use crate::vector3::{Vector3 as Color, Vector3 as Point, Vector3};
@@ -15,6 +17,24 @@ pub trait Material {
         normal: &Vector3,
         front_face: bool,
     ) -> Option<ScatterRecord>;
+
+    // Fraction of the light arriving from `direction` that is scattered back along the incoming
+    // ray, including the cosine term. Used to shade with light sampled from the surface. Only
+    // materials with a non-zero `scattering_pdf` need to implement it.
+    fn scattering(&self, _r_in: &Ray, _normal: &Vector3, _direction: &Vector3) -> Color {
+        Color::zero()
+    }
+
+    // Solid angle density with which `scatter` picks `direction`. Zero for perfectly specular
+    // materials, which can't be shaded with sampled light.
+    fn scattering_pdf(&self, _r_in: &Ray, _normal: &Vector3, _direction: &Vector3) -> f64 {
+        0.0
+    }
+
+    // Overall color of the surface, which guides the denoiser. White for clear materials.
+    fn albedo(&self) -> Color {
+        Color::new(1.0, 1.0, 1.0)
+    }
 }
 
 #[derive(Clone)]
@@ -44,6 +64,24 @@ impl Material for Lambertian {
             scattered_ray,
         })
     }
+
+    fn scattering(&self, r_in: &Ray, normal: &Vector3, direction: &Vector3) -> Color {
+        self.scattering_pdf(r_in, normal, direction) * self.albedo
+    }
+
+    fn scattering_pdf(&self, _: &Ray, normal: &Vector3, direction: &Vector3) -> f64 {
+        // Offsetting the normal by a random unit vector yields a cosine distribution.
+        let cosine = normal.dot(&direction.unit());
+        if cosine > 0.0 {
+            cosine / PI
+        } else {
+            0.0
+        }
+    }
+
+    fn albedo(&self) -> Color {
+        self.albedo
+    }
 }
 
 #[derive(Clone)]
@@ -78,6 +116,10 @@ impl Material for Metal {
             Option::None
         }
     }
+
+    fn albedo(&self) -> Color {
+        self.albedo
+    }
 }
 
 #[derive(Clone)]
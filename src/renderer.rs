@@ -0,0 +1,649 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::camera::Camera;
+use crate::checkpoint::{load_checkpoint, save_checkpoint, Checkpoint, RenderState};
+use crate::cryptomatte::Cryptomatte;
+use crate::denoise::{Denoiser, FeatureBuffers};
+use crate::error::{check, Error, Result};
+use crate::exr::write_exr;
+use crate::film::{AdaptiveSampling, Budget, Film};
+use crate::filter::{Filter, MitchellFilter};
+use crate::integrator::{ray_aovs, AOV_CHANNELS};
+use crate::scene::Scene;
+use crate::stats::{set_counting, take_stats, Stats};
+use crate::util::{random_f64, seed_pixel};
+use crate::vector3::Vector3 as Color;
+
+pub struct RenderSettings {
+    pub width: usize,
+    pub height: usize,
+    pub filter: Arc<dyn Filter>,
+    pub max_depth: u8,
+    pub adaptive: AdaptiveSampling,
+    // Samples taken for each pixel that hasn't converged in every pass over the image.
+    pub samples_per_pass: u32,
+    pub budget: Budget,
+    // Seed of the random numbers of the camera rays. The scene, if it is random, has to be
+    // built from the same numbers every time for a checkpoint to be resumed.
+    pub seed: u64,
+    pub denoiser: Option<Denoiser>,
+    // Records the render passes in channels of the film, and the object and material mattes.
+    pub aovs: bool,
+    // File to save the render to every `checkpoint_interval` passes and when it stops.
+    pub checkpoint: Option<PathBuf>,
+    pub checkpoint_interval: u32,
+    // Checkpoint to carry on from, made with the same settings apart from the limits on
+    // samples, time and noise.
+    pub resume: Option<PathBuf>,
+    // Leaves samples whose radiance isn't finite out of the image, where one would otherwise
+    // spoil the whole pixel. They are counted either way, including towards the samples taken.
+    pub discard_nonfinite: bool,
+    // Largest value of any channel of the light reaching the first surface after a bounce, to
+    // suppress fireflies. None leaves it unclamped.
+    pub clamp_indirect: Option<f64>,
+    // Counts the rays traced and the intersection tests into `Render::stats`, which slows every
+    // ray down a little. The phases are timed either way.
+    pub stats: bool,
+}
+
+impl RenderSettings {
+    pub fn new(width: usize, height: usize) -> RenderSettings {
+        RenderSettings {
+            width,
+            height,
+            filter: Arc::new(MitchellFilter {
+                radius: 2.0,
+                b: 1.0 / 3.0,
+                c: 1.0 / 3.0,
+            }),
+            max_depth: 50,
+            adaptive: AdaptiveSampling {
+                min_samples: 16,
+                max_samples: 500,
+                threshold: 0.01,
+            },
+            samples_per_pass: 4,
+            budget: Budget::default(),
+            seed: 0,
+            denoiser: Option::None,
+            aovs: false,
+            checkpoint: Option::None,
+            checkpoint_interval: 8,
+            resume: Option::None,
+            discard_nonfinite: true,
+            clamp_indirect: Option::None,
+            stats: false,
+        }
+    }
+
+    // Fails unless the settings describe a render that can be done.
+    pub fn validate(&self) -> Result<()> {
+        check(
+            self.width > 0 && self.height > 0,
+            "the image must be at least 1x1",
+        )?;
+        check(
+            self.samples_per_pass > 0,
+            "samples_per_pass must be positive",
+        )?;
+        check(
+            self.adaptive.min_samples <= self.adaptive.max_samples,
+            "min_samples must not exceed max_samples",
+        )?;
+        check(
+            self.adaptive.threshold >= 0.0 && self.adaptive.threshold.is_finite(),
+            "the adaptive threshold must be finite and not negative",
+        )?;
+        check(
+            self.checkpoint_interval > 0,
+            "checkpoint_interval must be positive",
+        )
+    }
+}
+
+// Where a render is, reported after every row.
+#[derive(Copy, Clone, Debug)]
+pub struct Progress {
+    // Pass being rendered, from 0, and the number there will be if no budget stops the render
+    // first.
+    pub pass: u32,
+    pub total_passes: u32,
+    pub rows_done: usize,
+    pub rows: usize,
+    pub elapsed: Duration,
+}
+
+// Asks a render to stop, from any thread. The render finishes the row it is on and returns what
+// it has so far.
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+// Most samples with radiance that isn't finite kept in `Render::nonfinite`.
+const NONFINITE_LOGGED: usize = 100;
+
+// A sample whose radiance wasn't finite, with the bounce it went bad at, 0 for the camera ray.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct NonFiniteSample {
+    pub x: usize,
+    pub y: usize,
+    pub pass: u32,
+    pub bounce: u8,
+}
+
+// Everything a render produced.
+pub struct Render {
+    pub film: Film,
+    pub features: FeatureBuffers,
+    pub object_matte: Cryptomatte,
+    pub material_matte: Cryptomatte,
+    // Passes done, which doesn't count one cut short by cancelling or running out of time.
+    pub state: RenderState,
+    pub elapsed: Duration,
+    pub cancelled: bool,
+    // The time budget ran out part way through a pass, which was cut short as if cancelled.
+    pub out_of_time: bool,
+    // The last error saving a checkpoint, which doesn't stop the render.
+    pub checkpoint_error: Option<Error>,
+    // Number of samples whose radiance wasn't finite, and the first of them.
+    pub nonfinite_samples: u64,
+    pub nonfinite: Vec<NonFiniteSample>,
+    // Number of samples with indirect light clamped, and the total luminance taken off them.
+    pub clamped_samples: u64,
+    pub clamped_energy: f64,
+    // Counts of the rays traced, if the settings asked for them, and the time spent on resuming,
+    // sampling and checkpoints.
+    pub stats: Stats,
+    denoiser: Option<Denoiser>,
+}
+
+impl Render {
+    // Final image, row by row from the top, denoised if the settings asked for it.
+    pub fn image(&self) -> Vec<Color> {
+        match &self.denoiser {
+            Some(denoiser) => denoiser.denoise(&self.film.pixels(), &self.features),
+            None => self.film.pixels(),
+        }
+    }
+
+    // Writes the image, the render passes and the mattes, with `ranks` objects and materials
+    // per pixel, to a multi-layer OpenEXR file.
+    pub fn write_exr<W: Write>(&self, out: W, ranks: usize) -> Result<()> {
+        let mut channels = self.film.exr_channels();
+        channels.extend(self.object_matte.channels(ranks));
+        channels.extend(self.material_matte.channels(ranks));
+        let mut metadata = self.object_matte.metadata();
+        metadata.extend(self.material_matte.metadata());
+        write_exr(out, self.film.width, self.film.height, &channels, &metadata)
+    }
+}
+
+pub struct Renderer {
+    pub settings: RenderSettings,
+}
+
+impl Renderer {
+    pub fn new(settings: RenderSettings) -> Renderer {
+        Renderer { settings }
+    }
+
+    // Renders the scene in progressive passes until the adaptive sampling is done with every
+    // pixel, the budget runs out or the render is cancelled. Fails only if the settings are
+    // invalid or the checkpoint to resume from can't be read.
+    pub fn render(
+        &self,
+        scene: &Scene,
+        camera: &dyn Camera,
+        progress: &mut dyn FnMut(&Progress),
+        cancel: &CancelToken,
+    ) -> Result<Render> {
+        let settings = &self.settings;
+        settings.validate()?;
+        let (width, height) = (settings.width, settings.height);
+        let mut film = Film::new(width, height, Box::new(settings.filter.clone()));
+        let mut features = FeatureBuffers::new(width, height);
+        let mut object_matte = Cryptomatte::new("CryptoObject", width, height);
+        let mut material_matte = Cryptomatte::new("CryptoMaterial", width, height);
+        if settings.aovs {
+            film.add_channels(&AOV_CHANNELS);
+            // Samples carry the IDs the integrator hashed, so the names are only needed once.
+            for i in 0..scene.world.len() {
+                object_matte.add_name(&scene.object_name(i));
+                material_matte.add_name(&scene.material_name(i));
+            }
+        }
+        // Counts left over from whatever the thread did before belong to something else.
+        take_stats();
+        let mut stats = Stats::default();
+
+        let mut state = RenderState {
+            seed: settings.seed,
+            passes: 0,
+        };
+        if let Some(path) = &settings.resume {
+            let resume_start = Instant::now();
+            let mut parts: [&mut dyn Checkpoint; 4] = [
+                &mut film,
+                &mut features,
+                &mut object_matte,
+                &mut material_matte,
+            ];
+            state = load_checkpoint(path, &mut parts)?;
+            if state.seed != settings.seed {
+                return Err(Error::Parse(format!(
+                    "checkpoint was rendered with seed {}, not {}",
+                    state.seed, settings.seed
+                )));
+            }
+            stats.add_phase("resume", resume_start.elapsed());
+        }
+
+        let total_passes = settings
+            .adaptive
+            .max_samples
+            .div_ceil(settings.samples_per_pass);
+        let start = Instant::now();
+        let mut cancelled = false;
+        let mut out_of_time = false;
+        let mut checkpoint_error = Option::None;
+        let mut nonfinite = Vec::new();
+        let mut nonfinite_samples = 0;
+        let (mut clamped_samples, mut clamped_energy) = (0, 0.0);
+        let counting = set_counting(settings.stats);
+        for pass in state.passes..total_passes {
+            let pass_start = Instant::now();
+            let mut sampled = false;
+
+            for y in 0..height {
+                if cancel.is_cancelled() {
+                    cancelled = true;
+                    break;
+                }
+                if settings.budget.out_of_time(start.elapsed()) {
+                    out_of_time = true;
+                    break;
+                }
+                for x in 0..width {
+                    if settings.adaptive.done(&film, x, y) {
+                        continue;
+                    }
+                    sampled = true;
+                    seed_pixel(state.seed, pass, x, y);
+                    for _ in 0..settings.samples_per_pass {
+                        let film_x = x as f64 + random_f64();
+                        let film_y = y as f64 + random_f64();
+                        let u = film_x / width as f64;
+                        let v = 1.0 - film_y / height as f64;
+
+                        let color = match camera.get_weighted_ray(u, v) {
+                            Some((r, weight)) => {
+                                let mut aovs = ray_aovs(&r, scene, settings.max_depth);
+                                if let Some(bounce) = aovs.nonfinite_bounce {
+                                    nonfinite_samples += 1;
+                                    if nonfinite.len() < NONFINITE_LOGGED {
+                                        nonfinite.push(NonFiniteSample { x, y, pass, bounce });
+                                    }
+                                    if settings.discard_nonfinite {
+                                        film.discard_sample(film_x, film_y);
+                                        continue;
+                                    }
+                                }
+                                if let Some(max) = settings.clamp_indirect {
+                                    let removed = aovs.clamp_indirect(max);
+                                    if removed > 0.0 {
+                                        clamped_samples += 1;
+                                        clamped_energy += removed;
+                                    }
+                                }
+                                if weight != 1.0 {
+                                    aovs.scale_light(weight);
+                                }
+                                if settings.denoiser.is_some() {
+                                    features.add_sample(film_x, film_y, &aovs.features());
+                                }
+                                if settings.aovs {
+                                    film.add_channel_sample(film_x, film_y, &aovs.values());
+                                    let object = aovs.object.map(|_| aovs.object_id);
+                                    object_matte.add_sample(film_x, film_y, object);
+                                    let material = aovs.object.map(|_| aovs.material_id);
+                                    material_matte.add_sample(film_x, film_y, material);
+                                }
+                                aovs.beauty()
+                            }
+                            None => Color::zero(),
+                        };
+                        film.add_sample(film_x, film_y, color);
+                    }
+                }
+                progress(&Progress {
+                    pass,
+                    total_passes,
+                    rows_done: y + 1,
+                    rows: height,
+                    elapsed: start.elapsed(),
+                });
+            }
+            stats.add_phase("sampling", pass_start.elapsed());
+            // A pass cut short would be sampled again on resuming, so it isn't saved.
+            if cancelled || out_of_time {
+                break;
+            }
+
+            state.passes = pass + 1;
+            let stop = !sampled
+                || settings
+                    .budget
+                    .exhausted(start.elapsed(), pass_start.elapsed(), &film);
+            if let Some(path) = &settings.checkpoint {
+                if stop
+                    || state.passes.is_multiple_of(settings.checkpoint_interval)
+                    || state.passes == total_passes
+                {
+                    let save_start = Instant::now();
+                    let parts: [&dyn Checkpoint; 4] =
+                        [&film, &features, &object_matte, &material_matte];
+                    if let Err(e) = save_checkpoint(path, &state, &parts) {
+                        checkpoint_error = Option::Some(e);
+                    }
+                    stats.add_phase("checkpoint", save_start.elapsed());
+                }
+            }
+            if stop {
+                break;
+            }
+        }
+
+        set_counting(counting);
+        stats.merge(&take_stats());
+        Ok(Render {
+            film,
+            features,
+            object_matte,
+            material_matte,
+            state,
+            elapsed: start.elapsed(),
+            cancelled,
+            out_of_time,
+            checkpoint_error,
+            nonfinite_samples,
+            nonfinite,
+            clamped_samples,
+            clamped_energy,
+            stats,
+            denoiser: settings.denoiser,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::camera::PerspectiveCamera;
+    use crate::cryptomatte::name_id;
+    use crate::denoise::Denoiser;
+    use crate::film::AdaptiveSampling;
+    use crate::material::{Lambertian, Material, ScatterRecord};
+    use crate::ray::Ray;
+    use crate::renderer::{CancelToken, RenderSettings, Renderer};
+    use crate::scene::Scene;
+    use crate::sphere::Sphere;
+    use crate::stats::{take_stats, Primitive, Stats};
+    use crate::vector3::{Vector3 as Color, Vector3 as Point3, Vector3};
+
+    fn scene() -> Scene {
+        Scene::new(vec![Box::new(
+            Sphere::new(
+                Point3::zero(),
+                1.0,
+                Lambertian::new(Color::new(0.5, 0.5, 0.5)),
+            )
+            .unwrap(),
+        )])
+    }
+
+    fn camera() -> PerspectiveCamera {
+        PerspectiveCamera::new(
+            Point3::new(0.0, 0.0, 5.0),
+            Point3::zero(),
+            Vector3::new(0.0, 1.0, 0.0),
+            40.0,
+            1.5,
+            0.0,
+            5.0,
+        )
+        .unwrap()
+    }
+
+    fn settings() -> RenderSettings {
+        let mut settings = RenderSettings::new(6, 4);
+        settings.adaptive = AdaptiveSampling {
+            min_samples: 8,
+            max_samples: 8,
+            threshold: 0.01,
+        };
+        settings
+    }
+
+    #[test]
+    fn render() {
+        let mut rows = Vec::new();
+        let mut settings = settings();
+        settings.stats = true;
+        let render = Renderer::new(settings)
+            .render(
+                &scene(),
+                &camera(),
+                &mut |progress| rows.push((progress.pass, progress.rows_done)),
+                &CancelToken::default(),
+            )
+            .unwrap();
+        assert!(!render.cancelled);
+        assert_eq!(2, render.state.passes);
+        assert_eq!(24 * 8, render.stats.camera_rays);
+        assert!(render.stats.intersections(Primitive::Sphere).hits > 0);
+        assert_eq!("sampling", render.stats.phases[0].0);
+        assert_eq!(vec![(0, 1), (0, 2), (0, 3), (0, 4)], rows[..4]);
+        assert_eq!(8, rows.len());
+        assert_eq!(8.0, render.film.mean_sample_count());
+        // The sphere fills the middle of the image, lit by the sky.
+        let image = render.image();
+        assert_eq!(24, image.len());
+        assert!(image[6 + 3].x > 0.0);
+    }
+
+    #[test]
+    fn stats() {
+        let counted = |denoiser| {
+            let mut settings = settings();
+            settings.stats = true;
+            settings.denoiser = denoiser;
+            Renderer::new(settings)
+                .render(&scene(), &camera(), &mut |_| {}, &CancelToken::default())
+                .unwrap()
+                .stats
+        };
+        let stats = counted(Option::None);
+        assert_eq!(24 * 8, stats.camera_rays);
+        // The denoiser's features come from the camera rays already traced.
+        let denoised = counted(Option::Some(Denoiser::default()));
+        assert_eq!(stats.intersections, denoised.intersections);
+
+        // Without counting, only the phases are timed.
+        let render = Renderer::new(self::settings())
+            .render(&scene(), &camera(), &mut |_| {}, &CancelToken::default())
+            .unwrap();
+        assert_eq!(0, render.stats.camera_rays);
+        assert_eq!(0, render.stats.intersections(Primitive::Sphere).tests);
+        assert_eq!("sampling", render.stats.phases[0].0);
+    }
+
+    #[test]
+    fn stats_from_threads() {
+        let renders: Vec<Stats> = (0..4)
+            .map(|_| {
+                std::thread::spawn(|| {
+                    let mut settings = settings();
+                    settings.stats = true;
+                    Renderer::new(settings)
+                        .render(&scene(), &camera(), &mut |_| {}, &CancelToken::default())
+                        .unwrap()
+                        .stats
+                })
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect();
+        let mut total = Stats::default();
+        for stats in &renders {
+            total.merge(stats);
+        }
+        assert_eq!(4 * 24 * 8, total.camera_rays);
+        let tests = |stats: &Stats| stats.intersections(Primitive::Sphere).tests;
+        assert!(tests(&total) > 0);
+        assert_eq!(renders.iter().map(tests).sum::<u64>(), tests(&total));
+        let sampling = renders.iter().map(|stats| stats.phases[0].1).sum();
+        assert_eq!(vec![(String::from("sampling"), sampling)], total.phases);
+        // The workers counted into their own counters and left this thread's alone.
+        assert_eq!(Stats::default(), take_stats());
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let render = |change: fn(&mut RenderSettings)| {
+            let mut settings = settings();
+            change(&mut settings);
+            Renderer::new(settings).render(
+                &scene(),
+                &camera(),
+                &mut |_| {},
+                &CancelToken::default(),
+            )
+        };
+        assert!(render(|_| {}).is_ok());
+        assert!(render(|s| s.width = 0).is_err());
+        assert!(render(|s| s.height = 0).is_err());
+        assert!(render(|s| s.samples_per_pass = 0).is_err());
+        assert!(render(|s| s.checkpoint_interval = 0).is_err());
+        assert!(render(|s| s.adaptive.min_samples = s.adaptive.max_samples + 1).is_err());
+        assert!(render(|s| s.adaptive.threshold = -0.1).is_err());
+        assert!(render(|s| s.adaptive.threshold = f64::NAN).is_err());
+        assert!(render(|s| s.adaptive.threshold = f64::INFINITY).is_err());
+    }
+
+    #[test]
+    fn cancel() {
+        let cancel = CancelToken::default();
+        let render = Renderer::new(settings())
+            .render(
+                &scene(),
+                &camera(),
+                &mut |progress| {
+                    if progress.rows_done == 2 {
+                        cancel.cancel();
+                    }
+                },
+                &cancel,
+            )
+            .unwrap();
+        assert!(render.cancelled);
+        assert_eq!(0, render.state.passes);
+        // The rows done before cancelling are kept.
+        assert_eq!(4, render.film.sample_count(0, 1));
+        assert_eq!(0, render.film.sample_count(0, 2));
+    }
+
+    #[test]
+    fn aovs() {
+        let mut settings = settings();
+        settings.aovs = true;
+        let mut scene = scene();
+        scene.object_names = vec![String::from("ball")];
+        let render = Renderer::new(settings)
+            .render(&scene, &camera(), &mut |_| {}, &CancelToken::default())
+            .unwrap();
+        // The middle of the image sees mostly the ball, and the corners only the sky.
+        let id = name_id("ball");
+        let ranked = render.object_matte.ranked(3, 2);
+        assert_eq!(id, ranked[0].0);
+        assert!(ranked[0].1 > 0.5);
+        assert!(render.object_matte.ranked(0, 0).is_empty());
+        let ids = render.film.channel("object.id").unwrap();
+        assert_eq!(id as f64, ids[2 * 6 + 3]);
+        assert_eq!(0.0, ids[0]);
+        let depth = render.film.channel("depth.Z").unwrap();
+        assert!(4.0 < depth[2 * 6 + 3] && depth[2 * 6 + 3] < 5.0);
+        assert_eq!(0.0, depth[0]);
+        let manifest = &render.material_matte.metadata()[3].1;
+        assert!(manifest.contains("\"default\":"));
+    }
+
+    #[test]
+    fn out_of_time() {
+        let mut settings = settings();
+        settings.budget.time = Option::Some(Duration::ZERO);
+        let render = Renderer::new(settings)
+            .render(&scene(), &camera(), &mut |_| {}, &CancelToken::default())
+            .unwrap();
+        // The time is up before the first row.
+        assert!(render.out_of_time);
+        assert!(!render.cancelled);
+        assert_eq!(0, render.state.passes);
+        assert_eq!(0.0, render.film.mean_sample_count());
+    }
+
+    // Scatters with an attenuation that isn't a number, so every sample that hits it is bad.
+    struct Broken;
+
+    impl Material for Broken {
+        fn scatter(
+            &self,
+            _r_in: &Ray,
+            p: &Point3,
+            normal: &Vector3,
+            _front_face: bool,
+        ) -> Option<ScatterRecord> {
+            Option::Some(ScatterRecord {
+                attenuation: Color::new(f64::NAN, 0.0, 0.0),
+                scattered_ray: Ray::new(*p, *normal),
+            })
+        }
+    }
+
+    #[test]
+    fn discard_nonfinite() {
+        // The camera is inside a broken sphere, so no sample is ever kept.
+        let scene = Scene::new(vec![Box::new(
+            Sphere::new(Point3::zero(), 100.0, Broken).unwrap(),
+        )]);
+        let mut settings = settings();
+        settings.adaptive.max_samples = u32::MAX;
+        settings.budget.error = Option::Some(0.05);
+        let render = Renderer::new(settings)
+            .render(&scene, &camera(), &mut |_| {}, &CancelToken::default())
+            .unwrap();
+        // The render stops rather than sampling the pixels forever.
+        assert!(render.nonfinite_samples > 0);
+        assert_eq!(
+            render.nonfinite_samples,
+            24 * render.film.sample_count(0, 0) as u64
+        );
+        assert!(render.film.all_discarded(0, 0));
+    }
+}
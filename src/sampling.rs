@@ -0,0 +1,265 @@
+use crate::error::{check, Result};
+
+// Piecewise constant distribution over [0, 1) proportional to a tabulated function.
+pub struct Distribution1D {
+    pub func: Vec<f64>,
+    cdf: Vec<f64>,
+    pub integral: f64,
+}
+
+impl Distribution1D {
+    pub fn new(func: &[f64]) -> Result<Distribution1D> {
+        let n = func.len();
+        check(n > 0, "a distribution needs at least one value")?;
+        let func: Vec<f64> = func.iter().map(|f| f.abs()).collect();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 1..=n {
+            cdf[i] = cdf[i - 1] + func[i - 1] / n as f64;
+        }
+        let integral = cdf[n];
+        if integral == 0.0 {
+            // Fall back to a uniform distribution rather than dividing by zero.
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f64 / n as f64;
+            }
+        } else {
+            for c in cdf.iter_mut() {
+                *c /= integral;
+            }
+        }
+        Ok(Distribution1D {
+            func,
+            cdf,
+            integral,
+        })
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    // Maps a uniform sample to a point in [0, 1), returning it with its density and the index
+    // of the segment it fell in.
+    pub fn sample_continuous(&self, u: f64) -> (f64, f64, usize) {
+        // Last cdf entry not greater than u.
+        let offset = self.cdf.partition_point(|c| *c <= u).clamp(1, self.count()) - 1;
+        let mut du = u - self.cdf[offset];
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        if width > 0.0 {
+            du /= width;
+        }
+        let pdf = self.pdf(offset);
+        ((offset as f64 + du) / self.count() as f64, pdf, offset)
+    }
+
+    pub fn pdf(&self, index: usize) -> f64 {
+        if self.integral > 0.0 {
+            self.func[index] / self.integral
+        } else {
+            1.0
+        }
+    }
+}
+
+// Piecewise constant distribution over [0, 1)^2, sampled by picking a row from the marginal
+// distribution and then a column from that row.
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    // `func` holds `height` rows of `width` values each.
+    pub fn new(func: &[f64], width: usize, height: usize) -> Result<Distribution2D> {
+        check(
+            width > 0 && height > 0,
+            "a distribution needs at least one value",
+        )?;
+        check(
+            width.checked_mul(height) == Option::Some(func.len()),
+            "a distribution needs a value for every cell",
+        )?;
+        let conditional = func
+            .chunks(width)
+            .map(Distribution1D::new)
+            .collect::<Result<Vec<Distribution1D>>>()?;
+        let marginal =
+            Distribution1D::new(&conditional.iter().map(|d| d.integral).collect::<Vec<_>>())?;
+        Ok(Distribution2D {
+            conditional,
+            marginal,
+        })
+    }
+
+    // Returns (u, v, pdf), where v selects the row and u the column.
+    pub fn sample(&self, u0: f64, u1: f64) -> (f64, f64, f64) {
+        let (v, pdf_v, row) = self.marginal.sample_continuous(u1);
+        let (u, pdf_u, _) = self.conditional[row].sample_continuous(u0);
+        (u, v, pdf_u * pdf_v)
+    }
+
+    pub fn pdf(&self, u: f64, v: f64) -> f64 {
+        let row = ((v * self.marginal.count() as f64) as usize).min(self.marginal.count() - 1);
+        let row_distribution = &self.conditional[row];
+        let column =
+            ((u * row_distribution.count() as f64) as usize).min(row_distribution.count() - 1);
+        if self.marginal.integral == 0.0 {
+            return 1.0;
+        }
+        row_distribution.func[column] / self.marginal.integral
+    }
+}
+
+// Walker's alias method for sampling from a discrete distribution in constant time.
+pub struct AliasTable {
+    bins: Vec<AliasBin>,
+}
+
+struct AliasBin {
+    // Probability of keeping the bin's own index rather than its alias.
+    q: f64,
+    pmf: f64,
+    alias: usize,
+}
+
+impl AliasTable {
+    pub fn new(weights: &[f64]) -> AliasTable {
+        let n = weights.len();
+        let total: f64 = weights.iter().sum();
+        let mut bins: Vec<AliasBin> = weights
+            .iter()
+            .map(|w| AliasBin {
+                q: 0.0,
+                pmf: if total > 0.0 {
+                    w / total
+                } else {
+                    1.0 / n as f64
+                },
+                alias: 0,
+            })
+            .collect();
+
+        // Pair bins below the average with ones above it until every bin holds exactly 1/n.
+        let mut under = Vec::new();
+        let mut over = Vec::new();
+        for (i, bin) in bins.iter().enumerate() {
+            let p = bin.pmf * n as f64;
+            if p < 1.0 {
+                under.push((i, p));
+            } else {
+                over.push((i, p));
+            }
+        }
+        while !under.is_empty() && !over.is_empty() {
+            let (u, pu) = under.pop().unwrap();
+            let (o, po) = over.pop().unwrap();
+            bins[u].q = pu;
+            bins[u].alias = o;
+            let excess = pu + po - 1.0;
+            if excess < 1.0 {
+                under.push((o, excess));
+            } else {
+                over.push((o, excess));
+            }
+        }
+        // Whatever is left is within rounding error of 1/n.
+        for (i, _) in over.into_iter().chain(under) {
+            bins[i].q = 1.0;
+            bins[i].alias = i;
+        }
+        AliasTable { bins }
+    }
+
+    pub fn len(&self) -> usize {
+        self.bins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bins.is_empty()
+    }
+
+    // Returns an index with its probability.
+    pub fn sample(&self, u: f64) -> (usize, f64) {
+        let scaled = u * self.len() as f64;
+        let offset = (scaled as usize).min(self.len() - 1);
+        let up = (scaled - offset as f64).min(1.0);
+        let index = if up < self.bins[offset].q {
+            offset
+        } else {
+            self.bins[offset].alias
+        };
+        (index, self.bins[index].pmf)
+    }
+
+    pub fn pmf(&self, index: usize) -> f64 {
+        self.bins[index].pmf
+    }
+}
+
+// Multiple importance sampling weight for a sample drawn from the first of two strategies.
+pub fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+    let f = pdf * pdf;
+    let g = other_pdf * other_pdf;
+    if f + g == 0.0 {
+        return 0.0;
+    }
+    f / (f + g)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sampling::{power_heuristic, AliasTable, Distribution1D, Distribution2D};
+
+    #[test]
+    fn distribution_1d() {
+        let distribution = Distribution1D::new(&[1.0, 3.0]).unwrap();
+        assert_eq!(2.0, distribution.integral);
+        assert_eq!((0.0, 0.5, 0), distribution.sample_continuous(0.0));
+        assert_eq!((0.25, 0.5, 0), distribution.sample_continuous(0.125));
+        assert_eq!((0.5, 1.5, 1), distribution.sample_continuous(0.25));
+        assert_eq!((0.75, 1.5, 1), distribution.sample_continuous(0.625));
+    }
+
+    #[test]
+    fn zero_distribution() {
+        let distribution = Distribution1D::new(&[0.0, 0.0]).unwrap();
+        assert_eq!((0.25, 1.0, 0), distribution.sample_continuous(0.25));
+    }
+
+    #[test]
+    fn distribution_2d() {
+        // Only the bottom right cell has any weight.
+        let distribution = Distribution2D::new(&[0.0, 0.0, 0.0, 2.0], 2, 2).unwrap();
+        let (u, v, pdf) = distribution.sample(0.5, 0.5);
+        assert_eq!((0.75, 0.75, 4.0), (u, v, pdf));
+        assert_eq!(4.0, distribution.pdf(u, v));
+        assert_eq!(0.0, distribution.pdf(0.25, 0.25));
+    }
+
+    #[test]
+    fn empty() {
+        assert!(Distribution1D::new(&[]).is_err());
+        assert!(Distribution2D::new(&[], 0, 0).is_err());
+        assert!(Distribution2D::new(&[], 0, 2).is_err());
+        assert!(Distribution2D::new(&[1.0, 2.0, 3.0], 2, 2).is_err());
+    }
+
+    #[test]
+    fn alias_table() {
+        let table = AliasTable::new(&[1.0, 2.0, 0.0, 5.0]);
+        let mut counts = [0; 4];
+        let n = 8000;
+        for i in 0..n {
+            let (index, pmf) = table.sample((i as f64 + 0.5) / n as f64);
+            assert_eq!(table.pmf(index), pmf);
+            counts[index] += 1;
+        }
+        assert_eq!([1000, 2000, 0, 5000], counts);
+    }
+
+    #[test]
+    fn power() {
+        assert_eq!(0.8, power_heuristic(2.0, 1.0));
+        assert_eq!(1.0, power_heuristic(1.0, 0.0));
+    }
+}
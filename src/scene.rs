@@ -0,0 +1,47 @@
+use std::borrow::Cow;
+
+use crate::environment::{Environment, GradientEnvironment};
+use crate::hittable::Hittable;
+use crate::light::Light;
+use crate::light_sampler::LightSampler;
+
+pub struct Scene {
+    pub world: Vec<Box<dyn Hittable>>,
+    pub environment: Box<dyn Environment>,
+    pub lights: Vec<Box<dyn Light>>,
+    // Picks one light per shading point for next event estimation. Without one, every light is
+    // sampled at every hit. It must be built from `lights` and rebuilt when they change.
+    pub light_sampler: Option<Box<dyn LightSampler>>,
+    // Names of each object of the world and of its material, in the same order, which are
+    // hashed into the IDs of ID passes and mattes. Objects past the end of either are named
+    // after their index and share a default material.
+    pub object_names: Vec<String>,
+    pub material_names: Vec<String>,
+}
+
+impl Scene {
+    pub fn new(world: Vec<Box<dyn Hittable>>) -> Scene {
+        Scene {
+            world,
+            environment: Box::new(GradientEnvironment::default()),
+            lights: Vec::new(),
+            light_sampler: Option::None,
+            object_names: Vec::new(),
+            material_names: Vec::new(),
+        }
+    }
+
+    pub fn object_name(&self, index: usize) -> Cow<'_, str> {
+        match self.object_names.get(index) {
+            Some(name) => Cow::Borrowed(name),
+            None => Cow::Owned(format!("object{}", index)),
+        }
+    }
+
+    pub fn material_name(&self, index: usize) -> Cow<'_, str> {
+        match self.material_names.get(index) {
+            Some(name) => Cow::Borrowed(name),
+            None => Cow::Borrowed("default"),
+        }
+    }
+}
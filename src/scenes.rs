@@ -0,0 +1,250 @@
+use crate::environment::ConstantEnvironment;
+use crate::error::Result;
+use crate::heightfield::Heightfield;
+use crate::hittable::Hittable;
+use crate::light::{Light, PointLight};
+use crate::light_sampler::BvhLightSampler;
+use crate::material::{Dielectric, Lambertian, Metal};
+use crate::scene::Scene;
+use crate::sphere::Sphere;
+use crate::util::{random_f64, random_range};
+use crate::vector3::{Vector3 as Color, Vector3 as Point3, Vector3};
+
+// The cover of Ray Tracing in One Weekend: three large balls among hundreds of small random
+// ones. The scene is built from the random numbers of the current thread, so seed them first
+// for the same scene every time. Best seen from (13, 2, 3) looking at the origin.
+pub fn random_scene() -> Result<Scene> {
+    let mut world: Vec<Box<dyn Hittable>> = Vec::new();
+    let mut object_names = Vec::new();
+    let mut material_names = Vec::new();
+
+    let ground_material = Lambertian::new(Color::new(0.5, 0.5, 0.5));
+    world.push(Box::new(Sphere::new(
+        Point3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        ground_material.clone(),
+    )?));
+    object_names.push(String::from("ground"));
+    material_names.push(String::from("ground"));
+
+    for a in -11..11 {
+        for b in -11..11 {
+            let choose_mat = random_f64();
+            let center = Point3::new(
+                a as f64 + 0.9 * random_f64(),
+                0.2,
+                b as f64 + 0.9 * random_f64(),
+            );
+
+            if (center - Point3::new(4.0, 0.2, 0.0)).length() > 0.9 {
+                if choose_mat < 0.8 {
+                    // diffuse
+                    let albedo = Color::new_random() * Color::new_random();
+                    let sphere_material = Lambertian::new(albedo);
+                    world.push(Box::new(Sphere::new(center, 0.2, sphere_material)?));
+                    material_names.push(String::from("diffuse"));
+                } else if choose_mat < 0.95 {
+                    // metal
+                    let albedo = Color::new_random_range(0.5, 1.0);
+                    let fuzz = random_range(0.0, 0.5);
+                    let sphere_material = Metal::new(albedo, fuzz);
+                    world.push(Box::new(Sphere::new(center, 0.2, sphere_material)?));
+                    material_names.push(String::from("metal"));
+                } else {
+                    // glass
+                    let sphere_material = Dielectric::new(1.5);
+                    world.push(Box::new(Sphere::new(center, 0.2, sphere_material)?));
+                    material_names.push(String::from("glass"));
+                }
+                object_names.push(format!("sphere_{}_{}", a, b));
+            }
+        }
+    }
+
+    let material1 = Dielectric::new(1.5);
+    world.push(Box::new(Sphere::new(
+        Point3::new(0.0, 1.0, 0.0),
+        1.0,
+        material1,
+    )?));
+    object_names.push(String::from("glass_ball"));
+    material_names.push(String::from("glass"));
+
+    let material2 = Lambertian::new(Color::new(0.4, 0.2, 0.1));
+    world.push(Box::new(Sphere::new(
+        Point3::new(-4.0, 1.0, 0.0),
+        1.0,
+        material2,
+    )?));
+    object_names.push(String::from("diffuse_ball"));
+    material_names.push(String::from("brown"));
+
+    let material3 = Metal::new(Color::new(0.7, 0.6, 0.5), 0.0);
+    world.push(Box::new(Sphere::new(
+        Point3::new(4.0, 1.0, 0.0),
+        1.0,
+        material3,
+    )?));
+    object_names.push(String::from("metal_ball"));
+    material_names.push(String::from("mirror"));
+
+    let mut scene = Scene::new(world);
+    scene.object_names = object_names;
+    scene.material_names = material_names;
+    Ok(scene)
+}
+
+// Closed box with a red left and a green right wall, lit by a point light in the upper half,
+// with a mirror ball and a glass ball on the floor. The walls are spheres so large they are
+// flat for all purposes. The box spans 0 to 100 on every axis, with the opening at z = 100.
+pub fn cornell_box() -> Result<Scene> {
+    const R: f64 = 1e5;
+    let white = Color::new(0.73, 0.73, 0.73);
+    let wall = |center: Point3, albedo: Color| -> Result<Box<dyn Hittable>> {
+        Ok(Box::new(Sphere::new(center, R, Lambertian::new(albedo))?))
+    };
+    let world: Vec<Box<dyn Hittable>> = vec![
+        wall(Point3::new(-R, 50.0, 50.0), Color::new(0.65, 0.05, 0.05))?,
+        wall(
+            Point3::new(100.0 + R, 50.0, 50.0),
+            Color::new(0.12, 0.45, 0.15),
+        )?,
+        wall(Point3::new(50.0, -R, 50.0), white)?,
+        wall(Point3::new(50.0, 100.0 + R, 50.0), white)?,
+        wall(Point3::new(50.0, 50.0, -R), white)?,
+        Box::new(Sphere::new(
+            Point3::new(30.0, 18.0, 40.0),
+            18.0,
+            Metal::new(Color::new(0.9, 0.9, 0.9), 0.0),
+        )?),
+        Box::new(Sphere::new(
+            Point3::new(70.0, 18.0, 65.0),
+            18.0,
+            Dielectric::new(1.5),
+        )?),
+    ];
+    let mut scene = Scene::new(world);
+    scene.environment = Box::new(ConstantEnvironment {
+        color: Color::zero(),
+    });
+    scene.lights.push(Box::new(PointLight::new(
+        Point3::new(50.0, 75.0, 60.0),
+        Color::new(3000.0, 3000.0, 3000.0),
+    )));
+    scene.object_names = [
+        "left", "right", "floor", "ceiling", "back", "mirror", "glass",
+    ]
+    .iter()
+    .map(|name| name.to_string())
+    .collect();
+    Ok(scene)
+}
+
+// A grid of glass balls of different indices of refraction over a diffuse floor, where most
+// paths go through several refractions and total internal reflections. Best seen from
+// (0, 4, 9) looking at (0, 0.5, 0).
+pub fn glass_scene() -> Result<Scene> {
+    let mut world: Vec<Box<dyn Hittable>> = vec![Box::new(Sphere::new(
+        Point3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        Lambertian::new(Color::new(0.4, 0.45, 0.5)),
+    )?)];
+    for i in 0..5 {
+        for j in 0..5 {
+            let center = Point3::new(i as f64 * 1.6 - 3.2, 0.6, j as f64 * 1.6 - 3.2);
+            let index = 1.3 + 0.1 * ((i + j) % 5) as f64;
+            world.push(Box::new(Sphere::new(center, 0.6, Dielectric::new(index))?));
+        }
+    }
+    Ok(Scene::new(world))
+}
+
+// Rolling terrain made of a `samples` by `samples` heightfield, which with 256 samples is over
+// 130,000 triangles. It spans -50 to 50 along x and z and rises up to 8. Best seen from
+// (0, 20, 60) looking at the origin.
+pub fn terrain(samples: usize) -> Result<Scene> {
+    let mut heights = Vec::with_capacity(samples * samples);
+    for j in 0..samples {
+        for i in 0..samples {
+            let (x, z) = (i as f64 / samples as f64, j as f64 / samples as f64);
+            let height = 0.5
+                + 0.25 * (x * 13.0).sin() * (z * 11.0).cos()
+                + 0.15 * (x * 37.0 + z * 23.0).sin()
+                + 0.1 * (x * 71.0).cos() * (z * 83.0).sin();
+            heights.push(height);
+        }
+    }
+    let world: Vec<Box<dyn Hittable>> = vec![Box::new(Heightfield::new(
+        samples,
+        samples,
+        &heights,
+        Point3::new(-50.0, 0.0, -50.0),
+        Vector3::new(100.0, 8.0, 100.0),
+        Lambertian::new(Color::new(0.35, 0.5, 0.25)),
+    )?)];
+    Ok(Scene::new(world))
+}
+
+// A road at night lined with 64 street lamps and nothing else to light it, with balls along the
+// middle. Next event estimation picks one lamp per hit with a light BVH, rather than tracing a
+// shadow ray to every lamp. Best seen from (0, 3, 12) looking at (0, 1, -20).
+pub fn street() -> Result<Scene> {
+    let mut world: Vec<Box<dyn Hittable>> = vec![Box::new(Sphere::new(
+        Point3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        Lambertian::new(Color::new(0.5, 0.5, 0.5)),
+    )?)];
+    let mut lights: Vec<Box<dyn Light>> = Vec::new();
+    for i in 0..32 {
+        let z = -6.0 * i as f64;
+        for side in [-1.0, 1.0] {
+            lights.push(Box::new(PointLight::new(
+                Point3::new(5.0 * side, 4.0, z),
+                Color::new(60.0, 45.0, 25.0),
+            )));
+        }
+        let albedo = [
+            Color::new(0.7, 0.2, 0.2),
+            Color::new(0.2, 0.6, 0.3),
+            Color::new(0.2, 0.3, 0.7),
+        ][i % 3];
+        world.push(Box::new(Sphere::new(
+            Point3::new(2.0 * (i % 3) as f64 - 2.0, 0.7, z - 3.0),
+            0.7,
+            Lambertian::new(albedo),
+        )?));
+    }
+    let mut scene = Scene::new(world);
+    scene.environment = Box::new(ConstantEnvironment {
+        color: Color::zero(),
+    });
+    scene.light_sampler = Option::Some(Box::new(BvhLightSampler::new(&lights)));
+    scene.lights = lights;
+    Ok(scene)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scenes::{cornell_box, random_scene, street, terrain};
+    use crate::util::seed_random;
+
+    #[test]
+    fn names() {
+        seed_random(1);
+        let scene = random_scene().unwrap();
+        assert_eq!(scene.world.len(), scene.object_names.len());
+        assert_eq!(scene.world.len(), scene.material_names.len());
+        // The same seed builds the same scene.
+        seed_random(1);
+        assert_eq!(scene.object_names, random_scene().unwrap().object_names);
+
+        let scene = cornell_box().unwrap();
+        assert_eq!(scene.world.len(), scene.object_names.len());
+
+        assert!(terrain(1).is_err());
+
+        let scene = street().unwrap();
+        assert_eq!(64, scene.lights.len());
+        assert!(scene.light_sampler.is_some());
+    }
+}
@@ -0,0 +1,500 @@
+use crate::hittable::{HitRecord, Hittable};
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::stats::{count_test, Primitive};
+use crate::util::clamp;
+use crate::vector3::{Vector3 as Point3, Vector3};
+
+// Signed distance to a surface: negative inside, positive outside. The magnitude must never
+// overestimate the distance to the surface for sphere tracing to be exact.
+pub trait Sdf {
+    fn distance(&self, p: Point3) -> f64;
+}
+
+impl<F: Fn(Point3) -> f64> Sdf for F {
+    fn distance(&self, p: Point3) -> f64 {
+        self(p)
+    }
+}
+
+pub struct SdfObject<S: Sdf, M: Material> {
+    pub sdf: S,
+    pub material: M,
+    pub max_steps: u32,
+    pub max_distance: f64,
+    pub epsilon: f64,
+    // Fraction of the distance bound advanced at each step. Distortions such as twisting break
+    // the distance bound, and stepping less than the bound keeps them from tunnelling through.
+    pub step_scale: f64,
+}
+
+impl<S: Sdf, M: Material> SdfObject<S, M> {
+    pub fn new(sdf: S, material: M) -> SdfObject<S, M> {
+        SdfObject {
+            sdf,
+            material,
+            max_steps: 512,
+            max_distance: 1000.0,
+            epsilon: 1e-4,
+            step_scale: 1.0,
+        }
+    }
+
+    // Estimates the gradient with central differences. It points out of the surface.
+    pub fn gradient(&self, p: Point3) -> Vector3 {
+        let h = self.epsilon;
+        let dx = Vector3::new(h, 0.0, 0.0);
+        let dy = Vector3::new(0.0, h, 0.0);
+        let dz = Vector3::new(0.0, 0.0, h);
+        Vector3::new(
+            self.sdf.distance(p + dx) - self.sdf.distance(p - dx),
+            self.sdf.distance(p + dy) - self.sdf.distance(p - dy),
+            self.sdf.distance(p + dz) - self.sdf.distance(p - dz),
+        )
+    }
+}
+
+impl<S: Sdf, M: Material> Hittable for SdfObject<S, M> {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let t = self.march(r, t_min, t_max);
+        count_test(Primitive::Sdf, t.is_some());
+        let t = t?;
+        let p = r.at(t);
+        let outward_normal = self.gradient(p).unit();
+        Option::Some(HitRecord::from_normal(
+            p,
+            t,
+            r,
+            outward_normal,
+            &self.material,
+        ))
+    }
+}
+
+impl<S: Sdf, M: Material> SdfObject<S, M> {
+    // Ray parameter at which sphere tracing reaches the surface.
+    fn march(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
+        let speed = r.direction.length();
+        let t_max = t_max.min(self.max_distance / speed);
+        let mut t = t_min;
+        let mut d = self.sdf.distance(r.at(t));
+
+        // A ray leaving a surface starts on it, so step off before deciding which side it is on.
+        while d.abs() < self.epsilon {
+            t += self.epsilon / speed;
+            if t > t_max {
+                return Option::None;
+            }
+            d = self.sdf.distance(r.at(t));
+        }
+        let side = d.signum();
+
+        for _ in 0..self.max_steps {
+            let distance = side * d;
+            if distance < self.epsilon {
+                return Option::Some(t);
+            }
+            t += self.step_scale * distance / speed;
+            if t > t_max {
+                return Option::None;
+            }
+            d = self.sdf.distance(r.at(t));
+        }
+        Option::None
+    }
+}
+
+fn abs(v: Vector3) -> Vector3 {
+    Vector3::new(v.x.abs(), v.y.abs(), v.z.abs())
+}
+
+fn max(v: Vector3, m: f64) -> Vector3 {
+    Vector3::new(v.x.max(m), v.y.max(m), v.z.max(m))
+}
+
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t
+}
+
+// Primitives. All of them are centered at the origin; use `Translate` to place them.
+
+pub struct Ball {
+    pub radius: f64,
+}
+
+impl Sdf for Ball {
+    fn distance(&self, p: Point3) -> f64 {
+        p.length() - self.radius
+    }
+}
+
+pub struct Cuboid {
+    pub half_extents: Vector3,
+}
+
+impl Sdf for Cuboid {
+    fn distance(&self, p: Point3) -> f64 {
+        let q = abs(p) - self.half_extents;
+        max(q, 0.0).length() + q.max_component().min(0.0)
+    }
+}
+
+// Torus lying in the xz plane.
+pub struct Torus {
+    pub major_radius: f64,
+    pub minor_radius: f64,
+}
+
+impl Sdf for Torus {
+    fn distance(&self, p: Point3) -> f64 {
+        let ring = (p.x * p.x + p.z * p.z).sqrt() - self.major_radius;
+        (ring * ring + p.y * p.y).sqrt() - self.minor_radius
+    }
+}
+
+// Capped cylinder along the y axis.
+pub struct Cylinder {
+    pub radius: f64,
+    pub half_height: f64,
+}
+
+impl Sdf for Cylinder {
+    fn distance(&self, p: Point3) -> f64 {
+        let dx = (p.x * p.x + p.z * p.z).sqrt() - self.radius;
+        let dy = p.y.abs() - self.half_height;
+        dx.max(dy).min(0.0) + (dx.max(0.0).powi(2) + dy.max(0.0).powi(2)).sqrt()
+    }
+}
+
+// Half space below the plane through `offset * normal`. The normal must be a unit vector.
+pub struct Plane {
+    pub normal: Vector3,
+    pub offset: f64,
+}
+
+impl Sdf for Plane {
+    fn distance(&self, p: Point3) -> f64 {
+        p.dot(&self.normal) - self.offset
+    }
+}
+
+pub struct Mandelbulb {
+    pub power: f64,
+    pub iterations: u32,
+}
+
+impl Sdf for Mandelbulb {
+    fn distance(&self, p: Point3) -> f64 {
+        let mut z = p;
+        let mut dr = 1.0;
+        let mut r = z.length();
+        for _ in 0..self.iterations {
+            r = z.length();
+            if r > 2.0 {
+                break;
+            }
+            if r == 0.0 {
+                // Zero raised to the power is zero, with no angles to speak of.
+                z = p;
+                dr = 1.0;
+                continue;
+            }
+            // Raise z to the power in spherical coordinates, tracking the running derivative.
+            let theta = (z.z / r).acos() * self.power;
+            let phi = z.y.atan2(z.x) * self.power;
+            dr = r.powf(self.power - 1.0) * self.power * dr + 1.0;
+            let zr = r.powf(self.power);
+            z =
+                zr * Vector3::new(
+                    theta.sin() * phi.cos(),
+                    phi.sin() * theta.sin(),
+                    theta.cos(),
+                ) + p;
+        }
+        if r == 0.0 {
+            // The estimate tends to zero where z does, though the logarithm is undefined there.
+            return 0.0;
+        }
+        0.5 * r.ln() * r / dr
+    }
+}
+
+// Menger sponge filling the cube [-1, 1]^3.
+pub struct MengerSponge {
+    pub iterations: u32,
+}
+
+impl Sdf for MengerSponge {
+    fn distance(&self, p: Point3) -> f64 {
+        let wrap = |x: f64| x - 2.0 * (x / 2.0).floor() - 1.0;
+        let mut d = Cuboid {
+            half_extents: Vector3::new(1.0, 1.0, 1.0),
+        }
+        .distance(p);
+        let mut scale = 1.0;
+        for _ in 0..self.iterations {
+            // Carve the cross out of every sub-cube at this level.
+            let a = Vector3::new(wrap(p.x * scale), wrap(p.y * scale), wrap(p.z * scale));
+            scale *= 3.0;
+            let r = abs(Vector3::new(1.0, 1.0, 1.0) - 3.0 * abs(a));
+            let da = r.x.max(r.y);
+            let db = r.y.max(r.z);
+            let dc = r.z.max(r.x);
+            let c = (da.min(db).min(dc) - 1.0) / scale;
+            d = d.max(c);
+        }
+        d
+    }
+}
+
+// Operators
+
+pub struct Translate<S: Sdf> {
+    pub sdf: S,
+    pub offset: Vector3,
+}
+
+impl<S: Sdf> Sdf for Translate<S> {
+    fn distance(&self, p: Point3) -> f64 {
+        self.sdf.distance(p - self.offset)
+    }
+}
+
+pub struct Scale<S: Sdf> {
+    pub sdf: S,
+    pub factor: f64,
+}
+
+impl<S: Sdf> Sdf for Scale<S> {
+    fn distance(&self, p: Point3) -> f64 {
+        self.sdf.distance(p / self.factor) * self.factor
+    }
+}
+
+pub struct Union<A: Sdf, B: Sdf> {
+    pub a: A,
+    pub b: B,
+}
+
+impl<A: Sdf, B: Sdf> Sdf for Union<A, B> {
+    fn distance(&self, p: Point3) -> f64 {
+        self.a.distance(p).min(self.b.distance(p))
+    }
+}
+
+pub struct Intersection<A: Sdf, B: Sdf> {
+    pub a: A,
+    pub b: B,
+}
+
+impl<A: Sdf, B: Sdf> Sdf for Intersection<A, B> {
+    fn distance(&self, p: Point3) -> f64 {
+        self.a.distance(p).max(self.b.distance(p))
+    }
+}
+
+// Carves `b` out of `a`.
+pub struct Subtraction<A: Sdf, B: Sdf> {
+    pub a: A,
+    pub b: B,
+}
+
+impl<A: Sdf, B: Sdf> Sdf for Subtraction<A, B> {
+    fn distance(&self, p: Point3) -> f64 {
+        self.a.distance(p).max(-self.b.distance(p))
+    }
+}
+
+// Union with a fillet of size `k` where the two surfaces meet (polynomial smooth minimum).
+pub struct SmoothUnion<A: Sdf, B: Sdf> {
+    pub a: A,
+    pub b: B,
+    pub k: f64,
+}
+
+impl<A: Sdf, B: Sdf> Sdf for SmoothUnion<A, B> {
+    fn distance(&self, p: Point3) -> f64 {
+        let d1 = self.a.distance(p);
+        let d2 = self.b.distance(p);
+        let h = clamp(0.5 + 0.5 * (d2 - d1) / self.k, 0.0, 1.0);
+        lerp(d2, d1, h) - self.k * h * (1.0 - h)
+    }
+}
+
+// Morphs from `a` at t = 0 to `b` at t = 1.
+pub struct Blend<A: Sdf, B: Sdf> {
+    pub a: A,
+    pub b: B,
+    pub t: f64,
+}
+
+impl<A: Sdf, B: Sdf> Sdf for Blend<A, B> {
+    fn distance(&self, p: Point3) -> f64 {
+        lerp(self.a.distance(p), self.b.distance(p), self.t)
+    }
+}
+
+// Tiles space with copies of the shape. A zero period leaves that axis unrepeated. The shape
+// should fit inside a single cell.
+pub struct Repeat<S: Sdf> {
+    pub sdf: S,
+    pub period: Vector3,
+}
+
+impl<S: Sdf> Sdf for Repeat<S> {
+    fn distance(&self, p: Point3) -> f64 {
+        let wrap = |x: f64, period: f64| {
+            if period == 0.0 {
+                x
+            } else {
+                x - period * (x / period).round()
+            }
+        };
+        self.sdf.distance(Vector3::new(
+            wrap(p.x, self.period.x),
+            wrap(p.y, self.period.y),
+            wrap(p.z, self.period.z),
+        ))
+    }
+}
+
+// Twists the shape around the y axis by `rate` radians per unit of height. This is not a true
+// distance, so objects using it need a `step_scale` below one.
+pub struct Twist<S: Sdf> {
+    pub sdf: S,
+    pub rate: f64,
+}
+
+impl<S: Sdf> Sdf for Twist<S> {
+    fn distance(&self, p: Point3) -> f64 {
+        let angle = self.rate * p.y;
+        let (sin, cos) = angle.sin_cos();
+        self.sdf.distance(Vector3::new(
+            cos * p.x - sin * p.z,
+            p.y,
+            sin * p.x + cos * p.z,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hittable::Hittable;
+    use crate::material::Lambertian;
+    use crate::ray::Ray;
+    use crate::sdf::{
+        Ball, Cuboid, Mandelbulb, MengerSponge, Repeat, Sdf, SdfObject, SmoothUnion, Translate,
+    };
+    use crate::vector3::{Vector3 as Point3, Vector3};
+
+    fn assert_near(expected: f64, actual: f64) {
+        assert!(
+            (expected - actual).abs() < 1e-3,
+            "{} != {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn sphere_trace() {
+        let object = SdfObject::new(Ball { radius: 1.0 }, Lambertian::new(Vector3::zero()));
+        let r = Ray::new(Point3::new(0.0, 0.0, 5.0), Vector3::new(0.0, 0.0, -2.0));
+        let hit = object.hit(&r, 0.001, f64::INFINITY).unwrap();
+        assert_near(2.0, hit.t);
+        assert!(hit.front_face);
+        assert_near(1.0, hit.normal.z);
+
+        // Continuing from the surface finds the far side from the inside.
+        let exit = object.hit(&r, hit.t, f64::INFINITY).unwrap();
+        assert_near(3.0, exit.t);
+        assert!(!exit.front_face);
+
+        assert!(object.hit(&r, 0.001, 1.5).is_none());
+    }
+
+    #[test]
+    fn closure() {
+        let object = SdfObject::new(|p: Point3| p.y + 1.0, Lambertian::new(Vector3::zero()));
+        let r = Ray::new(Point3::new(0.0, 1.0, 0.0), Vector3::new(0.0, -1.0, 0.0));
+        assert_near(2.0, object.hit(&r, 0.001, f64::INFINITY).unwrap().t);
+    }
+
+    #[test]
+    fn cuboid() {
+        let cuboid = Cuboid {
+            half_extents: Vector3::new(1.0, 2.0, 3.0),
+        };
+        assert_near(1.0, cuboid.distance(Point3::new(2.0, 0.0, 0.0)));
+        assert_near(-1.0, cuboid.distance(Point3::new(0.0, 0.0, 0.0)));
+        assert_near(2.0_f64.sqrt(), cuboid.distance(Point3::new(2.0, 3.0, 0.0)));
+    }
+
+    #[test]
+    fn smooth_union() {
+        let union = SmoothUnion {
+            a: Ball { radius: 1.0 },
+            b: Translate {
+                sdf: Ball { radius: 1.0 },
+                offset: Vector3::new(3.0, 0.0, 0.0),
+            },
+            k: 0.5,
+        };
+        // Far from the seam the union matches the closer shape, and near it the fillet adds
+        // material.
+        assert_near(1.0, union.distance(Point3::new(-2.0, 0.0, 0.0)));
+        assert!(union.distance(Point3::new(1.5, 0.0, 0.0)) < 0.5);
+    }
+
+    #[test]
+    fn repeat() {
+        let repeat = Repeat {
+            sdf: Ball { radius: 0.5 },
+            period: Vector3::new(2.0, 0.0, 0.0),
+        };
+        assert_near(-0.5, repeat.distance(Point3::new(4.0, 0.0, 0.0)));
+        assert_near(0.5, repeat.distance(Point3::new(4.0, 1.0, 0.0)));
+    }
+
+    #[test]
+    fn mandelbulb() {
+        let bulb = Mandelbulb {
+            power: 8.0,
+            iterations: 12,
+        };
+        assert!(bulb.distance(Point3::new(3.0, 0.0, 0.0)) > 0.5);
+        assert!(bulb.distance(Point3::new(0.0, 0.0, 0.5)) < 0.0);
+        // The origin and a bulb of no iterations are where the estimate used to be NaN.
+        assert_eq!(0.0, bulb.distance(Point3::zero()));
+        let ball = Mandelbulb {
+            power: 8.0,
+            iterations: 0,
+        };
+        assert!(ball.distance(Point3::new(2.0, 0.0, 0.0)) > 0.0);
+        assert!(ball.distance(Point3::new(0.5, 0.0, 0.0)) < 0.0);
+
+        let object = SdfObject::new(bulb, Lambertian::new(Vector3::zero()));
+        let r = Ray::new(Point3::new(0.0, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0));
+        let hit = object.hit(&r, 0.001, f64::INFINITY).unwrap();
+        assert!(3.5 < hit.t && hit.t < 4.5, "{}", hit.t);
+        assert!(object.sdf.distance(hit.p).abs() < 1e-3);
+    }
+
+    #[test]
+    fn menger_sponge() {
+        let sponge = MengerSponge { iterations: 3 };
+        assert_near(1.0, sponge.distance(Point3::new(2.0, 0.0, 0.0)));
+        assert!(sponge.distance(Point3::new(0.95, 0.95, 0.95)) < 0.0);
+        // The middle of the cube is carved out.
+        assert!(sponge.distance(Point3::zero()) > 0.0);
+
+        let object = SdfObject::new(sponge, Lambertian::new(Vector3::zero()));
+        let r = Ray::new(Point3::new(0.95, 0.95, 5.0), Vector3::new(0.0, 0.0, -1.0));
+        let hit = object.hit(&r, 0.001, f64::INFINITY).unwrap();
+        assert_near(4.0, hit.t);
+        assert_near(1.0, hit.normal.z);
+        // Straight down the tunnel through the middle of every face.
+        let r = Ray::new(Point3::new(0.0, 0.0, 5.0), Vector3::new(0.0, 0.0, -1.0));
+        assert!(object.hit(&r, 0.001, f64::INFINITY).is_none());
+    }
+}
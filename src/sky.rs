@@ -0,0 +1,323 @@
+use std::f64::consts::PI;
+
+use crate::environment::{Environment, EnvironmentSample};
+use crate::error::{check, Result};
+use crate::util::{degrees_to_radians, random_f64};
+use crate::vector3::{Vector3 as Color, Vector3};
+
+// Angular radius of the sun seen from the earth.
+const SUN_ANGULAR_RADIUS: f64 = 0.00465;
+// Luminance of the sun outside the atmosphere, in the kcd/m^2 units of the sky model.
+const SUN_LUMINANCE: f64 = 2.0e6;
+
+// Preetham, Shirley and Smits' analytic daylight model ("A Practical Analytic Model for
+// Daylight", 1999), with a directly sampled sun disk. Radiance is in kcd/m^2 times `intensity`.
+// The ground below the horizon is a diffuse plane lit by the sun and the sky.
+pub struct PreethamSky {
+    sun_direction: Vector3,
+    sun_radiance: Color,
+    sun_cos_max: f64,
+    zenith: [f64; 3],
+    // Perez distribution coefficients A to E for luminance and the two chromaticities.
+    perez: [[f64; 5]; 3],
+    ground: Color,
+    intensity: f64,
+}
+
+impl PreethamSky {
+    // `turbidity` ranges from 2 (clear) to 10 (hazy), outside which the fit of the model breaks
+    // down. The sun elevation is measured from the
+    // horizon and the azimuth clockwise from -z towards +x, both in degrees.
+    pub fn new(
+        turbidity: f64,
+        ground_albedo: Color,
+        sun_elevation: f64,
+        sun_azimuth: f64,
+        intensity: f64,
+    ) -> Result<PreethamSky> {
+        check(
+            (2.0..=10.0).contains(&turbidity),
+            "turbidity must be between 2 and 10",
+        )?;
+        check(
+            sun_elevation.is_finite() && sun_azimuth.is_finite(),
+            "the sun elevation and azimuth must be finite",
+        )?;
+        let t = turbidity;
+        let elevation = degrees_to_radians(sun_elevation);
+        let azimuth = degrees_to_radians(sun_azimuth);
+        let sun_direction = Vector3::new(
+            elevation.cos() * azimuth.sin(),
+            elevation.sin(),
+            -elevation.cos() * azimuth.cos(),
+        );
+        // The model is only defined for the sun above the horizon.
+        let theta_s = (PI / 2.0 - elevation).clamp(0.0, PI / 2.0 - 0.01);
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let chromaticity = |m: [[f64; 4]; 3]| {
+            let theta = [theta_s.powi(3), theta_s.powi(2), theta_s, 1.0];
+            let row = |r: [f64; 4]| (0..4).map(|i| r[i] * theta[i]).sum::<f64>();
+            t * t * row(m[0]) + t * row(m[1]) + row(m[2])
+        };
+        let zenith_x = chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let zenith_y = chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        let perez = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+        // Normalize so that the distribution equals the zenith value at the zenith.
+        let mut zenith = [zenith_luminance, zenith_x, zenith_y];
+        for i in 0..3 {
+            zenith[i] /= perez_function(&perez[i], 0.0, theta_s);
+        }
+
+        let mut sky = PreethamSky {
+            sun_direction,
+            sun_radiance: sun_radiance(t, theta_s),
+            sun_cos_max: SUN_ANGULAR_RADIUS.cos(),
+            zenith,
+            perez,
+            ground: Color::zero(),
+            intensity,
+        };
+        sky.ground = ground_albedo * sky.horizontal_irradiance() / PI;
+        Ok(sky)
+    }
+
+    pub fn sun_direction(&self) -> Vector3 {
+        self.sun_direction
+    }
+
+    // Radiance of the sky dome alone, without the sun disk or the intensity scale.
+    fn sky_radiance(&self, direction: &Vector3) -> Color {
+        let cos_theta = direction.y.max(0.001);
+        let theta = cos_theta.acos();
+        let gamma = direction.dot(&self.sun_direction).clamp(-1.0, 1.0).acos();
+        let luminance = self.zenith[0] * perez_function(&self.perez[0], theta, gamma);
+        let x = self.zenith[1] * perez_function(&self.perez[1], theta, gamma);
+        let y = self.zenith[2] * perez_function(&self.perez[2], theta, gamma);
+        xyy_to_rgb(x, y, luminance)
+    }
+
+    // Irradiance on an upward facing plane from the sun and the sky dome.
+    fn horizontal_irradiance(&self) -> Color {
+        let sun_solid_angle = 2.0 * PI * (1.0 - self.sun_cos_max);
+        let mut irradiance = self.sun_radiance * sun_solid_angle * self.sun_direction.y.max(0.0);
+
+        let (n_theta, n_phi) = (32, 64);
+        for i in 0..n_theta {
+            let theta = PI / 2.0 * (i as f64 + 0.5) / n_theta as f64;
+            for j in 0..n_phi {
+                let phi = 2.0 * PI * (j as f64 + 0.5) / n_phi as f64;
+                let direction = Vector3::new(
+                    theta.sin() * phi.cos(),
+                    theta.cos(),
+                    theta.sin() * phi.sin(),
+                );
+                let solid_angle =
+                    theta.sin() * (PI / 2.0 / n_theta as f64) * (2.0 * PI / n_phi as f64);
+                irradiance += self.sky_radiance(&direction) * theta.cos() * solid_angle;
+            }
+        }
+        irradiance
+    }
+
+    fn in_sun(&self, direction: &Vector3) -> bool {
+        direction.y > 0.0 && direction.dot(&self.sun_direction) >= self.sun_cos_max
+    }
+}
+
+impl Environment for PreethamSky {
+    fn radiance(&self, direction: &Vector3) -> Color {
+        let direction = direction.unit();
+        if direction.y < 0.0 {
+            return self.intensity * self.ground;
+        }
+        let mut radiance = self.sky_radiance(&direction);
+        if self.in_sun(&direction) {
+            radiance += self.sun_radiance;
+        }
+        self.intensity * radiance
+    }
+
+    // Only the sun disk is sampled; the sky is smooth enough to be found by scattered rays.
+    fn sample(&self) -> Option<EnvironmentSample> {
+        if self.sun_direction.y <= 0.0 {
+            return Option::None;
+        }
+        // Uniform direction in the cone subtended by the sun.
+        let cos_theta = 1.0 - random_f64() * (1.0 - self.sun_cos_max);
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        let phi = 2.0 * PI * random_f64();
+        let (u, v) = orthonormal_basis(&self.sun_direction);
+        let direction =
+            sin_theta * phi.cos() * u + sin_theta * phi.sin() * v + cos_theta * self.sun_direction;
+        // Close to the horizon part of the disk is hidden by the ground, which `pdf` leaves out.
+        if direction.y <= 0.0 {
+            return Option::None;
+        }
+        Option::Some(EnvironmentSample {
+            direction,
+            radiance: self.intensity * (self.sky_radiance(&direction) + self.sun_radiance),
+            pdf: 1.0 / (2.0 * PI * (1.0 - self.sun_cos_max)),
+        })
+    }
+
+    fn pdf(&self, direction: &Vector3) -> f64 {
+        if self.in_sun(&direction.unit()) {
+            1.0 / (2.0 * PI * (1.0 - self.sun_cos_max))
+        } else {
+            0.0
+        }
+    }
+}
+
+fn perez_function(coefficients: &[f64; 5], theta: f64, gamma: f64) -> f64 {
+    let [a, b, c, d, e] = *coefficients;
+    (1.0 + a * (b / theta.cos()).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+}
+
+fn xyy_to_rgb(x: f64, y: f64, luminance: f64) -> Color {
+    if y <= 0.0 {
+        return Color::zero();
+    }
+    let cie_x = x * luminance / y;
+    let cie_z = (1.0 - x - y) * luminance / y;
+    // XYZ to linear sRGB primaries.
+    Color::new(
+        (3.2406 * cie_x - 1.5372 * luminance - 0.4986 * cie_z).max(0.0),
+        (-0.9689 * cie_x + 1.8758 * luminance + 0.0415 * cie_z).max(0.0),
+        (0.0557 * cie_x - 0.2040 * luminance + 1.0570 * cie_z).max(0.0),
+    )
+}
+
+// Sunlight attenuated by Rayleigh and aerosol scattering along the path through the
+// atmosphere, evaluated at representative wavelengths for red, green and blue.
+fn sun_radiance(turbidity: f64, theta_s: f64) -> Color {
+    let relative_air_mass =
+        1.0 / (theta_s.cos() + 0.15 * (93.885 - theta_s.to_degrees()).powf(-1.253));
+    let beta = 0.04608 * turbidity - 0.04586;
+    let transmittance = |wavelength: f64| {
+        let rayleigh = (-0.008735 * wavelength.powf(-4.08) * relative_air_mass).exp();
+        let aerosol = (-beta * wavelength.powf(-1.3) * relative_air_mass).exp();
+        rayleigh * aerosol
+    };
+    SUN_LUMINANCE
+        * Color::new(
+            transmittance(0.68),
+            transmittance(0.55),
+            transmittance(0.44),
+        )
+}
+
+fn orthonormal_basis(w: &Vector3) -> (Vector3, Vector3) {
+    let a = if w.x.abs() > 0.9 {
+        Vector3::new(0.0, 1.0, 0.0)
+    } else {
+        Vector3::new(1.0, 0.0, 0.0)
+    };
+    let v = w.cross(&a).unit();
+    let u = w.cross(&v);
+    (u, v)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::environment::Environment;
+    use crate::sky::PreethamSky;
+    use crate::vector3::{Vector3 as Color, Vector3};
+
+    fn sky() -> PreethamSky {
+        PreethamSky::new(3.0, Color::new(0.3, 0.3, 0.3), 30.0, 90.0, 1.0).unwrap()
+    }
+
+    #[test]
+    fn sun_direction() {
+        let sun = sky().sun_direction();
+        assert!((sun.x - 0.75_f64.sqrt()).abs() < 1e-9);
+        assert!((sun.y - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn blue_sky() {
+        let sky = sky();
+        let zenith = sky.radiance(&Vector3::new(0.0, 1.0, 0.0));
+        assert!(zenith.z > zenith.x);
+        // The sky is brighter around the sun than opposite it.
+        let near_sun = sky.radiance(&Vector3::new(1.0, 0.8, 0.0));
+        let away = sky.radiance(&Vector3::new(-1.0, 0.8, 0.0));
+        assert!(near_sun.y > away.y);
+    }
+
+    #[test]
+    fn sun_samples() {
+        let sky = sky();
+        for _ in 0..100 {
+            let sample = sky.sample().unwrap();
+            assert!(sample.direction.dot(&sky.sun_direction()) > 0.9999);
+            assert!(sample.radiance.y > 1000.0 * sky.radiance(&Vector3::new(0.0, 1.0, 0.0)).y);
+            assert!((sample.pdf - sky.pdf(&sample.direction)).abs() < 1e-6 * sample.pdf);
+        }
+        assert_eq!(0.0, sky.pdf(&Vector3::new(0.0, 1.0, 0.0)));
+
+        // The horizon hides the lower part of a setting sun.
+        let sunset = PreethamSky::new(3.0, Color::zero(), 0.1, 90.0, 1.0).unwrap();
+        let samples: Vec<_> = (0..1000).filter_map(|_| sunset.sample()).collect();
+        assert!(
+            500 < samples.len() && samples.len() < 950,
+            "{}",
+            samples.len()
+        );
+        for sample in samples {
+            assert!(sample.direction.y > 0.0);
+            assert_eq!(sample.pdf, sunset.pdf(&sample.direction));
+        }
+    }
+
+    #[test]
+    fn ground() {
+        let sky = sky();
+        let ground = sky.radiance(&Vector3::new(0.0, -1.0, 0.0));
+        assert!(ground.y > 0.0);
+        let dark = PreethamSky::new(3.0, Color::zero(), 30.0, 90.0, 1.0).unwrap();
+        assert_eq!(Color::zero(), dark.radiance(&Vector3::new(0.0, -1.0, 0.0)));
+    }
+
+    #[test]
+    fn invalid() {
+        for turbidity in [1.0, 12.0, f64::NAN] {
+            assert!(PreethamSky::new(turbidity, Color::zero(), 30.0, 90.0, 1.0).is_err());
+        }
+        assert!(PreethamSky::new(3.0, Color::zero(), f64::NAN, 90.0, 1.0).is_err());
+    }
+}
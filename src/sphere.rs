@@ -1,7 +1,11 @@
-use crate::hittable::{HitRecord, Hittable};
+use std::f64::consts::PI;
+
+use crate::error::{check, Result};
+use crate::hittable::{HitRecord, Hittable, Interval};
 use crate::material::Material;
 use crate::ray::Ray;
-use crate::vector3::Vector3 as Point3;
+use crate::stats::{count_test, Primitive};
+use crate::vector3::{Vector3 as Point3, Vector3};
 
 pub struct Sphere<M: Material> {
     pub center: Point3,
@@ -10,17 +14,40 @@ pub struct Sphere<M: Material> {
 }
 
 impl<M: Material> Sphere<M> {
-    pub fn new(center: Point3, radius: f64, material: M) -> Sphere<M> {
-        Sphere {
+    pub fn new(center: Point3, radius: f64, material: M) -> Result<Sphere<M>> {
+        check(
+            radius > 0.0 && radius.is_finite(),
+            "sphere radius must be positive and finite",
+        )?;
+        Ok(Sphere {
             center,
             radius,
             material,
-        }
+        })
     }
 }
 
-impl<M: Material> Hittable for Sphere<M> {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+// Maps a point on the unit sphere to latitude and longitude, both in [0, 1], with v = 0 at the
+// bottom and u = 0 on the -x axis.
+fn sphere_uv(p: &Vector3) -> (f64, f64) {
+    let theta = (-p.y).acos();
+    let phi = (-p.z).atan2(p.x) + PI;
+    (phi / (2.0 * PI), theta / PI)
+}
+
+impl<M: Material> Sphere<M> {
+    fn record<'a>(&'a self, r: &Ray, t: f64) -> HitRecord<'a> {
+        let p = r.at(t);
+        let outward_normal = (p - self.center) / self.radius;
+        let mut rec = HitRecord::from_normal(p, t, r, outward_normal, &self.material);
+        let (u, v) = sphere_uv(&outward_normal);
+        rec.u = u;
+        rec.v = v;
+        rec
+    }
+
+    // Ray parameter of the nearest intersection in [t_min, t_max].
+    fn nearest_root(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<f64> {
         let oc = r.origin - self.center;
         let a = r.direction.length_squared();
         let half_b = oc.dot(&r.direction);
@@ -41,14 +68,41 @@ impl<M: Material> Hittable for Sphere<M> {
             }
         }
 
-        let p = r.at(root);
-        let outward_normal = (p - self.center) / self.radius;
-        Option::Some(HitRecord::from_normal(
-            p,
-            root,
-            r,
-            outward_normal,
-            &self.material,
-        ))
+        Option::Some(root)
+    }
+}
+
+impl<M: Material> Hittable for Sphere<M> {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let root = self.nearest_root(r, t_min, t_max);
+        count_test(Primitive::Sphere, root.is_some());
+        root.map(|t| self.record(r, t))
+    }
+
+    fn intervals(&self, r: &Ray, t_min: f64, t_max: f64) -> Vec<Interval<'_>> {
+        let oc = r.origin - self.center;
+        let a = r.direction.length_squared();
+        let half_b = oc.dot(&r.direction);
+        let c = oc.length_squared() - self.radius * self.radius;
+
+        let discriminant = half_b * half_b - a * c;
+        if discriminant < 0.0 {
+            return Vec::new();
+        }
+        let sqrtd = discriminant.sqrt();
+
+        let t_enter = (-half_b - sqrtd) / a;
+        let t_exit = (-half_b + sqrtd) / a;
+        if t_exit < t_min || t_max < t_enter {
+            return Vec::new();
+        }
+
+        let record = |t: f64| self.record(r, t);
+        vec![Interval {
+            t_enter: t_enter.max(t_min),
+            enter: Option::Some(t_enter).filter(|t| *t >= t_min).map(record),
+            t_exit: t_exit.min(t_max),
+            exit: Option::Some(t_exit).filter(|t| *t <= t_max).map(record),
+        }]
     }
 }
@@ -0,0 +1,267 @@
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::io::Write;
+use std::time::Duration;
+
+use crate::error::Result;
+
+thread_local! {
+    // Counters of the current thread, which only it touches.
+    static STATS: RefCell<Stats> = RefCell::new(Stats::default());
+    // Whether the current thread counts at all, which it doesn't unless asked to.
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Kinds of object whose intersection tests are counted.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Primitive {
+    Sphere,
+    Heightfield,
+    Sdf,
+    Csg,
+}
+
+pub const PRIMITIVES: [Primitive; 4] = [
+    Primitive::Sphere,
+    Primitive::Heightfield,
+    Primitive::Sdf,
+    Primitive::Csg,
+];
+
+impl Primitive {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Primitive::Sphere => "sphere",
+            Primitive::Heightfield => "heightfield",
+            Primitive::Sdf => "sdf",
+            Primitive::Csg => "csg",
+        }
+    }
+}
+
+// Rays tested against one kind of primitive, and how many of them hit it.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Intersections {
+    pub tests: u64,
+    pub hits: u64,
+}
+
+// Counts of the work done by a render. Every thread counts into its own copy, so counting costs
+// no more than an increment, and the copies are collected with `take_stats` and added up with
+// `merge` once the threads are done. Nothing is counted unless `set_counting` turns it on, but
+// the phases are timed either way. Paths end at the maximum depth rather than by Russian
+// roulette, so there are no terminations to count, and the objects of a scene are tested one
+// after another rather than through a tree, so the light BVH is the only one whose nodes are
+// counted.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub camera_rays: u64,
+    // Rays that continue a path after it scatters off a surface.
+    pub scatter_rays: u64,
+    // Rays towards sampled lights and environment directions.
+    pub shadow_rays: u64,
+    // Surfaces hit along the paths of camera rays, including the first.
+    pub path_vertices: u64,
+    // Indexed by `Primitive`.
+    pub intersections: [Intersections; 4],
+    // Heightfield grid cells a ray stepped through.
+    pub cells_visited: u64,
+    // Nodes of the light BVH visited while picking lights to sample.
+    pub light_nodes_visited: u64,
+    // Wall clock time of each phase of the render, in the order they first ran.
+    pub phases: Vec<(String, Duration)>,
+}
+
+impl Stats {
+    pub fn intersections(&self, primitive: Primitive) -> Intersections {
+        self.intersections[primitive as usize]
+    }
+
+    // Mean number of surfaces hit by a camera ray and the rays scattered after it.
+    pub fn mean_path_length(&self) -> f64 {
+        if self.camera_rays == 0 {
+            return 0.0;
+        }
+        self.path_vertices as f64 / self.camera_rays as f64
+    }
+
+    pub fn add_phase(&mut self, name: &str, duration: Duration) {
+        match self.phases.iter_mut().find(|(phase, _)| phase == name) {
+            Some((_, total)) => *total += duration,
+            None => self.phases.push((name.to_string(), duration)),
+        }
+    }
+
+    pub fn merge(&mut self, other: &Stats) {
+        self.camera_rays += other.camera_rays;
+        self.scatter_rays += other.scatter_rays;
+        self.shadow_rays += other.shadow_rays;
+        self.path_vertices += other.path_vertices;
+        for (total, counts) in self.intersections.iter_mut().zip(&other.intersections) {
+            total.tests += counts.tests;
+            total.hits += counts.hits;
+        }
+        self.cells_visited += other.cells_visited;
+        self.light_nodes_visited += other.light_nodes_visited;
+        for (name, duration) in &other.phases {
+            self.add_phase(name, *duration);
+        }
+    }
+
+    pub fn write_json<W: Write>(&self, mut out: W) -> Result<()> {
+        writeln!(out, "{{")?;
+        writeln!(
+            out,
+            "  \"rays\": {{\"camera\": {}, \"scatter\": {}, \"shadow\": {}}},",
+            self.camera_rays, self.scatter_rays, self.shadow_rays
+        )?;
+        writeln!(out, "  \"mean_path_length\": {},", self.mean_path_length())?;
+        writeln!(out, "  \"intersections\": {{")?;
+        for (i, primitive) in PRIMITIVES.iter().enumerate() {
+            let counts = self.intersections(*primitive);
+            let separator = if i + 1 < PRIMITIVES.len() { "," } else { "" };
+            writeln!(
+                out,
+                "    \"{}\": {{\"tests\": {}, \"hits\": {}}}{}",
+                primitive.name(),
+                counts.tests,
+                counts.hits,
+                separator
+            )?;
+        }
+        writeln!(out, "  }},")?;
+        writeln!(out, "  \"cells_visited\": {},", self.cells_visited)?;
+        writeln!(
+            out,
+            "  \"light_nodes_visited\": {},",
+            self.light_nodes_visited
+        )?;
+        let phases = self
+            .phases
+            .iter()
+            .map(|(name, duration)| format!("\"{}\": {}", name, duration.as_secs_f64()))
+            .collect::<Vec<String>>()
+            .join(", ");
+        writeln!(out, "  \"seconds\": {{{}}}", phases)?;
+        writeln!(out, "}}")?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Without counting there is nothing but the phases to show.
+        if self.camera_rays > 0 {
+            writeln!(
+                f,
+                "Rays: {} camera, {} scatter, {} shadow",
+                self.camera_rays, self.scatter_rays, self.shadow_rays
+            )?;
+            writeln!(f, "Mean path length: {:.2}", self.mean_path_length())?;
+        }
+        for primitive in PRIMITIVES {
+            let counts = self.intersections(primitive);
+            if counts.tests > 0 {
+                writeln!(
+                    f,
+                    "{} tests: {}, hits: {}",
+                    primitive.name(),
+                    counts.tests,
+                    counts.hits
+                )?;
+            }
+        }
+        if self.cells_visited > 0 {
+            writeln!(f, "Heightfield cells visited: {}", self.cells_visited)?;
+        }
+        if self.light_nodes_visited > 0 {
+            writeln!(f, "Light BVH nodes visited: {}", self.light_nodes_visited)?;
+        }
+        for (name, duration) in &self.phases {
+            writeln!(f, "{}: {:.2}s", name, duration.as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
+
+// Turns counting on or off for the current thread, returning whether it was on.
+pub(crate) fn set_counting(on: bool) -> bool {
+    COUNTING.with(|counting| counting.replace(on))
+}
+
+// Adds to the counters of the current thread, if it is counting.
+pub(crate) fn count<F: FnOnce(&mut Stats)>(f: F) {
+    if COUNTING.with(Cell::get) {
+        STATS.with(|stats| f(&mut stats.borrow_mut()));
+    }
+}
+
+pub(crate) fn count_test(primitive: Primitive, hit: bool) {
+    count(|stats| {
+        let counts = &mut stats.intersections[primitive as usize];
+        counts.tests += 1;
+        counts.hits += hit as u64;
+    });
+}
+
+// Returns the counters of the current thread and starts them again from zero.
+pub fn take_stats() -> Stats {
+    STATS.with(|stats| stats.take())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::stats::{count, count_test, set_counting, take_stats, Primitive, Stats};
+
+    #[test]
+    fn merge() {
+        take_stats();
+        // Nothing is counted until counting is turned on.
+        count(|stats| stats.camera_rays += 2);
+        assert_eq!(Stats::default(), take_stats());
+        assert!(!set_counting(true));
+        count(|stats| stats.camera_rays += 2);
+        count(|stats| stats.path_vertices += 3);
+        count_test(Primitive::Sphere, true);
+        count_test(Primitive::Sphere, false);
+        let mut stats = take_stats();
+        assert_eq!(Stats::default(), take_stats());
+        stats.add_phase("sampling", Duration::from_secs(1));
+
+        // Another thread's counters.
+        let other = std::thread::spawn(|| {
+            set_counting(true);
+            count_test(Primitive::Sphere, true);
+            count(|stats| stats.camera_rays += 1);
+            let mut stats = take_stats();
+            stats.add_phase("sampling", Duration::from_secs(2));
+            stats.add_phase("denoise", Duration::from_secs(1));
+            stats
+        })
+        .join()
+        .unwrap();
+        stats.merge(&other);
+
+        assert_eq!(3, stats.camera_rays);
+        assert_eq!(1.0, stats.mean_path_length());
+        assert_eq!(3, stats.intersections(Primitive::Sphere).tests);
+        assert_eq!(2, stats.intersections(Primitive::Sphere).hits);
+        assert_eq!(
+            vec![
+                (String::from("sampling"), Duration::from_secs(3)),
+                (String::from("denoise"), Duration::from_secs(1)),
+            ],
+            stats.phases
+        );
+
+        let mut json = Vec::new();
+        stats.write_json(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.contains("\"rays\": {\"camera\": 3, \"scatter\": 0, \"shadow\": 0}"));
+        assert!(json.contains("\"sphere\": {\"tests\": 3, \"hits\": 2},"));
+        assert!(json.contains("\"csg\": {\"tests\": 0, \"hits\": 0}\n"));
+        assert!(json.contains("\"seconds\": {\"sampling\": 3, \"denoise\": 1}"));
+    }
+}
@@ -0,0 +1,318 @@
+use std::f64::consts::PI;
+
+use crate::camera::{basis, Camera, PerspectiveCamera};
+use crate::error::{check, Result};
+use crate::ray::Ray;
+use crate::vector3::{Vector3 as Point3, Vector3};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Eye {
+    Left,
+    Right,
+}
+
+// How the two views share one image.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StereoLayout {
+    // Left eye in the left half.
+    SideBySide,
+    // Left eye in the top half.
+    OverUnder,
+}
+
+impl StereoLayout {
+    // Aspect ratio of the combined image for views of the given aspect ratio.
+    pub fn aspect_ratio(&self, eye_aspect_ratio: f64) -> f64 {
+        match self {
+            StereoLayout::SideBySide => 2.0 * eye_aspect_ratio,
+            StereoLayout::OverUnder => eye_aspect_ratio / 2.0,
+        }
+    }
+}
+
+// Renders the views of two cameras into the halves of one image.
+pub struct StereoCamera {
+    pub left: Box<dyn Camera>,
+    pub right: Box<dyn Camera>,
+    pub layout: StereoLayout,
+}
+
+impl StereoCamera {
+    pub fn new(
+        left: Box<dyn Camera>,
+        right: Box<dyn Camera>,
+        layout: StereoLayout,
+    ) -> StereoCamera {
+        StereoCamera {
+            left,
+            right,
+            layout,
+        }
+    }
+
+    // Pair of pinhole cameras `ipd` apart with parallel axes and off-axis frustums, which share
+    // their image window at the `convergence` distance. Objects at that distance appear at the
+    // depth of the screen, and with infinity the views are parallel and everything appears in
+    // front of it. `aspect_ratio` is that of each eye.
+    #[allow(clippy::too_many_arguments)]
+    pub fn perspective(
+        look_from: Point3,
+        look_at: Point3,
+        v_up: Vector3,
+        v_fov: f64,
+        aspect_ratio: f64,
+        ipd: f64,
+        convergence: f64,
+        layout: StereoLayout,
+    ) -> Result<StereoCamera> {
+        check(ipd >= 0.0, "ipd must not be negative")?;
+        check(convergence > 0.0, "convergence must be positive")?;
+        let (u, _, w) = basis(look_from, look_at, v_up)?;
+        let parallel = convergence.is_infinite();
+        let eye = |sign: f64| -> Result<PerspectiveCamera> {
+            let offset = sign * ipd / 2.0 * u;
+            let position = look_from + offset;
+            let mut camera = PerspectiveCamera::new(
+                position,
+                position - w,
+                v_up,
+                v_fov,
+                aspect_ratio,
+                0.0,
+                if parallel { 1.0 } else { convergence },
+            )?;
+            if !parallel {
+                camera.offset_window(-offset);
+            }
+            Ok(camera)
+        };
+        Ok(StereoCamera::new(
+            Box::new(eye(-1.0)?),
+            Box::new(eye(1.0)?),
+            layout,
+        ))
+    }
+
+    // Omnidirectional stereo panoramas for both eyes.
+    pub fn ods(
+        look_from: Point3,
+        look_at: Point3,
+        v_up: Vector3,
+        ipd: f64,
+        convergence: f64,
+        layout: StereoLayout,
+    ) -> Result<StereoCamera> {
+        let eye = |eye| OdsCamera::new(look_from, look_at, v_up, ipd, convergence, eye);
+        Ok(StereoCamera::new(
+            Box::new(eye(Eye::Left)?),
+            Box::new(eye(Eye::Right)?),
+            layout,
+        ))
+    }
+}
+
+impl Camera for StereoCamera {
+    fn get_ray(&self, s: f64, t: f64) -> Option<Ray> {
+        match self.layout {
+            StereoLayout::SideBySide if s < 0.5 => self.left.get_ray(2.0 * s, t),
+            StereoLayout::SideBySide => self.right.get_ray(2.0 * s - 1.0, t),
+            StereoLayout::OverUnder if t >= 0.5 => self.left.get_ray(s, 2.0 * t - 1.0),
+            StereoLayout::OverUnder => self.right.get_ray(s, 2.0 * t),
+        }
+    }
+
+    fn get_weighted_ray(&self, s: f64, t: f64) -> Option<(Ray, f64)> {
+        match self.layout {
+            StereoLayout::SideBySide if s < 0.5 => self.left.get_weighted_ray(2.0 * s, t),
+            StereoLayout::SideBySide => self.right.get_weighted_ray(2.0 * s - 1.0, t),
+            StereoLayout::OverUnder if t >= 0.5 => self.left.get_weighted_ray(s, 2.0 * t - 1.0),
+            StereoLayout::OverUnder => self.right.get_weighted_ray(s, 2.0 * t),
+        }
+    }
+}
+
+// One eye of an omnidirectional stereo (ODS) equirectangular panorama. Every column is seen from
+// a different point on a circle `ipd` across, so that looking in any direction gives the right
+// parallax. The circle shrinks towards the poles, where the eyes can't be told apart anyway,
+// to avoid the swirl otherwise seen there.
+pub struct OdsCamera {
+    origin: Point3,
+    u: Vector3,
+    v: Vector3,
+    w: Vector3,
+    // Signed radius of the viewing circle, negative for the left eye.
+    radius: f64,
+    convergence: f64,
+}
+
+impl OdsCamera {
+    // Rays of the two eyes cross at the `convergence` distance, or never with infinity.
+    pub fn new(
+        look_from: Point3,
+        look_at: Point3,
+        v_up: Vector3,
+        ipd: f64,
+        convergence: f64,
+        eye: Eye,
+    ) -> Result<OdsCamera> {
+        check(ipd >= 0.0, "ipd must not be negative")?;
+        check(convergence > 0.0, "convergence must be positive")?;
+        let (u, v, w) = basis(look_from, look_at, v_up)?;
+        let sign = match eye {
+            Eye::Left => -1.0,
+            Eye::Right => 1.0,
+        };
+        Ok(OdsCamera {
+            origin: look_from,
+            u,
+            v,
+            w,
+            radius: sign * ipd / 2.0,
+            convergence,
+        })
+    }
+}
+
+impl Camera for OdsCamera {
+    fn get_ray(&self, s: f64, t: f64) -> Option<Ray> {
+        // The same mapping as the mono equirectangular camera.
+        let phi = (s - 0.5) * 2.0 * PI;
+        let latitude = (t - 0.5) * PI;
+        let direction = latitude.cos() * phi.sin() * self.u + latitude.sin() * self.v
+            - latitude.cos() * phi.cos() * self.w;
+
+        // Right of the horizontal viewing direction.
+        let right = phi.cos() * self.u + phi.sin() * self.w;
+        let offset = self.radius * latitude.cos() * right;
+        let origin = self.origin + offset;
+        if self.convergence.is_infinite() {
+            return Option::Some(Ray::new(origin, direction));
+        }
+        let target = self.origin + self.convergence * direction;
+        Option::Some(Ray::new(origin, target - origin))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::camera::Camera;
+    use crate::ray::Ray;
+    use crate::stereo::{Eye, OdsCamera, StereoCamera, StereoLayout};
+    use crate::vector3::{Vector3 as Point3, Vector3};
+
+    fn assert_near(expected: Vector3, actual: Vector3) {
+        assert!((expected - actual).length() < 1e-9, "{:?}", actual);
+    }
+
+    fn up() -> Vector3 {
+        Vector3::new(0.0, 1.0, 0.0)
+    }
+
+    // Point where a ray reaches the plane z = -distance.
+    fn at_depth(r: &Ray, distance: f64) -> Point3 {
+        r.at((-distance - r.origin.z) / r.direction.z)
+    }
+
+    #[test]
+    fn layouts() {
+        assert_eq!(3.0, StereoLayout::SideBySide.aspect_ratio(1.5));
+        assert_eq!(1.0, StereoLayout::OverUnder.aspect_ratio(2.0));
+
+        let look_at = Point3::new(0.0, 0.0, -1.0);
+        let side_by_side = StereoCamera::perspective(
+            Point3::zero(),
+            look_at,
+            up(),
+            90.0,
+            1.0,
+            0.064,
+            2.0,
+            StereoLayout::SideBySide,
+        )
+        .unwrap();
+        let left = side_by_side.get_ray(0.25, 0.5).unwrap();
+        let right = side_by_side.get_ray(0.75, 0.5).unwrap();
+        assert_near(Point3::new(-0.032, 0.0, 0.0), left.origin);
+        assert_near(Point3::new(0.032, 0.0, 0.0), right.origin);
+
+        let over_under = StereoCamera::ods(
+            Point3::zero(),
+            look_at,
+            up(),
+            0.064,
+            f64::INFINITY,
+            StereoLayout::OverUnder,
+        )
+        .unwrap();
+        assert!(over_under.get_ray(0.5, 0.75).unwrap().origin.x < 0.0);
+        assert!(over_under.get_ray(0.5, 0.25).unwrap().origin.x > 0.0);
+    }
+
+    #[test]
+    fn convergence() {
+        let camera = StereoCamera::perspective(
+            Point3::zero(),
+            Point3::new(0.0, 0.0, -1.0),
+            up(),
+            60.0,
+            1.5,
+            0.064,
+            3.0,
+            StereoLayout::SideBySide,
+        )
+        .unwrap();
+        // Matching pixels of the two eyes see the same point at the convergence distance, and
+        // the axes stay parallel.
+        for (s, t) in [(0.1, 0.2), (0.25, 0.5), (0.4, 0.9)] {
+            let left = camera.get_ray(s, t).unwrap();
+            let right = camera.get_ray(s + 0.5, t).unwrap();
+            assert_near(at_depth(&left, 3.0), at_depth(&right, 3.0));
+        }
+
+        // At infinity, matching pixels see parallel rays.
+        let parallel = StereoCamera::perspective(
+            Point3::zero(),
+            Point3::new(0.0, 0.0, -1.0),
+            up(),
+            60.0,
+            1.5,
+            0.064,
+            f64::INFINITY,
+            StereoLayout::SideBySide,
+        )
+        .unwrap();
+        for (s, t) in [(0.1, 0.2), (0.25, 0.5), (0.4, 0.9)] {
+            let left = parallel.get_ray(s, t).unwrap();
+            let right = parallel.get_ray(s + 0.5, t).unwrap();
+            assert_near(left.direction.unit(), right.direction.unit());
+            assert_near(left.origin + Vector3::new(0.064, 0.0, 0.0), right.origin);
+        }
+    }
+
+    #[test]
+    fn ods() {
+        let look_at = Point3::new(0.0, 0.0, -1.0);
+        let left = OdsCamera::new(Point3::zero(), look_at, up(), 0.064, 2.0, Eye::Left).unwrap();
+        let right = OdsCamera::new(Point3::zero(), look_at, up(), 0.064, 2.0, Eye::Right).unwrap();
+        for s in [0.1, 0.5, 0.8] {
+            let l = left.get_ray(s, 0.5).unwrap();
+            let r = right.get_ray(s, 0.5).unwrap();
+            assert!(((l.origin - r.origin).length() - 0.064).abs() < 1e-9);
+            // The origins lie across the viewing direction and the rays converge.
+            assert!(
+                (l.origin - r.origin)
+                    .dot(&(l.direction + r.direction))
+                    .abs()
+                    < 1e-9
+            );
+            assert_near(l.at(1.0), r.at(1.0));
+        }
+        // Facing forward, the left eye is on the left.
+        assert_near(
+            Point3::new(-0.032, 0.0, 0.0),
+            left.get_ray(0.5, 0.5).unwrap().origin,
+        );
+        // The eyes merge at the poles.
+        assert_near(Point3::zero(), left.get_ray(0.3, 1.0).unwrap().origin);
+    }
+}
@@ -1,17 +1,55 @@
+use std::cell::Cell;
 use std::f64::consts::PI;
 
 use rand::{thread_rng, Rng};
 
+thread_local! {
+    // State of the SplitMix64 generator behind the random functions, one per thread. Threads
+    // start from a random seed, so renders are only repeatable once seeded.
+    static RANDOM_STATE: Cell<u64> = Cell::new(thread_rng().gen());
+}
+
 pub fn degrees_to_radians(degrees: f64) -> f64 {
     degrees * PI / 180.0
 }
 
+// Restarts the random numbers of the current thread from a seed.
+pub fn seed_random(seed: u64) {
+    RANDOM_STATE.with(|state| state.set(seed));
+}
+
+// Seeds the random numbers of the current thread for one pass over a pixel, so that every pass
+// of every pixel draws the same numbers however the render is split up or resumed.
+pub fn seed_pixel(seed: u64, pass: u32, x: usize, y: usize) {
+    let mut h = mix64(seed ^ 0x9e37_79b9_7f4a_7c15);
+    for value in [pass as u64, x as u64, y as u64] {
+        h = mix64(h ^ value);
+    }
+    seed_random(h);
+}
+
+pub fn random_u64() -> u64 {
+    RANDOM_STATE.with(|state| {
+        let s = state.get().wrapping_add(0x9e37_79b9_7f4a_7c15);
+        state.set(s);
+        mix64(s)
+    })
+}
+
 pub fn random_f64() -> f64 {
-    thread_rng().gen_range(0.0..1.0)
+    // The top 53 bits make a double in [0, 1).
+    (random_u64() >> 11) as f64 * (1.0 / (1_u64 << 53) as f64)
 }
 
 pub fn random_range(min: f64, max: f64) -> f64 {
-    thread_rng().gen_range(min..max)
+    min + (max - min) * random_f64()
+}
+
+// SplitMix64 finalizer, which scrambles the bits of a 64-bit value.
+fn mix64(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
 }
 
 pub fn clamp(x: f64, min: f64, max: f64) -> f64 {
@@ -25,8 +63,9 @@ pub fn clamp(x: f64, min: f64, max: f64) -> f64 {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
-    use crate::util::{clamp, random_f64, random_range};
+    use crate::util::{clamp, random_f64, random_range, seed_pixel, seed_random};
 
     #[test]
     fn random_bounds() {
@@ -48,6 +87,22 @@ mod tests {
         }
     }
 
+    #[test]
+    fn seeded() {
+        seed_random(42);
+        let first: Vec<f64> = (0..10).map(|_| random_f64()).collect();
+        seed_random(42);
+        let second: Vec<f64> = (0..10).map(|_| random_f64()).collect();
+        assert_eq!(first, second);
+
+        seed_pixel(42, 0, 3, 4);
+        let a = random_f64();
+        seed_pixel(42, 0, 4, 3);
+        assert_ne!(a, random_f64());
+        seed_pixel(42, 0, 3, 4);
+        assert_eq!(a, random_f64());
+    }
+
     #[test]
     fn test_clamp() {
         assert_eq!(0.0, clamp(-1.0, 0.0, 1.0));
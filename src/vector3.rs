@@ -26,6 +26,12 @@ impl Vector3 {
         let s = 1e-8;
         self.x.abs() < s && self.y.abs() < s && self.z.abs() < s
     }
+    pub fn is_finite(&self) -> bool {
+        self.x.is_finite() && self.y.is_finite() && self.z.is_finite()
+    }
+    pub fn max_component(&self) -> f64 {
+        self.x.max(self.y).max(self.z)
+    }
     pub fn reflect(&self, n: &Vector3) -> Vector3 {
         self - 2.0 * self.dot(n) * n
     }
@@ -77,6 +83,7 @@ impl Vector3 {
     pub fn new_random_unit_vector() -> Vector3 {
         Vector3::new_random_in_unit_sphere().unit()
     }
+    #[allow(clippy::needless_return)]
     pub fn new_random_in_hemisphere(normal: &Vector3) -> Vector3 {
         let in_unit_sphere = Vector3::new_random_in_unit_sphere();
         return if in_unit_sphere.dot(normal) > 0.0 {